@@ -21,20 +21,60 @@ impl Edge {
         }
     }
 
+    /// Returns all [NodeID]s this [Edge] connects.
+    pub fn nodes(&self) -> Vec<&NodeID> {
+        match &self.kind {
+            EdgeKind::Equality(node_ids) => node_ids.iter().collect(),
+            EdgeKind::Definition { object, label } => vec![object, label],
+            EdgeKind::Using { client, object } => vec![client, object],
+            EdgeKind::Contains { container, object } => vec![container, object],
+            EdgeKind::Reference { dest, .. } => vec![dest],
+        }
+    }
+
+    /// Adds the [RecordEvent] to the history, and applies its updates.
     pub fn add_history(&mut self, re: RecordEvent) {
-        if let Some(last) = self.history.last() {
-            if last != &re {
-                self.history.push(re.clone());
-                if let Record::Edge(re) = &re.1 {
-                    for update in &re.updates {
-                        self.update(update.clone());
-                    }
+        if self.history.last() != Some(&re) {
+            self.history.push(re.clone());
+            if let Record::Edge(re) = &re.1 {
+                for update in &re.updates {
+                    self.update(update.clone());
                 }
             }
         }
     }
 
-    pub fn update(&mut self, _update: EdgeAction) {
-        todo!()
+    pub fn update(&mut self, update: EdgeAction) {
+        match update {
+            EdgeAction::UpdateIDs(ids) => match &mut self.kind {
+                EdgeKind::Equality(node_ids) => *node_ids = ids,
+                EdgeKind::Definition {
+                    object: a,
+                    label: b,
+                }
+                | EdgeKind::Using {
+                    client: a,
+                    object: b,
+                }
+                | EdgeKind::Contains {
+                    container: a,
+                    object: b,
+                } => {
+                    if let [id_a, id_b, ..] = ids.as_slice() {
+                        *a = id_a.clone();
+                        *b = id_b.clone();
+                    } else {
+                        log::warn!("Ignoring UpdateIDs with less than two IDs");
+                    }
+                }
+                EdgeKind::Reference { dest, .. } => {
+                    if let Some(id) = ids.first() {
+                        *dest = id.clone();
+                    }
+                }
+            },
+            EdgeAction::Validity(validity) => self.validity = validity,
+            EdgeAction::Delete => {}
+        }
     }
 }
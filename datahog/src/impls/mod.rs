@@ -1,6 +1,9 @@
 use either::Either;
 
-use crate::structs::{Edge, HasID, Node, Record, RecordCUD, Timestamp, Transaction, Validity};
+use crate::structs::{
+    Edge, EdgeAction, EdgeID, HasID, Node, NodeID, NodeUpdate, Record, RecordCUD, SourceID,
+    Timestamp, Transaction, Validity,
+};
 
 pub mod edge;
 pub mod node;
@@ -31,8 +34,37 @@ impl Transaction {
             })],
         }
     }
+
+    pub fn update_node(id: NodeID, updates: Vec<NodeUpdate>) -> Self {
+        Self {
+            timestamp: timestamp_now(),
+            records: vec![Record::Node(RecordCUD {
+                base: Either::Left(id),
+                updates,
+            })],
+        }
+    }
+
+    pub fn update_edge(id: EdgeID, updates: Vec<EdgeAction>) -> Self {
+        Self {
+            timestamp: timestamp_now(),
+            records: vec![Record::Edge(RecordCUD {
+                base: Either::Left(id),
+                updates,
+            })],
+        }
+    }
+}
+
+impl SourceID {
+    /// The [NodeID] of the root node of a [crate::structs::Source].
+    /// As long as the [SourceID] is stable, the root node is stable, too.
+    pub fn root_node(&self) -> NodeID {
+        NodeID::hash_domain_parts("source_root", &[self.as_ref()])
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn timestamp_now() -> Timestamp {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -40,6 +72,13 @@ pub fn timestamp_now() -> Timestamp {
         .as_nanos() as Timestamp
 }
 
+/// The [std::time::SystemTime] is not available in the browser, so only
+/// millisecond precision is available.
+#[cfg(target_arch = "wasm32")]
+pub fn timestamp_now() -> Timestamp {
+    flarch::tasks::now() as Timestamp * 1_000_000
+}
+
 impl<ID, Create, Update> RecordCUD<ID, Create, Update>
 where
     Create: HasID<ID>,
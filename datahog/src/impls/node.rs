@@ -49,19 +49,24 @@ impl Node {
                     self.update(update);
                 }
             }
+            NodeUpdate::DataBlob(index, blob) => {
+                self.data_blob.insert(index, blob);
+            }
+            NodeUpdate::DataBlobRemove(index) => {
+                self.data_blob.remove(&index);
+            }
+            NodeUpdate::DataView(dv) => self.data_view = dv,
             NodeUpdate::Delete => {}
-            _ => todo!(),
         }
     }
 
+    /// Adds the [RecordEvent] to the history, and applies its updates.
     pub fn add_history(&mut self, re: RecordEvent) {
-        if let Some(last) = self.history.last() {
-            if last != &re {
-                self.history.push(re.clone());
-                if let Record::Node(rn) = &re.1 {
-                    for update in &rn.updates {
-                        self.update(update.clone());
-                    }
+        if self.history.last() != Some(&re) {
+            self.history.push(re.clone());
+            if let Record::Node(rn) = &re.1 {
+                for update in &rn.updates {
+                    self.update(update.clone());
                 }
             }
         }
@@ -0,0 +1,290 @@
+//! Integrity rules which are checked by the [crate::worldview::WorldView]
+//! before a [Transaction] is applied.
+//!
+//! Currently this only covers cycles of [EdgeKind::Contains] edges: containment
+//! trees, directory exports and the block editor all walk the `Contains` edges
+//! and would loop forever on a cycle.
+//! A cycle is rejected, unless one of its nodes has been explicitly allowed to
+//! be part of a cycle.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::Result;
+use either::Either;
+
+use crate::structs::{Edge, EdgeAction, EdgeID, EdgeKind, NodeID, Record, Transaction};
+
+/// The policy for [EdgeKind::Contains] cycles.
+#[derive(Debug, Default, Clone)]
+pub struct ContainsPolicy {
+    allowed: HashSet<NodeID>,
+}
+
+impl ContainsPolicy {
+    /// Allows or forbids cycles going through the given container.
+    pub fn allow_cycle(&mut self, container: &NodeID, allow: bool) {
+        if allow {
+            self.allowed.insert(container.clone());
+        } else {
+            self.allowed.remove(container);
+        }
+    }
+
+    /// Returns true if cycles going through this container are allowed.
+    pub fn is_allowed(&self, container: &NodeID) -> bool {
+        self.allowed.contains(container)
+    }
+
+    /// Returns an error if applying the [Transaction] to the given edges
+    /// creates a new [EdgeKind::Contains] cycle which is not allowed.
+    pub fn check(&self, edges: &HashMap<EdgeID, Edge>, tx: &Transaction) -> Result<()> {
+        let mut graph = ContainsGraph::new(edges);
+        for record in &tx.records {
+            let Record::Edge(rc) = record else {
+                continue;
+            };
+            let id = rc.get_id();
+            // Only new edges and new ends of an edge can create a cycle.
+            let mut linked = false;
+            if let Either::Right(edge) = &rc.base
+                && let EdgeKind::Contains { container, object } = &edge.kind
+            {
+                graph.insert(&id, container, object);
+                linked = true;
+            }
+            for update in &rc.updates {
+                match update {
+                    EdgeAction::UpdateIDs(ids) if graph.edges.contains_key(&id) => {
+                        if let [container, object, ..] = ids.as_slice() {
+                            graph.remove(&id);
+                            graph.insert(&id, container, object);
+                            linked = true;
+                        }
+                    }
+                    EdgeAction::Delete => graph.remove(&id),
+                    _ => {}
+                }
+            }
+            if linked
+                && let Some((container, object)) = graph.edges.get(&id)
+                && let Some(cycle) = graph.path(object, container)
+                && !cycle.iter().any(|n| self.is_allowed(n))
+            {
+                anyhow::bail!("Edge {id} creates a Contains-cycle: {cycle:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns all [EdgeKind::Contains] cycles in the given edges.
+/// Each cycle is returned once, as the list of nodes going from a container
+/// to its object.
+pub fn contains_cycles(edges: &HashMap<EdgeID, Edge>) -> Vec<Vec<NodeID>> {
+    let graph = ContainsGraph::new(edges);
+    let mut cycles = vec![];
+    let mut done = HashSet::new();
+    for start in graph.children.keys() {
+        if done.contains(start) {
+            continue;
+        }
+        // Iterative DFS keeping the current path to extract the cycles.
+        let mut path: Vec<NodeID> = vec![];
+        let mut on_path = HashSet::new();
+        let mut stack = vec![(start.clone(), false)];
+        while let Some((node, leaving)) = stack.pop() {
+            if leaving {
+                path.pop();
+                on_path.remove(&node);
+                done.insert(node);
+                continue;
+            }
+            if done.contains(&node) {
+                continue;
+            }
+            if on_path.contains(&node) {
+                let pos = path.iter().position(|n| n == &node).unwrap();
+                cycles.push(path[pos..].to_vec());
+                continue;
+            }
+            path.push(node.clone());
+            on_path.insert(node.clone());
+            stack.push((node.clone(), true));
+            for child in graph.children.get(&node).into_iter().flatten() {
+                stack.push((child.clone(), false));
+            }
+        }
+    }
+    cycles
+}
+
+/// The [EdgeKind::Contains] edges as an adjacency list.
+struct ContainsGraph {
+    edges: HashMap<EdgeID, (NodeID, NodeID)>,
+    children: HashMap<NodeID, Vec<NodeID>>,
+}
+
+impl ContainsGraph {
+    fn new(edges: &HashMap<EdgeID, Edge>) -> Self {
+        let mut graph = Self {
+            edges: HashMap::new(),
+            children: HashMap::new(),
+        };
+        for (id, edge) in edges {
+            if let EdgeKind::Contains { container, object } = &edge.kind {
+                graph.insert(id, container, object);
+            }
+        }
+        graph
+    }
+
+    fn insert(&mut self, id: &EdgeID, container: &NodeID, object: &NodeID) {
+        self.edges
+            .insert(id.clone(), (container.clone(), object.clone()));
+        self.children
+            .entry(container.clone())
+            .or_default()
+            .push(object.clone());
+    }
+
+    fn remove(&mut self, id: &EdgeID) {
+        if let Some((container, object)) = self.edges.remove(id)
+            && let Some(children) = self.children.get_mut(&container)
+            && let Some(pos) = children.iter().position(|c| c == &object)
+        {
+            children.remove(pos);
+        }
+    }
+
+    /// Returns the nodes on the path from `from` to `to`, if it exists.
+    fn path(&self, from: &NodeID, to: &NodeID) -> Option<Vec<NodeID>> {
+        let mut parent: HashMap<NodeID, NodeID> = HashMap::new();
+        let mut queue = VecDeque::from([from.clone()]);
+        let mut seen = HashSet::from([from.clone()]);
+        while let Some(node) = queue.pop_front() {
+            if &node == to {
+                let mut path = vec![node.clone()];
+                let mut current = node;
+                while let Some(p) = parent.get(&current) {
+                    path.push(p.clone());
+                    current = p.clone();
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for child in self.children.get(&node).into_iter().flatten() {
+                if seen.insert(child.clone()) {
+                    parent.insert(child.clone(), node.clone());
+                    queue.push_back(child.clone());
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        structs::{Node, RecordCUD, SourceID, Validity},
+        worldview::WorldView,
+    };
+
+    #[tokio::test]
+    async fn test_contains_cycle() -> Result<()> {
+        let mut wv = WorldView::new();
+        let sid = SourceID::rnd();
+        let (a, b, c) = (Node::label("a"), Node::label("b"), Node::label("c"));
+        for node in [&a, &b, &c] {
+            wv.add_transactions(&sid, vec![Transaction::create_node(node.clone())])
+                .await?;
+        }
+        let ab = Edge::contains(a.id.clone(), b.id.clone());
+        let bc = Edge::contains(b.id.clone(), c.id.clone());
+        wv.add_transactions(
+            &sid,
+            vec![Transaction::create_edge(ab), Transaction::create_edge(bc)],
+        )
+        .await?;
+
+        let ca = Edge::contains(c.id.clone(), a.id.clone());
+        assert!(
+            wv.add_transactions(&sid, vec![Transaction::create_edge(ca.clone())])
+                .await
+                .is_err()
+        );
+        let aa = Edge::contains(a.id.clone(), a.id.clone());
+        assert!(
+            wv.add_transactions(&sid, vec![Transaction::create_edge(aa)])
+                .await
+                .is_err()
+        );
+        assert!(wv.contains_cycles().is_empty());
+
+        // The updates of the sources are checked, too.
+        let d = Node::label("d");
+        let (applied, _, _) = wv
+            .process_updates(vec![
+                Transaction::create_edge(ca.clone()),
+                Transaction::create_node(d.clone()),
+            ])
+            .await?;
+        assert_eq!(applied.len(), 1);
+        assert!(wv.get_node(&d.id).is_some());
+        assert!(wv.get_edge(&ca.id).is_none());
+        assert!(wv.contains_cycles().is_empty());
+
+        wv.allow_contains_cycle(&b.id, true);
+        wv.add_transactions(&sid, vec![Transaction::create_edge(ca)])
+            .await?;
+        let cycles = wv.contains_cycles();
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_updates() {
+        let (a, b, c) = (NodeID::rnd(), NodeID::rnd(), NodeID::rnd());
+        let ab = Edge::contains(a.clone(), b.clone());
+        let bc = Edge::contains(b.clone(), c.clone());
+        let edges = HashMap::from([(ab.id.clone(), ab.clone()), (bc.id.clone(), bc.clone())]);
+        let policy = ContainsPolicy::default();
+        let update = |id: &EdgeID, updates| Transaction {
+            timestamp: 0,
+            records: vec![Record::Edge(RecordCUD {
+                base: Either::Left(id.clone()),
+                updates,
+            })],
+        };
+
+        let bad = update(
+            &bc.id,
+            vec![EdgeAction::UpdateIDs(vec![b.clone(), a.clone()])],
+        );
+        assert!(policy.check(&edges, &bad).is_err());
+        let good = update(
+            &bc.id,
+            vec![EdgeAction::UpdateIDs(vec![a.clone(), c.clone()])],
+        );
+        assert!(policy.check(&edges, &good).is_ok());
+
+        let ca = Edge::contains(c.clone(), a.clone());
+        let mut tx = Transaction::create_edge(ca);
+        assert!(policy.check(&edges, &tx).is_err());
+        tx.records.insert(
+            0,
+            update(&ab.id, vec![EdgeAction::Delete]).records.remove(0),
+        );
+        assert!(policy.check(&edges, &tx).is_ok());
+
+        assert!(contains_cycles(&edges).is_empty());
+
+        // Edges of an existing cycle can still change their validity.
+        let ba = Edge::contains(b.clone(), a.clone());
+        let cycle = HashMap::from([(ab.id.clone(), ab), (ba.id.clone(), ba.clone())]);
+        let validity = update(&ba.id, vec![EdgeAction::Validity(Validity::From(1))]);
+        assert!(policy.check(&cycle, &validity).is_ok());
+    }
+}
@@ -2,7 +2,13 @@
 //! The sources can be loaded from disk, database, or other sources.
 
 pub mod impls;
+pub mod integrity;
+pub mod markdown;
+pub mod objects;
+pub mod search;
 pub mod storage;
 pub mod structs;
+pub mod subscriptions;
+pub mod sync;
 pub mod views;
 pub mod worldview;
@@ -0,0 +1,366 @@
+//! The YAML front-matter at the start of a markdown file:
+//!
+//! ```text
+//! ---
+//! tags: [datahog, notes]
+//! status: draft
+//! due: 2025-01-31
+//! ---
+//! ```
+//!
+//! It is stored in a [DataBlob::Entry] whose name is the original text, and
+//! whose arguments are the fields:
+//! - strings become [DataBlob::Text], except for dates like `2025-01-31` and
+//!   RFC 3339 date-times, which become `date` and `datetime` entries with the
+//!   `timestamp` in nanoseconds
+//! - integers become [DataBlob::Int], all other numbers [DataBlob::Float]
+//! - booleans, lists, maps and null become `bool`, `list`, `map` and `null`
+//!   entries, where the items of a list are named by their position
+//!
+//! The original text is exported as long as it gives the same fields.
+//! Otherwise the front-matter is regenerated, keeping the order of the fields.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDate, NaiveTime, SecondsFormat};
+use num_bigfloat::BigFloat;
+use serde_yaml::{Mapping, Value};
+
+use crate::structs::DataBlob;
+
+/// Splits the front-matter, including the blank lines following it, from the
+/// markdown text.
+/// Returns [None] if the text doesn't start with a YAML mapping between `---`
+/// lines.
+pub fn split(text: &str) -> Option<(DataBlob, &str)> {
+    let (yaml, end) = delimit(text)?;
+    let fields = parse(yaml)?;
+    Some((
+        DataBlob::Entry(text[..end].to_string(), fields),
+        &text[end..],
+    ))
+}
+
+/// Returns the text of the front-matter stored in the [DataBlob::Entry].
+pub fn export(blob: &DataBlob) -> String {
+    let DataBlob::Entry(original, fields) = blob else {
+        return String::new();
+    };
+    let yaml = delimit(original).map(|(yaml, _)| yaml);
+    if yaml.and_then(parse).as_ref() == Some(fields) {
+        return original.clone();
+    }
+
+    let mut keys = yaml
+        .and_then(|yaml| serde_yaml::from_str::<Mapping>(yaml).ok())
+        .map(|mapping| mapping.into_iter().map(|(k, _)| key(k)).collect::<Vec<_>>())
+        .unwrap_or_default();
+    keys.retain(|k| fields.contains_key(k));
+    let mut added = fields
+        .keys()
+        .filter(|k| !keys.contains(k))
+        .cloned()
+        .collect::<Vec<_>>();
+    added.sort();
+    keys.extend(added);
+    let mapping = keys
+        .into_iter()
+        .map(|k| {
+            let value = to_yaml(&fields[&k]);
+            (Value::String(k), value)
+        })
+        .collect::<Mapping>();
+    let yaml = match mapping.is_empty() {
+        true => String::new(),
+        false => serde_yaml::to_string(&mapping).unwrap_or_default(),
+    };
+
+    let blank = original
+        .split_inclusive('\n')
+        .rev()
+        .take_while(|l| l.trim().is_empty())
+        .collect::<Vec<_>>();
+    let blank = match blank.is_empty() {
+        true => "\n".to_string(),
+        false => blank.into_iter().rev().collect(),
+    };
+    format!("---\n{yaml}---\n{blank}")
+}
+
+/// Returns the `tags` of the front-matter, given as a list or as a string
+/// separated by commas or spaces, without a leading `#`.
+pub fn tags(blob: &DataBlob) -> Vec<String> {
+    let DataBlob::Entry(_, fields) = blob else {
+        return vec![];
+    };
+    let texts = match fields.get("tags") {
+        Some(DataBlob::Text(text)) => vec![text.as_str()],
+        Some(DataBlob::Entry(name, items)) if name == "list" => list_items(items)
+            .into_iter()
+            .filter_map(|item| match item {
+                DataBlob::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    let mut tags: Vec<String> = vec![];
+    for tag in texts.into_iter().flat_map(|t| t.split([',', ' '])) {
+        let tag = tag.trim().trim_start_matches('#');
+        if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+/// Returns the YAML between the `---` lines, and the end of the front-matter
+/// including the blank lines following it.
+fn delimit(text: &str) -> Option<(&str, usize)> {
+    let mut lines = text.split_inclusive('\n');
+    let first = lines.next()?;
+    if first.trim_end() != "---" {
+        return None;
+    }
+    let mut end = first.len();
+    let mut yaml_end = None;
+    for line in lines {
+        match yaml_end {
+            None if matches!(line.trim_end(), "---" | "...") => yaml_end = Some(end),
+            Some(_) if !line.trim().is_empty() => break,
+            _ => {}
+        }
+        end += line.len();
+    }
+    Some((&text[first.len()..yaml_end?], end))
+}
+
+fn parse(yaml: &str) -> Option<HashMap<String, DataBlob>> {
+    match serde_yaml::from_str(yaml).ok()? {
+        Value::Mapping(mapping) => Some(
+            mapping
+                .into_iter()
+                .map(|(k, v)| (key(k), from_yaml(v)))
+                .collect(),
+        ),
+        Value::Null => Some(HashMap::new()),
+        _ => None,
+    }
+}
+
+fn key(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        other => serde_yaml::to_string(&other)
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    }
+}
+
+fn entry(name: &str, fields: impl IntoIterator<Item = (String, DataBlob)>) -> DataBlob {
+    DataBlob::Entry(name.into(), fields.into_iter().collect())
+}
+
+fn from_yaml(value: Value) -> DataBlob {
+    match value {
+        Value::Null => entry("null", []),
+        Value::Bool(b) => entry("bool", [("value".into(), DataBlob::Text(b.to_string()))]),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => DataBlob::Int(i.into()),
+            (_, Some(u)) => DataBlob::Int(u.into()),
+            _ => DataBlob::Float(BigFloat::from_f64(n.as_f64().unwrap_or(f64::NAN))),
+        },
+        Value::String(s) => date(&s).unwrap_or(DataBlob::Text(s)),
+        Value::Sequence(items) => entry(
+            "list",
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| (i.to_string(), from_yaml(v))),
+        ),
+        Value::Mapping(mapping) => entry(
+            "map",
+            mapping.into_iter().map(|(k, v)| (key(k), from_yaml(v))),
+        ),
+        Value::Tagged(tagged) => from_yaml(tagged.value),
+    }
+}
+
+/// Returns a `date` or `datetime` entry if the text is one.
+pub fn date(text: &str) -> Option<DataBlob> {
+    let (name, nanos) = match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        Ok(date) => (
+            "date",
+            date.and_time(NaiveTime::MIN)
+                .and_utc()
+                .timestamp_nanos_opt()?,
+        ),
+        Err(_) => (
+            "datetime",
+            DateTime::parse_from_rfc3339(text)
+                .ok()?
+                .timestamp_nanos_opt()?,
+        ),
+    };
+    Some(entry(
+        name,
+        [("timestamp".into(), DataBlob::Int(nanos.into()))],
+    ))
+}
+
+fn to_yaml(blob: &DataBlob) -> Value {
+    match blob {
+        DataBlob::Text(text) => Value::String(text.clone()),
+        DataBlob::Int(int) => i64::try_from(int)
+            .map(Value::from)
+            .unwrap_or_else(|_| Value::String(int.to_string())),
+        DataBlob::Float(float) => Value::from(float.to_f64()),
+        DataBlob::Entry(name, fields) => match name.as_str() {
+            "bool" => {
+                Value::Bool(matches!(fields.get("value"), Some(DataBlob::Text(v)) if v == "true"))
+            }
+            "date" | "datetime" => date_text(blob).map_or(Value::Null, Value::String),
+            "list" => Value::Sequence(list_items(fields).into_iter().map(to_yaml).collect()),
+            "map" => {
+                let mut keys = fields.keys().collect::<Vec<_>>();
+                keys.sort();
+                Value::Mapping(
+                    keys.into_iter()
+                        .map(|k| (Value::String(k.clone()), to_yaml(&fields[k])))
+                        .collect(),
+                )
+            }
+            _ => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+/// Returns the text of a `date` or `datetime` entry, the reverse of [date].
+pub fn date_text(blob: &DataBlob) -> Option<String> {
+    let DataBlob::Entry(name, fields) = blob else {
+        return None;
+    };
+    let Some(DataBlob::Int(nanos)) = fields.get("timestamp") else {
+        return None;
+    };
+    let time = DateTime::from_timestamp_nanos(i64::try_from(nanos).ok()?);
+    match name.as_str() {
+        "date" => Some(time.format("%Y-%m-%d").to_string()),
+        "datetime" => Some(time.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        _ => None,
+    }
+}
+
+/// Returns the items of a `list` entry, ordered by their position.
+fn list_items(fields: &HashMap<String, DataBlob>) -> Vec<&DataBlob> {
+    let mut items = fields
+        .iter()
+        .filter_map(|(k, v)| Some((k.parse::<usize>().ok()?, v)))
+        .collect::<Vec<_>>();
+    items.sort_by_key(|(i, _)| *i);
+    items.into_iter().map(|(_, v)| v).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOTE: &str = "---
+# Status of the note
+tags: [datahog, '#notes']
+status: draft
+due: 2025-01-31
+updated: 2025-01-31T10:30:00+01:00
+count: 3
+ratio: 0.5
+public: false
+author:
+  name: Jane
+---
+
+# Title
+";
+
+    #[test]
+    fn test_split() {
+        let (blob, body) = split(NOTE).unwrap();
+        assert_eq!(body, "# Title\n");
+        let DataBlob::Entry(original, fields) = &blob else {
+            panic!("Expected an entry");
+        };
+        assert_eq!(original, &NOTE[..NOTE.len() - body.len()]);
+        assert_eq!(fields["status"], DataBlob::Text("draft".into()));
+        assert_eq!(fields["count"], DataBlob::Int(3.into()));
+        assert_eq!(fields["ratio"], DataBlob::Float(BigFloat::from_f64(0.5)));
+        assert_eq!(
+            fields["due"],
+            entry(
+                "date",
+                [(
+                    "timestamp".into(),
+                    DataBlob::Int(1_738_281_600_000_000_000i64.into())
+                )]
+            )
+        );
+        assert_eq!(
+            fields["updated"],
+            entry(
+                "datetime",
+                [(
+                    "timestamp".into(),
+                    DataBlob::Int(1_738_315_800_000_000_000i64.into())
+                )]
+            )
+        );
+        assert!(matches!(&fields["author"], DataBlob::Entry(name, _) if name == "map"));
+        assert_eq!(tags(&blob), vec!["datahog", "notes"]);
+        assert_eq!(export(&blob), original.as_str());
+
+        for text in ["# Title\n", "---\nno end\n", "---\n- a list\n---\n"] {
+            assert!(split(text).is_none(), "{text}");
+        }
+        let (empty, body) = split("---\n---\nText").unwrap();
+        assert_eq!((export(&empty).as_str(), body), ("---\n---\n", "Text"));
+    }
+
+    #[test]
+    fn test_regenerate() {
+        let (blob, _) = split(NOTE).unwrap();
+        let DataBlob::Entry(original, mut fields) = blob else {
+            panic!("Expected an entry");
+        };
+        fields.insert("status".into(), DataBlob::Text("done".into()));
+        fields.insert("priority".into(), DataBlob::Int(1.into()));
+        fields.remove("author");
+        let text = export(&DataBlob::Entry(original, fields.clone()));
+        assert_eq!(
+            text,
+            "---
+tags:
+- datahog
+- '#notes'
+status: done
+due: 2025-01-31
+updated: 2025-01-31T09:30:00Z
+count: 3
+ratio: 0.5
+public: false
+priority: 1
+---
+
+"
+        );
+
+        // Regenerated front-matter gives the same fields.
+        let DataBlob::Entry(_, parsed) = split(&text).unwrap().0 else {
+            panic!("Expected an entry");
+        };
+        assert_eq!(parsed, fields);
+
+        // A new front-matter is followed by an empty line.
+        let new = entry("", [("status".into(), DataBlob::Text("new".into()))]);
+        assert_eq!(export(&new), "---\nstatus: new\n---\n\n");
+    }
+}
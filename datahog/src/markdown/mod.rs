@@ -0,0 +1,602 @@
+//! Markdown files are stored in a [Node] as a tree of [DataView]s:
+//! - every block (heading, paragraph, list, code block, table, ...) is stored
+//!   in its own [DataBlob::Text]
+//! - the blocks following a heading are its children, up to the next heading
+//!   of the same or a higher level
+//! - blocks of the same section are siblings
+//!
+//! Every [DataBlob::Text] keeps the raw text of its block, including the blank
+//! lines following it, so the file can be written back unchanged.
+//! When exporting, the levels of the headings are only changed if they don't
+//! fit the tree anymore, e.g., after a section has been moved.
+//! This is also why the blocks are split here, and not with `markdown-ppp`: its
+//! syntax tree doesn't keep the source text.
+//!
+//! The YAML front-matter is stored apart from the blocks, see [front_matter].
+//!
+//! Wiki-links and hashtags are extracted from the blocks with
+//! [links_and_tags], and resolved by [crate::storage::disk::SourceDisk].
+
+use std::collections::HashMap;
+
+use crate::structs::{DataBlob, DataView, Node};
+
+pub mod front_matter;
+
+/// A block of a markdown file with its raw text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub text: String,
+    /// The level of a heading, [None] for all other blocks.
+    pub level: Option<u8>,
+}
+
+/// A wiki-link like `[[Note]]`, `[[Note#Section]]` or `[[Note#Section|Alias]]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WikiLink {
+    /// The name or path of the linked note, empty for a section of the same
+    /// note.
+    pub target: String,
+    pub section: Option<String>,
+}
+
+/// Index of the first [DataBlob::Edge] holding the references of a note.
+/// These blobs are not part of the [DataView], so they're not exported.
+pub const LINK_BLOBS: u32 = 1 << 24;
+
+/// Index of the [DataBlob::Entry] with the front-matter of a note, which is
+/// not part of the [DataView] either.
+pub const FRONT_MATTER_BLOB: u32 = LINK_BLOBS - 1;
+
+/// Replaces the [DataBlob]s and the [DataView] of the node with the blocks of
+/// the markdown text.
+pub fn import(node: &mut Node, text: &str) {
+    let (front_matter, text) = match front_matter::split(text) {
+        Some((blob, body)) => (Some(blob), body),
+        None => (None, text),
+    };
+    let blocks = split_blocks(text);
+    node.data_blob = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (i as u32, DataBlob::Text(block.text.clone())))
+        .collect::<HashMap<_, _>>();
+    node.data_view = match section(&blocks, &mut 0, 0) {
+        Some(dv) => *dv,
+        None => {
+            node.data_blob.insert(0, DataBlob::Text("".into()));
+            DataView {
+                index: 0,
+                child: None,
+                sibling: None,
+            }
+        }
+    };
+    if let Some(blob) = front_matter {
+        node.data_blob.insert(FRONT_MATTER_BLOB, blob);
+    }
+}
+
+/// Returns the markdown text of the tree of [DataView]s of the node.
+pub fn export(node: &Node) -> String {
+    let mut out = node
+        .data_blob
+        .get(&FRONT_MATTER_BLOB)
+        .map(front_matter::export)
+        .unwrap_or_default();
+    export_section(node, Some(&node.data_view), 0, &mut out);
+    out
+}
+
+/// Appends the [DataView] and its siblings, which are in a section with a
+/// heading of the given level.
+fn export_section(node: &Node, mut current: Option<&DataView>, parent: u8, out: &mut String) {
+    // Re-importing a heading with a higher level than a preceding heading
+    // would move it into the section of that heading.
+    let mut max = 6;
+    while let Some(dv) = current {
+        let text = match node.data_blob.get(&dv.index) {
+            Some(DataBlob::Text(text)) => text.as_str(),
+            _ => "",
+        };
+        // Blocks created in an editor are not always followed by a new line.
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push_str("\n\n");
+        }
+        let mut level = parent;
+        match split_blocks(text).first().and_then(|b| b.level) {
+            Some(l) if l > parent && l <= max => {
+                out.push_str(text);
+                level = l;
+            }
+            Some(l) => {
+                level = if l > max { max } else { (parent + 1).min(6) };
+                out.push_str(&set_heading_level(text, level));
+            }
+            None => out.push_str(text),
+        }
+        if level > parent {
+            max = level;
+        }
+        export_section(node, dv.child.as_deref(), level, out);
+        current = dv.sibling.as_deref();
+    }
+}
+
+/// Returns the heading as an ATX heading with the given level.
+fn set_heading_level(text: &str, level: u8) -> String {
+    let title = heading_title(text).unwrap_or_default();
+    let blank = text
+        .split_inclusive('\n')
+        .rev()
+        .take_while(|l| is_blank(l))
+        .map(str::len)
+        .sum::<usize>();
+    let eol = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    format!(
+        "{} {title}{eol}{}",
+        "#".repeat(level as usize),
+        &text[text.len() - blank..]
+    )
+}
+
+/// Returns the title of a heading block without its markup, or [None] if the
+/// block is not a heading.
+pub fn heading_title(text: &str) -> Option<String> {
+    split_blocks(text).first()?.level?;
+    let lines = text.lines().filter(|l| !is_blank(l)).collect::<Vec<_>>();
+    Some(match atx_level(lines[0]) {
+        Some(_) => lines[0].trim().trim_start_matches('#').trim().to_string(),
+        // Setext heading: remove the underline.
+        None => lines[..lines.len() - 1]
+            .iter()
+            .map(|l| l.trim())
+            .collect::<Vec<_>>()
+            .join(" "),
+    })
+}
+
+/// Returns the index of the first heading of the node with this title,
+/// ignoring the case.
+pub fn find_heading(node: &Node, title: &str) -> Option<u32> {
+    node.data_blob
+        .iter()
+        .filter(|(index, _)| **index < LINK_BLOBS)
+        .filter_map(|(index, blob)| match blob {
+            DataBlob::Text(text) => heading_title(text)
+                .filter(|t| t.to_lowercase() == title.trim().to_lowercase())
+                .map(|_| *index),
+            _ => None,
+        })
+        .min()
+}
+
+/// Returns the wiki-links and the hashtags of a block, in the order they
+/// appear.
+/// Code blocks and inline code are ignored, as are hashtags which don't
+/// start with a letter, like issue numbers.
+pub fn links_and_tags(text: &str) -> (Vec<WikiLink>, Vec<String>) {
+    let first = text.lines().find(|l| !is_blank(l)).unwrap_or_default();
+    if fence_start(first).is_some() || unindent(first).is_none() {
+        return (vec![], vec![]);
+    }
+    let text = strip_code(text);
+
+    let mut links = vec![];
+    let mut plain = String::new();
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find("[[") {
+        plain.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after
+            .find("]]")
+            .filter(|end| !after[..*end].contains(['\n', '[']))
+        else {
+            plain.push_str("[[");
+            rest = after;
+            continue;
+        };
+        let inner = after[..end].split('|').next().unwrap_or_default();
+        let (target, section) = match inner.split_once('#') {
+            Some((target, section)) => (target, Some(section.trim().to_string())),
+            None => (inner, None),
+        };
+        let link = WikiLink {
+            target: target.trim().to_string(),
+            section: section.filter(|s| !s.is_empty()),
+        };
+        let empty = link.target.is_empty() && link.section.is_none();
+        if !empty && !links.contains(&link) {
+            links.push(link);
+        }
+        plain.push(' ');
+        rest = &after[end + 2..];
+    }
+    plain.push_str(rest);
+
+    let mut tags: Vec<String> = vec![];
+    let mut prev = ' ';
+    for (i, c) in plain.char_indices() {
+        if c == '#' && prev.is_whitespace() {
+            let tag = plain[i + 1..]
+                .split(|c: char| !(c.is_alphanumeric() || ['_', '-', '/'].contains(&c)))
+                .next()
+                .unwrap_or_default()
+                .trim_end_matches(['-', '/']);
+            if tag.starts_with(char::is_alphabetic) && !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+        prev = c;
+    }
+    (links, tags)
+}
+
+/// Replaces the inline code spans with a space.
+fn strip_code(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('`') {
+        out.push_str(&rest[..start]);
+        let ticks = &rest[start..];
+        let len = ticks.len() - ticks.trim_start_matches('`').len();
+        let body = &ticks[len..];
+        // The span ends with a run of backticks of the same length.
+        let mut pos = 0;
+        let mut end = None;
+        while let Some(p) = body[pos..].find('`') {
+            let p = pos + p;
+            let run = body[p..].len() - body[p..].trim_start_matches('`').len();
+            if run == len {
+                end = Some(p);
+                break;
+            }
+            pos = p + run;
+        }
+        match end {
+            Some(end) => {
+                out.push(' ');
+                rest = &body[end + len..];
+            }
+            None => {
+                out.push_str(&ticks[..len]);
+                rest = body;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Returns the blocks up to the next heading with a level smaller or equal to
+/// `level`, as a chain of siblings.
+fn section(blocks: &[Block], pos: &mut usize, level: u8) -> Option<Box<DataView>> {
+    let mut items = vec![];
+    while let Some(block) = blocks.get(*pos) {
+        if block.level.is_some_and(|l| l <= level) {
+            break;
+        }
+        let index = *pos as u32;
+        *pos += 1;
+        let child = block.level.and_then(|l| section(blocks, pos, l));
+        items.push((index, child));
+    }
+    items
+        .into_iter()
+        .rev()
+        .fold(None, |sibling, (index, child)| {
+            Some(Box::new(DataView {
+                index,
+                child,
+                sibling,
+            }))
+        })
+}
+
+/// Splits the markdown text into blocks.
+/// Concatenating the text of all blocks gives back the original text.
+pub fn split_blocks(text: &str) -> Vec<Block> {
+    let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+    let mut blocks = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        let start = i;
+        let mut level = None;
+        if is_blank(lines[i]) {
+            // Only possible at the start of the file.
+        } else if let Some((fence, len)) = fence_start(lines[i]) {
+            i += 1;
+            while i < lines.len() {
+                i += 1;
+                if is_fence_end(lines[i - 1], fence, len) {
+                    break;
+                }
+            }
+        } else if let Some(l) = atx_level(lines[i]) {
+            level = Some(l);
+            i += 1;
+        } else {
+            let plain = is_plain(lines[i]);
+            i += 1;
+            while i < lines.len()
+                && !is_blank(lines[i])
+                && atx_level(lines[i]).is_none()
+                && fence_start(lines[i]).is_none()
+            {
+                i += 1;
+                if plain && let Some(l) = setext_level(lines[i - 1]) {
+                    level = Some(l);
+                    break;
+                }
+            }
+        }
+        while i < lines.len() && is_blank(lines[i]) {
+            i += 1;
+        }
+        blocks.push(Block {
+            text: lines[start..i].concat(),
+            level,
+        });
+    }
+    blocks
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+/// Returns the line without its up to 3 spaces of indentation, or [None] if
+/// it's indented by 4 or more spaces.
+fn unindent(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start_matches(' ');
+    (line.len() - trimmed.len() < 4).then_some(trimmed)
+}
+
+/// Returns the level of an ATX heading like `## Title`.
+pub(crate) fn atx_level(line: &str) -> Option<u8> {
+    let line = unindent(line)?;
+    let level = line.len() - line.trim_start_matches('#').len();
+    let rest = &line[level..];
+    ((1..=6).contains(&level) && (rest.is_empty() || rest.starts_with([' ', '\t', '\r', '\n'])))
+        .then_some(level as u8)
+}
+
+/// Returns the level of a setext heading underline: `===` or `---`.
+pub(crate) fn setext_level(line: &str) -> Option<u8> {
+    let line = unindent(line)?.trim_end();
+    let level = match line.chars().next()? {
+        '=' => 1,
+        '-' => 2,
+        _ => return None,
+    };
+    line.chars()
+        .all(|c| c == line.chars().next().unwrap())
+        .then_some(level)
+}
+
+/// Returns true if the line starts a paragraph, which can be turned into a
+/// setext heading, and not a list, quote, table or indented code.
+fn is_plain(line: &str) -> bool {
+    let Some(line) = unindent(line) else {
+        return false;
+    };
+    let ordered = line
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .starts_with(['.', ')']);
+    !(line.starts_with(['-', '*', '+', '>', '|', '<'])
+        || ordered && line.starts_with(char::is_numeric))
+}
+
+/// Returns the character and the length of the opening fence of a code block.
+fn fence_start(line: &str) -> Option<(char, usize)> {
+    let line = unindent(line)?;
+    let fence = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.len() - line.trim_start_matches(fence).len();
+    let info = &line[len..];
+    (len >= 3 && !(fence == '`' && info.contains('`'))).then_some((fence, len))
+}
+
+fn is_fence_end(line: &str, fence: char, len: usize) -> bool {
+    unindent(line).is_some_and(|line| {
+        let rest = line.trim_start_matches(fence);
+        line.len() - rest.len() >= len && rest.trim().is_empty()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::structs::NodeUpdate;
+
+    const NOTE: &str = "Intro line
+
+# Title
+
+Some *text*.
+
+## Section A
+
+- one
+- two
+
+```rust
+# not a heading
+
+fn main() {}
+```
+
+Section B
+---------
+
+| a | b |
+|---|---|
+| 1 | 2 |
+
+# Second
+";
+
+    #[test]
+    fn test_split_blocks() {
+        let blocks = split_blocks(NOTE);
+        assert_eq!(concat(&blocks), NOTE);
+        let levels = blocks.iter().map(|b| b.level).collect::<Vec<_>>();
+        assert_eq!(
+            levels,
+            vec![
+                None,
+                Some(1),
+                None,
+                Some(2),
+                None,
+                None,
+                Some(2),
+                None,
+                Some(1)
+            ]
+        );
+        assert_eq!(
+            blocks[5].text,
+            "```rust\n# not a heading\n\nfn main() {}\n```\n\n"
+        );
+        assert_eq!(blocks[6].text, "Section B\n---------\n\n");
+
+        for text in [
+            "",
+            "\n\n",
+            "no newline",
+            "- a\n---\n",
+            "```\nunclosed\n\n# x",
+        ] {
+            assert_eq!(concat(&split_blocks(text)), text);
+        }
+        assert_eq!(split_blocks("- a\n---\n").len(), 1);
+        assert_eq!(split_blocks("```\nunclosed\n\n# x").len(), 1);
+    }
+
+    #[test]
+    fn test_import() {
+        let mut node = Node::mime("text/markdown".into(), "note.md".into());
+        import(&mut node, NOTE);
+        assert_eq!(node.data_blob.len(), 9);
+
+        // Intro -> Title -> Second, with the sections as children.
+        let dv = &node.data_view;
+        assert_eq!(dv.index, 0);
+        let title = dv.sibling.as_ref().unwrap();
+        assert_eq!(title.index, 1);
+        let second = title.sibling.as_ref().unwrap();
+        assert_eq!(
+            (second.index, &second.child, &second.sibling),
+            (8, &None, &None)
+        );
+
+        let text = title.child.as_ref().unwrap();
+        assert_eq!(text.index, 2);
+        let section_a = text.sibling.as_ref().unwrap();
+        let section_b = section_a.sibling.as_ref().unwrap();
+        assert_eq!((section_a.index, section_b.index), (3, 6));
+        assert_eq!(section_b.child.as_ref().unwrap().index, 7);
+        let list = section_a.child.as_ref().unwrap();
+        assert_eq!((list.index, list.sibling.as_ref().unwrap().index), (4, 5));
+
+        import(&mut node, "");
+        assert_eq!(node.data_blob[&0], DataBlob::Text("".into()));
+        assert_eq!(node.data_view.child, None);
+    }
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/markdown");
+        let mut files = 0;
+        for entry in std::fs::read_dir(corpus)? {
+            let path = entry?.path();
+            let text = std::fs::read_to_string(&path)?;
+            let mut node = Node::mime("text/markdown".into(), "corpus".into());
+            import(&mut node, &text);
+            assert_eq!(export(&node), text, "{}", path.display());
+
+            // Going through the editor's DataNode keeps the text, too.
+            let dn = node.view_data_node_get();
+            node.view_data_node_set(&dn);
+            assert_eq!(export(&node), text, "{}", path.display());
+            files += 1;
+        }
+        assert!(files >= 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_edited() {
+        let mut node = Node::mime("text/markdown".into(), "note.md".into());
+        import(&mut node, "# A\n\ntext\n\n# B\n\n## C\n");
+        let view = |index, child, sibling| {
+            Some(Box::new(DataView {
+                index,
+                child,
+                sibling,
+            }))
+        };
+
+        // Moving B below A makes it a level 2 heading, and C a level 3.
+        let tree = view(0, view(1, None, view(2, view(3, None, None), None)), None);
+        node.update(NodeUpdate::DataView(*tree.unwrap()));
+        assert_eq!(export(&node), "# A\n\ntext\n\n## B\n\n### C\n");
+
+        // Moving C to the top, after A, makes it a level 1 heading.
+        let tree = view(0, view(1, None, view(2, None, None)), view(3, None, None));
+        node.update(NodeUpdate::DataView(*tree.unwrap()));
+        assert_eq!(export(&node), "# A\n\ntext\n\n## B\n\n# C\n");
+
+        // Blocks without a new line are separated, setext headings converted.
+        node.update(NodeUpdate::DataBlob(1, DataBlob::Text("edited".into())));
+        node.update(NodeUpdate::DataBlob(3, DataBlob::Text("C\n---\n".into())));
+        assert_eq!(export(&node), "# A\n\nedited\n\n## B\n\n# C\n");
+    }
+
+    #[test]
+    fn test_links_and_tags() {
+        let link = |target: &str, section: Option<&str>| WikiLink {
+            target: target.into(),
+            section: section.map(String::from),
+        };
+        let (links, tags) = links_and_tags(
+            "See [[Other Note]], [[Other Note#Some Section|there]] and [[#Local]].\n\
+             #todo #project/datahog, not a#tag nor #123 or `#code [[Code]]`.\n\
+             Again [[Other Note]] and #todo.\n",
+        );
+        assert_eq!(
+            links,
+            vec![
+                link("Other Note", None),
+                link("Other Note", Some("Some Section")),
+                link("", Some("Local")),
+            ]
+        );
+        assert_eq!(tags, vec!["todo", "project/datahog"]);
+
+        for text in [
+            "# Heading\n",
+            "```\n#tag [[Link]]\n```\n",
+            "    #tag\n",
+            "[[ ]]",
+        ] {
+            assert_eq!(links_and_tags(text), (vec![], vec![]), "{text}");
+        }
+        assert_eq!(links_and_tags("``a`#b`` #c").1, vec!["c"]);
+    }
+
+    #[test]
+    fn test_find_heading() {
+        let mut node = Node::mime("text/markdown".into(), "note.md".into());
+        import(&mut node, NOTE);
+        assert_eq!(find_heading(&node, "section a"), Some(3));
+        assert_eq!(find_heading(&node, "Section B"), Some(6));
+        assert_eq!(find_heading(&node, "not a heading"), None);
+    }
+
+    fn concat(blocks: &[Block]) -> String {
+        blocks.iter().map(|b| b.text.as_str()).collect()
+    }
+}
@@ -1,64 +1,507 @@
-use flarch::tasks::now;
-use tokio::sync::{broadcast, mpsc};
+//! Live handles on a [Node] or an [Edge].
+//!
+//! A handle keeps the current version of its element, refreshes it from the
+//! version sent by the source after each applied [Transaction], and turns
+//! local changes into the minimal set of [NodeUpdate]s or [EdgeAction]s.
+//! These are sent as [Transaction]s, so the UI never has to build them by hand.
+//! As the source always sends the latest version, a handle never misses
+//! a change, however many [Transaction]s are applied between two refreshes.
+//! Local changes are shown right away, and if the [Transaction] is rejected,
+//! the next refresh returns to the version of the source.
 
-use crate::structs::{Edge, Node, Transaction};
+use std::mem::discriminant;
 
-struct EdgeObject {
+use anyhow::Result;
+use either::Either;
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    impls::timestamp_now,
+    structs::{
+        Edge, EdgeAction, EdgeKind, Node, NodeID, NodeUpdate, Record, RecordCUD, RecordEvent,
+        Transaction,
+    },
+};
+
+pub struct EdgeObject {
     edge: Edge,
-    _source_updates: broadcast::Receiver<Transaction>,
-    _updates: mpsc::Sender<Transaction>,
+    deleted: bool,
+    source_updates: watch::Receiver<Option<Edge>>,
+    updates: mpsc::Sender<Transaction>,
 }
 
 impl EdgeObject {
     pub fn new(
         edge: Edge,
-        _source_updates: broadcast::Receiver<Transaction>,
+        source_updates: watch::Receiver<Option<Edge>>,
     ) -> (Self, mpsc::Receiver<Transaction>) {
-        let (_updates, rx) = mpsc::channel(10);
+        let (updates, rx) = mpsc::channel(10);
         (
             Self {
                 edge,
-                _source_updates,
-                _updates,
+                deleted: false,
+                source_updates,
+                updates,
             },
             rx,
         )
     }
 
-    pub fn update(&mut self, edge: Edge) {
-        self._updates.send(Transaction {
-            timestamp: now() as i128,
-            records: vec![],
+    /// The current version of the [Edge].
+    pub fn edge(&self) -> &Edge {
+        &self.edge
+    }
+
+    /// Returns true if the [Edge] has been deleted.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Takes the latest version of the [Edge] sent by the source.
+    /// Returns true if the [Edge] changed since the last call.
+    pub fn refresh(&mut self) -> bool {
+        let Some(current) = receive(&mut self.source_updates) else {
+            return false;
+        };
+        match current {
+            Some(edge) => {
+                let changed = self.deleted || edge != self.edge;
+                self.edge = edge;
+                self.deleted = false;
+                changed
+            }
+            None => !std::mem::replace(&mut self.deleted, true),
+        }
+    }
+
+    /// Sends the changes between the current [Edge] and the given [Edge].
+    /// The kind of the [Edge] can not change, only the [NodeID]s it points to.
+    pub async fn update(&mut self, edge: Edge) -> Result<()> {
+        let actions = edge_diff(&self.edge, &edge)?;
+        self.send(actions).await
+    }
+
+    /// Deletes the [Edge].
+    pub async fn delete(&mut self) -> Result<()> {
+        self.send(vec![EdgeAction::Delete]).await
+    }
+
+    async fn send(&mut self, actions: Vec<EdgeAction>) -> Result<()> {
+        if actions.is_empty() {
+            return Ok(());
+        }
+        let delete = actions.contains(&EdgeAction::Delete);
+        let record = Record::Edge(RecordCUD {
+            base: Either::Left(self.edge.id.clone()),
+            updates: actions,
         });
+        let tx = Transaction {
+            timestamp: timestamp_now(),
+            records: vec![record.clone()],
+        };
+        self.updates.send(tx.clone()).await?;
+        self.deleted |= delete;
+        self.edge.add_history(RecordEvent(tx.timestamp, record));
+        Ok(())
     }
 }
 
 pub struct NodeObject {
     node: Node,
-    _source_updates: broadcast::Receiver<Transaction>,
-    _updates: mpsc::Sender<Transaction>,
+    deleted: bool,
+    source_updates: watch::Receiver<Option<Node>>,
+    updates: mpsc::Sender<Transaction>,
 }
 
 impl NodeObject {
     pub fn new(
         node: Node,
-        _source_updates: broadcast::Receiver<Transaction>,
+        source_updates: watch::Receiver<Option<Node>>,
     ) -> (Self, mpsc::Receiver<Transaction>) {
-        let (_updates, rx) = mpsc::channel(10);
+        let (updates, rx) = mpsc::channel(10);
         (
             Self {
                 node,
-                _source_updates,
-                _updates,
+                deleted: false,
+                source_updates,
+                updates,
             },
             rx,
         )
     }
 
-    pub fn update(&mut self, node: Node) {
-        self._updates.send(Transaction {
-            timestamp: now() as i128,
-            records: vec![],
+    /// The current version of the [Node].
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    /// Returns true if the [Node] has been deleted.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Takes the latest version of the [Node], including its [Edge]s, sent by
+    /// the source.
+    /// Returns true if the [Node] changed since the last call.
+    pub fn refresh(&mut self) -> bool {
+        let Some(current) = receive(&mut self.source_updates) else {
+            return false;
+        };
+        match current {
+            Some(node) => {
+                let changed = self.deleted || node != self.node;
+                self.node = node;
+                self.deleted = false;
+                changed
+            }
+            None => !std::mem::replace(&mut self.deleted, true),
+        }
+    }
+
+    /// Sends the changes between the current [Node] and the given [Node].
+    /// Only the label, the data and the op_version are compared, the [Edge]s
+    /// must be changed through [EdgeObject]s.
+    pub async fn update(&mut self, node: Node) -> Result<()> {
+        let updates = node_diff(&self.node, &node)?;
+        self.send(updates).await
+    }
+
+    /// Sets a new label.
+    pub async fn set_label(&mut self, label: &str) -> Result<()> {
+        let mut node = self.node.clone();
+        node.label = label.into();
+        self.update(node).await
+    }
+
+    /// Deletes the [Node].
+    pub async fn delete(&mut self) -> Result<()> {
+        self.send(vec![NodeUpdate::Delete]).await
+    }
+
+    async fn send(&mut self, updates: Vec<NodeUpdate>) -> Result<()> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let delete = updates.contains(&NodeUpdate::Delete);
+        let record = Record::Node(RecordCUD {
+            base: Either::Left(self.node.id.clone()),
+            updates,
         });
+        let tx = Transaction {
+            timestamp: timestamp_now(),
+            records: vec![record.clone()],
+        };
+        self.updates.send(tx.clone()).await?;
+        self.deleted |= delete;
+        self.node.add_history(RecordEvent(tx.timestamp, record));
+        Ok(())
+    }
+}
+
+/// Returns the minimal list of [NodeUpdate]s to go from `old` to `new`.
+pub fn node_diff(old: &Node, new: &Node) -> Result<Vec<NodeUpdate>> {
+    if old.id != new.id {
+        anyhow::bail!("Cannot update node {} with node {}", old.id, new.id);
+    }
+    if old.kind != new.kind {
+        anyhow::bail!("The kind of a node cannot be changed");
+    }
+    let mut updates = vec![];
+    if old.label != new.label {
+        updates.push(NodeUpdate::Label(new.label.clone()));
+    }
+    let mut indexes = old
+        .data_blob
+        .keys()
+        .chain(new.data_blob.keys())
+        .collect::<Vec<_>>();
+    indexes.sort();
+    indexes.dedup();
+    for index in indexes {
+        match (old.data_blob.get(index), new.data_blob.get(index)) {
+            (Some(_), None) => updates.push(NodeUpdate::DataBlobRemove(*index)),
+            (o, Some(n)) if o != Some(n) => updates.push(NodeUpdate::DataBlob(*index, n.clone())),
+            _ => {}
+        }
+    }
+    if old.data_view != new.data_view {
+        updates.push(NodeUpdate::DataView(new.data_view.clone()));
+    }
+    if old.op_version != new.op_version {
+        updates = vec![NodeUpdate::Migrate(new.op_version, updates)];
+    }
+    Ok(updates)
+}
+
+/// Returns the minimal list of [EdgeAction]s to go from `old` to `new`.
+pub fn edge_diff(old: &Edge, new: &Edge) -> Result<Vec<EdgeAction>> {
+    if old.id != new.id {
+        anyhow::bail!("Cannot update edge {} with edge {}", old.id, new.id);
+    }
+    if discriminant(&old.kind) != discriminant(&new.kind) {
+        anyhow::bail!("The kind of an edge cannot be changed");
+    }
+    if let (EdgeKind::Reference { blob: a, .. }, EdgeKind::Reference { blob: b, .. }) =
+        (&old.kind, &new.kind)
+    {
+        if a != b {
+            anyhow::bail!("The blob of a reference cannot be changed");
+        }
+        if old.nodes() != new.nodes() {
+            anyhow::bail!("The destination of a reference cannot be changed");
+        }
+    }
+    let mut actions = vec![];
+    let new_ids = new.nodes().into_iter().cloned().collect::<Vec<NodeID>>();
+    if old.nodes() != new.nodes() {
+        actions.push(EdgeAction::UpdateIDs(new_ids));
+    }
+    if old.validity != new.validity {
+        actions.push(EdgeAction::Validity(new.validity.clone()));
+    }
+    Ok(actions)
+}
+
+/// Returns the latest version sent by the source, if it changed since the
+/// last call.
+/// Once the source is gone, nothing changes anymore.
+fn receive<T: Clone>(rx: &mut watch::Receiver<Option<T>>) -> Option<Option<T>> {
+    match rx.has_changed() {
+        Ok(true) => Some(rx.borrow_and_update().clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        structs::{DataBlob, SourceID, Validity},
+        worldview::WorldView,
+    };
+
+    #[test]
+    fn test_node_diff() -> Result<()> {
+        let old = Node::mime("text/markdown".into(), "old".into());
+        assert!(node_diff(&old, &old)?.is_empty());
+
+        let mut new = old.clone();
+        new.label = "new".into();
+        new.data_blob.insert(0, DataBlob::Text("text".into()));
+        new.data_blob.insert(1, DataBlob::Text("more".into()));
+        assert_eq!(
+            node_diff(&old, &new)?,
+            vec![
+                NodeUpdate::Label("new".into()),
+                NodeUpdate::DataBlob(0, DataBlob::Text("text".into())),
+                NodeUpdate::DataBlob(1, DataBlob::Text("more".into())),
+            ]
+        );
+
+        let mut updated = old.clone();
+        for update in node_diff(&old, &new)? {
+            updated.update(update);
+        }
+        assert_eq!(updated, new);
+
+        new.data_blob.remove(&0);
+        new.op_version = 1;
+        assert_eq!(
+            node_diff(&old, &new)?,
+            vec![NodeUpdate::Migrate(
+                1,
+                vec![
+                    NodeUpdate::Label("new".into()),
+                    NodeUpdate::DataBlobRemove(0),
+                    NodeUpdate::DataBlob(1, DataBlob::Text("more".into())),
+                ]
+            )]
+        );
+
+        assert!(node_diff(&old, &Node::label("old")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_edge_diff() -> Result<()> {
+        let (a, b, c) = (NodeID::rnd(), NodeID::rnd(), NodeID::rnd());
+        let old = Edge::contains(a.clone(), b.clone());
+        let mut new = old.clone();
+        new.kind = EdgeKind::Contains {
+            container: a.clone(),
+            object: c.clone(),
+        };
+        new.validity = Validity::Period(0, 1);
+        assert_eq!(
+            edge_diff(&old, &new)?,
+            vec![
+                EdgeAction::UpdateIDs(vec![a.clone(), c]),
+                EdgeAction::Validity(Validity::Period(0, 1))
+            ]
+        );
+        new.kind = EdgeKind::Using {
+            client: a.clone(),
+            object: b,
+        };
+        assert!(edge_diff(&old, &new).is_err());
+
+        let mut reference = Edge::contains(a.clone(), a.clone());
+        reference.kind = EdgeKind::Reference {
+            dest: a,
+            blob: None,
+        };
+        let mut new = reference.clone();
+        new.kind = EdgeKind::Reference {
+            dest: NodeID::rnd(),
+            blob: None,
+        };
+        assert!(edge_diff(&reference, &new).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_objects() -> Result<()> {
+        let mut wv = WorldView::new();
+        let sid = SourceID::rnd();
+        let (a, b) = (Node::label("a"), Node::label("b"));
+        let edge = Edge::contains(a.id.clone(), b.id.clone());
+        wv.add_transactions(
+            &sid,
+            vec![
+                Transaction::create_node(a.clone()),
+                Transaction::create_node(b.clone()),
+                Transaction::create_edge(edge.clone()),
+            ],
+        )
+        .await?;
+
+        let (mut obj_a, mut rx_a) = wv.node_object(&a.id).unwrap();
+        let (mut obj_a2, _) = wv.node_object(&a.id).unwrap();
+        obj_a.set_label("a2").await?;
+        assert_eq!(obj_a.node().label, "a2");
+        assert!(!obj_a2.refresh());
+
+        let tx = rx_a.try_recv()?;
+        assert_eq!(tx.records.len(), 1);
+        wv.add_transactions(&sid, vec![tx]).await?;
+        assert_eq!(wv.get_node(&a.id).unwrap().label, "a2");
+        assert!(!obj_a.refresh());
+        assert!(obj_a2.refresh());
+        assert_eq!(obj_a2.node().label, "a2");
+
+        let (mut obj_edge, mut rx_edge) = wv.edge_object(&edge.id).unwrap();
+        obj_edge.delete().await?;
+        wv.add_transactions(&sid, vec![rx_edge.try_recv()?]).await?;
+        assert!(wv.get_edge(&edge.id).is_none());
+        assert!(obj_edge.is_deleted());
+        assert!(!obj_edge.refresh());
+        assert!(obj_a2.refresh());
+        assert!(obj_a2.node().edges.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_objects_many_transactions() -> Result<()> {
+        let mut wv = WorldView::new();
+        let sid = SourceID::rnd();
+        let a = Node::label("a");
+        wv.add_transactions(&sid, vec![Transaction::create_node(a.clone())])
+            .await?;
+        let (mut obj_a, _) = wv.node_object(&a.id).unwrap();
+
+        let (mut txs, mut node) = (vec![], a.clone());
+        for i in 0..200 {
+            let mut new = node.clone();
+            new.label = format!("a{i}");
+            txs.push(Transaction {
+                timestamp: i,
+                records: vec![Record::Node(RecordCUD {
+                    base: Either::Left(a.id.clone()),
+                    updates: node_diff(&node, &new)?,
+                })],
+            });
+            node = new;
+        }
+        wv.process_updates(txs).await?;
+        assert!(obj_a.refresh());
+        assert_eq!(obj_a.node(), &wv.get_node(&a.id).unwrap());
+        assert_eq!(obj_a.node().label, "a199");
+        assert!(!obj_a.refresh());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_objects_cascade() -> Result<()> {
+        let mut wv = WorldView::new();
+        let sid = SourceID::rnd();
+        let (a, b) = (Node::label("a"), Node::label("b"));
+        let edge = Edge::contains(a.id.clone(), b.id.clone());
+        wv.add_transactions(
+            &sid,
+            vec![
+                Transaction::create_node(a.clone()),
+                Transaction::create_node(b.clone()),
+                Transaction::create_edge(edge.clone()),
+            ],
+        )
+        .await?;
+
+        let (mut obj_a, mut rx_a) = wv.node_object(&a.id).unwrap();
+        let (mut obj_b, _) = wv.node_object(&b.id).unwrap();
+        let (mut obj_edge, _) = wv.edge_object(&edge.id).unwrap();
+        assert_eq!(obj_b.node().edges.len(), 1);
+
+        obj_a.delete().await?;
+        wv.add_transactions(&sid, vec![rx_a.try_recv()?]).await?;
+        assert!(!obj_a.refresh());
+        assert!(obj_a.is_deleted());
+        assert!(obj_edge.refresh());
+        assert!(obj_edge.is_deleted());
+        assert!(obj_b.refresh());
+        assert!(obj_b.node().edges.is_empty());
+        assert_eq!(obj_b.node(), &wv.get_node(&b.id).unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_objects_rejected() -> Result<()> {
+        let mut wv = WorldView::new();
+        let sid = SourceID::rnd();
+        let (a, b, c) = (Node::label("a"), Node::label("b"), Node::label("c"));
+        let (ab, bc) = (
+            Edge::contains(a.id.clone(), b.id.clone()),
+            Edge::contains(b.id.clone(), c.id.clone()),
+        );
+        wv.add_transactions(
+            &sid,
+            vec![
+                Transaction::create_node(a.clone()),
+                Transaction::create_node(b.clone()),
+                Transaction::create_node(c.clone()),
+                Transaction::create_edge(ab),
+                Transaction::create_edge(bc.clone()),
+            ],
+        )
+        .await?;
+
+        // Moving `a` into `b` creates a cycle, so the handle goes back to the
+        // version of the WorldView.
+        let (mut obj_bc, mut rx_bc) = wv.edge_object(&bc.id).unwrap();
+        let mut new = bc.clone();
+        new.kind = EdgeKind::Contains {
+            container: b.id.clone(),
+            object: a.id.clone(),
+        };
+        obj_bc.update(new).await?;
+        assert_ne!(obj_bc.edge().kind, bc.kind);
+        assert!(
+            wv.add_transactions(&sid, vec![rx_bc.try_recv()?])
+                .await
+                .is_err()
+        );
+        assert!(obj_bc.refresh());
+        assert_eq!(obj_bc.edge(), &wv.get_edge(&bc.id).unwrap());
+        assert!(!obj_bc.refresh());
+        Ok(())
     }
 }
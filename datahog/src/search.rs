@@ -0,0 +1,340 @@
+//! Ranked search over [Node] labels, meant to be used like a command palette.
+//!
+//! Every word of the query must match a word of the label, either
+//! - exactly,
+//! - as a prefix (`dat` for `DataHog`),
+//! - as the initials of a camel-cased word (`dh` for `DataHog`),
+//! - as an abbreviation starting with the same letter (`prj` for `Project`),
+//! - or with a small number of typos (`roadmpa` for `Roadmap`).
+//!
+//! The match score is then boosted by how recently a node has been used, and
+//! by its [NodeKind].
+
+use std::collections::HashMap;
+
+use crate::structs::{Node, NodeID, NodeKind, Timestamp};
+
+/// Weights used to rank the search results.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchConfig {
+    /// Added to the score of [NodeKind::Label] nodes.
+    pub label: f64,
+    /// Added to the score of [NodeKind::MimeType] nodes.
+    pub mime_type: f64,
+    /// Added to the score of [NodeKind::Schema] nodes.
+    pub schema: f64,
+    /// Added to the score of a node which has just been used. It decays
+    /// exponentially with [SearchConfig::recent_half_life].
+    pub recent: f64,
+    /// Time in nanoseconds after which the recent boost is halved.
+    pub recent_half_life: Timestamp,
+    /// Maximum number of results returned.
+    pub limit: usize,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            label: 0.05,
+            mime_type: 0.0,
+            schema: 0.0,
+            recent: 0.3,
+            recent_half_life: 24 * 3600 * 1_000_000_000,
+            limit: 50,
+        }
+    }
+}
+
+/// A single result of [QuickSearch::search].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit {
+    pub id: NodeID,
+    pub score: f64,
+}
+
+/// Keeps track of the recently used [Node]s and ranks them against a query.
+#[derive(Clone, Debug, Default)]
+pub struct QuickSearch {
+    pub config: SearchConfig,
+    recent: HashMap<NodeID, Timestamp>,
+}
+
+impl QuickSearch {
+    pub fn new(config: SearchConfig) -> Self {
+        Self {
+            config,
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Remembers that the node has been used at the given time, so it gets
+    /// boosted in the following searches.
+    pub fn mark_used(&mut self, id: &NodeID, when: Timestamp) {
+        let last = self.recent.entry(id.clone()).or_insert(when);
+        *last = (*last).max(when);
+    }
+
+    /// Returns the matching nodes, best match first.
+    /// An empty query matches all nodes, ranked by recent use and kind.
+    pub fn search<'a>(
+        &self,
+        query: &str,
+        nodes: impl IntoIterator<Item = &'a Node>,
+        now: Timestamp,
+    ) -> Vec<SearchHit> {
+        let tokens = tokenize(query);
+        let mut hits = nodes
+            .into_iter()
+            .filter_map(|node| {
+                match_tokens(&tokens, &node.label).map(|score| {
+                    (
+                        node.label.to_lowercase(),
+                        SearchHit {
+                            id: node.id.clone(),
+                            score: score + self.boost(node, now),
+                        },
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|(la, a), (lb, b)| b.score.total_cmp(&a.score).then_with(|| la.cmp(lb)));
+        hits.truncate(self.config.limit);
+        hits.into_iter().map(|(_, hit)| hit).collect()
+    }
+
+    fn boost(&self, node: &Node, now: Timestamp) -> f64 {
+        let kind = match node.kind {
+            NodeKind::Label => self.config.label,
+            NodeKind::MimeType(_) => self.config.mime_type,
+            NodeKind::Schema => self.config.schema,
+        };
+        let recent = self
+            .recent
+            .get(&node.id)
+            .map(|used| {
+                let age = (now - used).max(0) as f64;
+                let half_life = self.config.recent_half_life.max(1) as f64;
+                self.config.recent * 0.5f64.powf(age / half_life)
+            })
+            .unwrap_or(0.0);
+        kind + recent
+    }
+}
+
+/// Returns a score between 0 and 1 if all words of the query match the label.
+pub fn match_label(query: &str, label: &str) -> Option<f64> {
+    match_tokens(&tokenize(query), label)
+}
+
+fn match_tokens(tokens: &[String], label: &str) -> Option<f64> {
+    if tokens.is_empty() {
+        return Some(0.0);
+    }
+    let words = LabelWord::split(label);
+    let mut total = 0.0;
+    let mut last_pos = 0;
+    let mut in_order = true;
+    for token in tokens {
+        let (pos, score) = words
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, word)| word.score(token).map(|s| (pos, s)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        in_order &= pos >= last_pos;
+        last_pos = pos;
+        total += score;
+    }
+    let order = if in_order { 1.0 } else { 0.9 };
+    Some(order * total / tokens.len() as f64)
+}
+
+fn tokenize(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// One word of a label, with its camel-case parts.
+struct LabelWord {
+    word: Vec<char>,
+    parts: Vec<Vec<char>>,
+    initials: Vec<char>,
+}
+
+impl LabelWord {
+    fn split(label: &str) -> Vec<Self> {
+        label
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| {
+                let mut parts: Vec<String> = vec![];
+                let mut prev_lower = false;
+                for c in w.chars() {
+                    if (c.is_uppercase() && prev_lower) || parts.is_empty() {
+                        parts.push(String::new());
+                    }
+                    prev_lower = c.is_lowercase() || c.is_numeric();
+                    parts.last_mut().unwrap().extend(c.to_lowercase());
+                }
+                let parts = parts
+                    .into_iter()
+                    .map(|p| p.chars().collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+                Self {
+                    word: w.to_lowercase().chars().collect(),
+                    initials: parts.iter().filter_map(|p| p.first()).cloned().collect(),
+                    parts,
+                }
+            })
+            .collect()
+    }
+
+    fn score(&self, token: &str) -> Option<f64> {
+        let token = token.chars().collect::<Vec<_>>();
+        let ratio = |word: &[char]| token.len() as f64 / word.len().max(1) as f64;
+        if self.word == token {
+            return Some(1.0);
+        }
+        if self.word.starts_with(&token) {
+            return Some(0.8 + 0.2 * ratio(&self.word));
+        }
+        if self.parts.len() > 1 {
+            if let Some(part) = self.parts.iter().skip(1).find(|p| p.starts_with(&token)) {
+                return Some(0.6 + 0.2 * ratio(part));
+            }
+            if token.len() > 1 && self.initials == token {
+                return Some(0.75);
+            }
+        }
+        if token.len() > 1 && token[0] == self.word[0] && is_subsequence(&token, &self.word) {
+            return Some(0.5 + 0.2 * ratio(&self.word));
+        }
+        let typos = match token.len() {
+            0..=3 => return None,
+            4..=7 => 1,
+            _ => 2,
+        };
+        // Compare against the prefixes of the word which might be the result of
+        // the user still typing.
+        let min = token.len().saturating_sub(typos);
+        let max = (token.len() + typos).min(self.word.len());
+        (min..=max)
+            .map(|len| edit_distance(&token, &self.word[..len]))
+            .min()
+            .filter(|&d| d <= typos)
+            .map(|d| 0.45 - 0.1 * d as f64)
+    }
+}
+
+fn is_subsequence(needle: &[char], haystack: &[char]) -> bool {
+    let mut hay = haystack.iter();
+    needle.iter().all(|n| hay.any(|h| h == n))
+}
+
+/// Optimal string alignment distance: like Levenshtein, but a transposition
+/// of two neighbouring characters counts as one edit.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_match_label() {
+        let label = "Project DataHog Roadmap";
+        for query in [
+            "prj dh rd",
+            "project",
+            "proj data",
+            "datahog",
+            "hog",
+            "roadmpa",
+            "Projcet",
+            "",
+        ] {
+            assert!(match_label(query, label).is_some(), "{query} should match");
+        }
+        for query in ["xyz", "prj xyz", "rpj", "dhx"] {
+            assert!(
+                match_label(query, label).is_none(),
+                "{query} shouldn't match"
+            );
+        }
+
+        let exact = match_label("project", label).unwrap();
+        let prefix = match_label("proj", label).unwrap();
+        let abbrev = match_label("prj", label).unwrap();
+        let typo = match_label("projcet", label).unwrap();
+        assert!(exact > prefix);
+        assert!(prefix > abbrev);
+        assert!(abbrev > typo);
+        assert!(match_label("prj rd", label).unwrap() > match_label("rd prj", label).unwrap());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        let d = |a: &str, b: &str| {
+            edit_distance(
+                &a.chars().collect::<Vec<_>>(),
+                &b.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(d("roadmap", "roadmap"), 0);
+        assert_eq!(d("roadmpa", "roadmap"), 1);
+        assert_eq!(d("rodmap", "roadmap"), 1);
+        assert_eq!(d("roadmaps", "roadmap"), 1);
+        assert_eq!(d("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let roadmap = Node::mime("text/markdown".into(), "Project DataHog Roadmap".into());
+        let label = Node::label("Project DataHog");
+        let other = Node::mime("text/markdown".into(), "Private Journal".into());
+        let nodes = [roadmap.clone(), label.clone(), other.clone()];
+
+        let mut qs = QuickSearch::default();
+        let ids = |hits: Vec<SearchHit>| hits.into_iter().map(|h| h.id).collect::<Vec<_>>();
+
+        assert_eq!(
+            ids(qs.search("prj dh rd", &nodes, 0)),
+            vec![roadmap.id.clone()]
+        );
+        assert_eq!(
+            ids(qs.search("prj dh", &nodes, 0)),
+            vec![label.id.clone(), roadmap.id.clone()]
+        );
+
+        let hour = 3600 * 1_000_000_000;
+        qs.mark_used(&roadmap.id, hour);
+        assert_eq!(
+            ids(qs.search("prj dh", &nodes, 2 * hour)),
+            vec![roadmap.id.clone(), label.id.clone()]
+        );
+        assert_eq!(qs.search("p", &nodes, 2 * hour).len(), 3);
+        assert_eq!(qs.search("", &nodes, 2 * hour)[0].id, roadmap.id);
+
+        qs.config.limit = 1;
+        assert_eq!(qs.search("", &nodes, 2 * hour).len(), 1);
+    }
+}
@@ -0,0 +1,684 @@
+//! Reads a CSV file with a header as a table:
+//! - the table is the root node of the source, labelled with the name of the
+//!   file
+//! - the header becomes a [NodeKind::Schema] node, with a `field`
+//!   [DataBlob::Entry] per column holding its `name` and `type`, which
+//!   defines the table with an [EdgeKind::Definition] edge
+//! - the type of a column is the one all its non-empty cells have: `int`,
+//!   `float`, `date` for the dates and date-times also used by
+//!   [front_matter], or `text`
+//! - every row becomes a node holding a [DataBlob::Schema] with the values of
+//!   its cells, labelled with its first cell, and contained in the table
+//!
+//! A row keeps its [NodeID] as long as its cells don't change, or, if they
+//! change, as long as it stays at the same position.
+//! New rows get a [NodeID] derived from their cells.
+//! The file is read again once it is modified, and changes to the cells and
+//! deleted rows are written back to it.
+//!
+//! [NodeKind::Schema]: crate::structs::NodeKind::Schema
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use num_bigfloat::BigFloat;
+use num_bigint::BigInt;
+
+use crate::{
+    markdown::front_matter,
+    storage::dir_trait::{Metadata, Reader, Writer},
+    structs::{
+        DataBlob, Edge, EdgeAction, EdgeID, EdgeKind, Node, NodeID, NodeKind, NodeUpdate, Record,
+        Source, SourceID, Transaction, Validity,
+    },
+};
+
+/// The MIME type of the row [Node]s.
+pub const ROW_MIME: &str = "text/csv";
+
+/// The type of the values of a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Int,
+    Float,
+    Date,
+    Text,
+}
+
+impl ColumnType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnType::Int => "int",
+            ColumnType::Float => "float",
+            ColumnType::Date => "date",
+            ColumnType::Text => "text",
+        }
+    }
+
+    /// Returns the type all the non-empty cells have.
+    fn infer<'a>(cells: impl Iterator<Item = &'a str> + Clone) -> Self {
+        let cells = cells.filter(|cell| !cell.is_empty());
+        if cells.clone().next().is_none() {
+            return ColumnType::Text;
+        }
+        [ColumnType::Int, ColumnType::Float, ColumnType::Date]
+            .into_iter()
+            .find(|ty| cells.clone().all(|cell| ty.parse(cell).is_some()))
+            .unwrap_or(ColumnType::Text)
+    }
+
+    /// Returns the value of a cell, or [None] if it is not of this type.
+    fn parse(&self, cell: &str) -> Option<DataBlob> {
+        match self {
+            ColumnType::Int => cell.parse::<BigInt>().ok().map(DataBlob::Int),
+            ColumnType::Float => cell
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(|f| DataBlob::Float(BigFloat::from_f64(f))),
+            ColumnType::Date => front_matter::date(cell),
+            ColumnType::Text => Some(DataBlob::Text(cell.into())),
+        }
+    }
+
+    /// Returns the value of a cell, which is text if it is empty or not of
+    /// this type.
+    fn value(&self, cell: &str) -> DataBlob {
+        match cell.is_empty() {
+            true => DataBlob::Text("".into()),
+            false => self
+                .parse(cell)
+                .unwrap_or_else(|| DataBlob::Text(cell.into())),
+        }
+    }
+}
+
+/// Returns the text of a cell holding the value.
+fn cell_text(value: &DataBlob) -> Option<String> {
+    match value {
+        DataBlob::Text(text) => Some(text.clone()),
+        DataBlob::Int(int) => Some(int.to_string()),
+        DataBlob::Float(float) => Some(float.to_f64().to_string()),
+        DataBlob::Entry(..) => front_matter::date_text(value),
+        _ => None,
+    }
+}
+
+/// Returns the text of the label and the cells of a row, to find the rows
+/// which didn't change.
+fn row_cells((label, values): &(String, DataBlob)) -> Vec<Option<String>> {
+    let mut cells = vec![Some(label.clone())];
+    if let DataBlob::Schema(_, values) = values {
+        cells.extend(values.iter().map(cell_text));
+    }
+    cells
+}
+
+/// The content of the CSV file.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Table {
+    header: Vec<String>,
+    types: Vec<ColumnType>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn parse(content: &[u8]) -> Result<Self> {
+        let mut reader = csv::Reader::from_reader(content);
+        let header = reader
+            .headers()?
+            .iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let rows = reader
+            .records()
+            .map(|record| Ok(record?.iter().map(String::from).collect()))
+            .collect::<Result<Vec<Vec<_>>>>()?;
+        let types = (0..header.len())
+            .map(|column| ColumnType::infer(rows.iter().map(|row| row[column].as_str())))
+            .collect();
+        Ok(Self {
+            header,
+            types,
+            rows,
+        })
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.write_record(&self.header)?;
+        for row in &self.rows {
+            writer.write_record(row)?;
+        }
+        Ok(writer.into_inner()?)
+    }
+
+    /// Returns the `field` entries of the columns.
+    fn fields(&self) -> Vec<DataBlob> {
+        self.header
+            .iter()
+            .zip(&self.types)
+            .map(|(name, ty)| {
+                DataBlob::Entry(
+                    "field".into(),
+                    HashMap::from([
+                        ("name".into(), DataBlob::Text(name.clone())),
+                        ("type".into(), DataBlob::Text(ty.name().into())),
+                    ]),
+                )
+            })
+            .collect()
+    }
+
+    fn values(&self, row: &[String]) -> Vec<DataBlob> {
+        row.iter()
+            .zip(&self.types)
+            .map(|(cell, ty)| ty.value(cell))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct SourceCsv<RW>
+where
+    RW: Reader + Writer + std::fmt::Debug + Sync + Send,
+{
+    disk: RW,
+    path: Vec<String>,
+    id: SourceID,
+    read: bool,
+    /// The [Metadata] of the file when it was read, or [None] if it must be
+    /// read again.
+    meta: Option<Metadata>,
+    table: Table,
+    /// The [NodeID]s of the rows of `table`.
+    ids: Vec<NodeID>,
+    /// The label and values of the rows, as they are in the
+    /// [crate::worldview::WorldView].
+    rows: HashMap<NodeID, (String, DataBlob)>,
+}
+
+#[async_trait]
+impl<RW: Reader + Writer + std::fmt::Debug + Sync + Send> Source for SourceCsv<RW> {
+    /// The first call returns the whole table, the following calls only the
+    /// changes once the file has been modified.
+    async fn get_updates(&mut self) -> Result<Vec<Transaction>> {
+        let path = self.path();
+        let meta = self.disk.metadata(&path).await?;
+        if self.meta.as_ref() == Some(&meta) {
+            return Ok(vec![]);
+        }
+        let table = Table::parse(&self.disk.read_bytes(&path).await?)?;
+        let mut txs = vec![];
+        let schema = self.schema_id();
+        let fields = table.fields();
+        if !self.read {
+            let name = self.path.last().cloned().unwrap_or_default();
+            let mut root = Node::label(&name);
+            root.id = self.id.root_node();
+            let mut node = Node::schema(name);
+            node.id = schema.clone();
+            node.data_blob = (0..).zip(fields).collect();
+            let edge = Edge {
+                id: EdgeID::hash_domain_parts("SourceCsv", &[root.id.as_ref(), schema.as_ref()]),
+                kind: EdgeKind::Definition {
+                    object: root.id.clone(),
+                    label: schema.clone(),
+                },
+                validity: Validity::from_now(),
+                history: vec![],
+            };
+            txs.extend([
+                Transaction::create_node(root),
+                Transaction::create_node(node),
+                Transaction::create_edge(edge),
+            ]);
+        } else if fields != self.table.fields() {
+            let mut updates = (0..)
+                .zip(fields)
+                .map(|(index, field)| NodeUpdate::DataBlob(index, field))
+                .collect::<Vec<_>>();
+            let removed = table.header.len()..self.table.header.len();
+            updates.extend(removed.map(|index| NodeUpdate::DataBlobRemove(index as u32)));
+            txs.push(Transaction::update_node(schema.clone(), updates));
+        }
+
+        let new_rows = table
+            .rows
+            .iter()
+            .map(|row| {
+                let label = row.first().cloned().unwrap_or_default();
+                (label, DataBlob::Schema(schema.clone(), table.values(row)))
+            })
+            .collect::<Vec<_>>();
+        let ids = self.row_ids(&table, &new_rows);
+        let mut rows = HashMap::new();
+        for (id, (label, values)) in ids.iter().cloned().zip(new_rows) {
+            match self.rows.get(&id) {
+                None => {
+                    let mut node = Node::mime(ROW_MIME.into(), label.clone());
+                    node.id = id.clone();
+                    node.data_blob = HashMap::from([(0, values.clone())]);
+                    let mut edge = Edge::contains(self.id.root_node(), id.clone());
+                    edge.id = self.row_edge(&id);
+                    txs.extend([
+                        Transaction::create_node(node),
+                        Transaction::create_edge(edge),
+                    ]);
+                }
+                Some(old) if old != &(label.clone(), values.clone()) => {
+                    txs.push(Transaction::update_node(
+                        id.clone(),
+                        vec![
+                            NodeUpdate::Label(label.clone()),
+                            NodeUpdate::DataBlob(0, values.clone()),
+                        ],
+                    ));
+                }
+                Some(_) => {}
+            }
+            rows.insert(id, (label, values));
+        }
+        let mut removed = self
+            .rows
+            .keys()
+            .filter(|id| !rows.contains_key(id))
+            .collect::<Vec<_>>();
+        removed.sort_by_key(|id| id.to_string());
+        for id in removed {
+            txs.extend([
+                Transaction::update_edge(self.row_edge(id), vec![EdgeAction::Delete]),
+                Transaction::update_node(id.clone(), vec![NodeUpdate::Delete]),
+            ]);
+        }
+
+        self.read = true;
+        self.meta = Some(meta);
+        self.table = table;
+        self.ids = ids;
+        self.rows = rows;
+        Ok(txs)
+    }
+
+    /// Writes the changes of the rows back to the file:
+    /// - changing the values of a row, or its label, rewrites the changed
+    ///   cells, the label being the first cell
+    /// - deleting the node of a row removes the row
+    ///
+    /// Other changes are ignored, and the next call to
+    /// [Source::get_updates] only returns the changes to the values which
+    /// could not be written as they are, e.g., a column whose type changed.
+    async fn add_tx(&mut self, txs: Vec<Transaction>) -> Result<()> {
+        let mut changed = false;
+        let mut deleted = vec![];
+        for record in txs.into_iter().flat_map(|tx| tx.records) {
+            let Record::Node(rc) = record else {
+                continue;
+            };
+            let id = rc.get_id();
+            let Some(index) = self.ids.iter().position(|row| row == &id) else {
+                continue;
+            };
+            for update in rc.updates {
+                match update {
+                    NodeUpdate::Label(label) => {
+                        if let Some(cell) = self.table.rows[index].first_mut() {
+                            *cell = label.clone();
+                        }
+                        if let Some(row) = self.rows.get_mut(&id) {
+                            row.0 = label;
+                        }
+                    }
+                    NodeUpdate::DataBlob(0, DataBlob::Schema(schema, values)) => {
+                        self.set_values(index, &values)?;
+                        if let Some(row) = self.rows.get_mut(&id) {
+                            row.1 = DataBlob::Schema(schema, values);
+                        }
+                    }
+                    NodeUpdate::Delete => deleted.push(index),
+                    _ => continue,
+                }
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+        deleted.sort();
+        deleted.dedup();
+        for index in deleted.into_iter().rev() {
+            self.table.rows.remove(index);
+            self.rows.remove(&self.ids.remove(index));
+        }
+        let path = self.path.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+        self.disk.overwrite(&path, &self.table.to_bytes()?).await?;
+        // Reads the file again, to find the values which changed on the way.
+        self.meta = None;
+        Ok(())
+    }
+
+    /// Returns the unique ID of this source, derived from the location of the
+    /// disk and the path of the file.
+    fn get_id(&self) -> SourceID {
+        self.id.clone()
+    }
+
+    /// Collects the rows contained in the table, so they keep their
+    /// [NodeID]s, even if their cells have been edited.
+    fn restore(&mut self, root: &NodeID, nodes: &HashMap<NodeID, Node>) {
+        for edge in nodes
+            .get(root)
+            .map(|n| n.edges.iter())
+            .into_iter()
+            .flatten()
+        {
+            if let EdgeKind::Contains { container, object } = &edge.kind
+                && container == root
+                && let Some(node) = nodes.get(object)
+                && node.kind == NodeKind::MimeType(ROW_MIME.into())
+                && let Some(values) = node.data_blob.get(&0)
+            {
+                self.rows
+                    .insert(object.clone(), (node.label.clone(), values.clone()));
+            }
+        }
+    }
+}
+
+impl<RW: Reader + Writer + std::fmt::Debug + Sync + Send> SourceCsv<RW> {
+    /// Reads the CSV file at `path` of the disk.
+    pub fn new(disk: RW, path: &[&str]) -> Self {
+        let id = SourceID::hash_domain_parts(
+            "SourceCsv",
+            &[disk.location().as_bytes(), path.join("/").as_bytes()],
+        );
+        Self {
+            disk,
+            path: path.iter().map(|p| p.to_string()).collect(),
+            id,
+            read: false,
+            meta: None,
+            table: Table::default(),
+            ids: vec![],
+            rows: HashMap::new(),
+        }
+    }
+
+    /// Returns the [NodeID] of the schema node.
+    pub fn schema_id(&self) -> NodeID {
+        NodeID::hash_domain_parts("SourceCsv", &[self.id.as_ref(), b"schema"])
+    }
+
+    /// Returns the [NodeID] of the row at this position.
+    pub fn row_id(&self, index: usize) -> Option<NodeID> {
+        self.ids.get(index).cloned()
+    }
+
+    /// Returns the [NodeID]s of the rows of the new table:
+    /// - a row with the same cells as a known row keeps its [NodeID]
+    /// - a changed row keeps the [NodeID] of the row which was at its position
+    /// - else the [NodeID] is derived from the cells of the row
+    fn row_ids(&self, table: &Table, rows: &[(String, DataBlob)]) -> Vec<NodeID> {
+        let mut taken = self.ids.iter().cloned().collect::<HashSet<_>>();
+        let mut restored = self
+            .rows
+            .keys()
+            .filter(|id| !taken.contains(*id))
+            .cloned()
+            .collect::<Vec<_>>();
+        restored.sort_by_key(|id| id.to_string());
+        taken.extend(restored.iter().cloned());
+        let known = self.ids.iter().cloned().chain(restored);
+        let mut by_cells: HashMap<Vec<Option<String>>, Vec<NodeID>> = HashMap::new();
+        for id in known.rev() {
+            by_cells
+                .entry(row_cells(&self.rows[&id]))
+                .or_default()
+                .push(id);
+        }
+
+        let mut ids = rows
+            .iter()
+            .map(|row| by_cells.get_mut(&row_cells(row))?.pop())
+            .collect::<Vec<_>>();
+        let unused = by_cells.into_values().flatten().collect::<HashSet<_>>();
+        for (id, old) in ids.iter_mut().zip(&self.ids) {
+            if id.is_none() && unused.contains(old) {
+                *id = Some(old.clone());
+            }
+        }
+        ids.into_iter()
+            .zip(&table.rows)
+            .map(|(id, row)| {
+                id.unwrap_or_else(|| {
+                    let id = (0u64..)
+                        .map(|n| {
+                            NodeID::hash_domain_parts(
+                                "SourceCsv",
+                                &[
+                                    self.id.as_ref(),
+                                    b"row",
+                                    row.join("\0").as_bytes(),
+                                    &n.to_le_bytes(),
+                                ],
+                            )
+                        })
+                        .find(|id| !taken.contains(id))
+                        .expect("Unbounded range");
+                    taken.insert(id.clone());
+                    id
+                })
+            })
+            .collect()
+    }
+
+    fn row_edge(&self, row: &NodeID) -> EdgeID {
+        EdgeID::hash_domain_parts("SourceCsv", &[self.id.root_node().as_ref(), row.as_ref()])
+    }
+
+    fn path(&self) -> Vec<&str> {
+        self.path.iter().map(|p| p.as_str()).collect()
+    }
+
+    /// Rewrites the cells whose value changed.
+    fn set_values(&mut self, index: usize, values: &[DataBlob]) -> Result<()> {
+        if values.len() != self.table.header.len() {
+            bail!(
+                "Row {index} has {} values instead of {}",
+                values.len(),
+                self.table.header.len()
+            );
+        }
+        for (column, value) in values.iter().enumerate() {
+            let cell = &mut self.table.rows[index][column];
+            if self.table.types[column].value(cell) == *value {
+                continue;
+            }
+            match cell_text(value) {
+                Some(text) => *cell = text,
+                None => bail!("Cannot write {value:?} to a CSV file"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{storage::dir_trait::EmulatedDir, structs::NodeKind, worldview::WorldView};
+
+    use super::*;
+
+    const TABLE: &str = "name,age,height,born
+Alice,31,1.7,1994-02-03
+Bob,,1.85,1990-07-01
+";
+
+    #[test]
+    fn test_infer() {
+        let table = Table::parse(TABLE.as_bytes()).unwrap();
+        assert_eq!(
+            table.types,
+            [
+                ColumnType::Text,
+                ColumnType::Int,
+                ColumnType::Float,
+                ColumnType::Date
+            ]
+        );
+        assert_eq!(
+            ColumnType::infer(["1", "2.5"].into_iter()),
+            ColumnType::Float
+        );
+        assert_eq!(
+            ColumnType::infer(["2025-01-31", "2025-01-31T10:00:00Z"].into_iter()),
+            ColumnType::Date
+        );
+        assert_eq!(ColumnType::infer(["1", "x"].into_iter()), ColumnType::Text);
+        assert_eq!(ColumnType::infer(["", ""].into_iter()), ColumnType::Text);
+    }
+
+    #[tokio::test]
+    async fn test_csv() -> Result<()> {
+        let dir = EmulatedDir::new_from_string(&[("people.csv", TABLE)]);
+        let mut source = SourceCsv::new(dir, &["people.csv"]);
+        let mut ww = WorldView::new();
+        let txs = source.get_updates().await?;
+        // The table, the schema and its edge, and 2 rows with their edges.
+        assert_eq!(txs.len(), 3 + 2 * 2);
+        ww.process_updates(txs).await?;
+
+        let schema = ww.get_node(&source.schema_id()).unwrap();
+        assert_eq!(schema.kind, NodeKind::Schema);
+        assert!(
+            matches!(&schema.data_blob[&1], DataBlob::Entry(name, fields)
+            if name == "field" && fields["name"] == DataBlob::Text("age".into())
+                && fields["type"] == DataBlob::Text("int".into()))
+        );
+        let alice = ww.get_node(&source.row_id(0).unwrap()).unwrap();
+        assert_eq!(alice.label, "Alice");
+        let DataBlob::Schema(id, values) = &alice.data_blob[&0] else {
+            panic!("Expected the values of the row");
+        };
+        assert_eq!(id, &schema.id);
+        assert_eq!(values[1], DataBlob::Int(31.into()));
+        assert_eq!(values[2], DataBlob::Float(BigFloat::from_f64(1.7)));
+        assert_eq!(
+            ww.get_node(&source.row_id(1).unwrap()).unwrap().data_blob[&0],
+            DataBlob::Schema(
+                schema.id.clone(),
+                vec![
+                    DataBlob::Text("Bob".into()),
+                    DataBlob::Text("".into()),
+                    DataBlob::Float(BigFloat::from_f64(1.85)),
+                    front_matter::date("1990-07-01").unwrap(),
+                ]
+            )
+        );
+        assert!(source.get_updates().await?.is_empty());
+
+        // Editing a cell only changes it in the file.
+        let mut values = values.clone();
+        values[1] = DataBlob::Int(32.into());
+        let tx = Transaction::update_node(
+            alice.id.clone(),
+            vec![NodeUpdate::DataBlob(
+                0,
+                DataBlob::Schema(id.clone(), values),
+            )],
+        );
+        source.add_tx(vec![tx.clone()]).await?;
+        ww.process_updates(vec![tx]).await?;
+        assert_eq!(
+            source.disk.read_file(&["people.csv"]).await?,
+            TABLE.replace("31", "32")
+        );
+        assert!(source.get_updates().await?.is_empty());
+
+        // The label is the first cell, so its value follows.
+        let tx = Transaction::update_node(alice.id.clone(), vec![NodeUpdate::Label("Ann".into())]);
+        source.add_tx(vec![tx]).await?;
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 1);
+        ww.process_updates(txs).await?;
+        let DataBlob::Schema(_, values) = &ww.get_node(&alice.id).unwrap().data_blob[&0] else {
+            panic!("Expected the values of the row");
+        };
+        assert_eq!(values[0], DataBlob::Text("Ann".into()));
+
+        // Writing text to a number changes the type of the column.
+        let values = vec![
+            DataBlob::Text("Bob".into()),
+            DataBlob::Text("unknown".into()),
+            DataBlob::Float(BigFloat::from_f64(1.85)),
+            front_matter::date("1990-07-01").unwrap(),
+        ];
+        let bob = source.row_id(1).unwrap();
+        let tx = Transaction::update_node(
+            bob.clone(),
+            vec![NodeUpdate::DataBlob(
+                0,
+                DataBlob::Schema(schema.id.clone(), values.clone()),
+            )],
+        );
+        source.add_tx(vec![tx.clone()]).await?;
+        ww.process_updates(vec![tx]).await?;
+        let txs = source.get_updates().await?;
+        // The schema and Alice's age change.
+        assert_eq!(txs.len(), 2);
+        ww.process_updates(txs).await?;
+
+        // Deleting a row removes it from the file.
+        source
+            .add_tx(vec![Transaction::update_node(
+                alice.id.clone(),
+                vec![NodeUpdate::Delete],
+            )])
+            .await?;
+        assert_eq!(
+            source.disk.read_file(&["people.csv"]).await?,
+            "name,age,height,born\nBob,unknown,1.85,1990-07-01\n"
+        );
+        assert!(source.get_updates().await?.is_empty());
+        ww.process_updates(vec![Transaction::update_node(
+            alice.id.clone(),
+            vec![NodeUpdate::Delete],
+        )])
+        .await?;
+        assert_eq!(source.row_id(0), Some(bob.clone()));
+        assert_eq!(ww.get_node(&bob).unwrap().label, "Bob");
+
+        // A row inserted at the top only adds its node.
+        source
+            .disk
+            .overwrite(
+                &["people.csv"],
+                b"name,age,height,born\nCarol,40,1.6,1985-01-01\nBob,unknown,1.85,1990-07-01\n",
+            )
+            .await?;
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 2);
+        ww.process_updates(txs).await?;
+        assert_eq!(source.row_id(1), Some(bob.clone()));
+        let carol = source.row_id(0).unwrap();
+        assert_eq!(ww.get_node(&carol).unwrap().label, "Carol");
+
+        // After a restart, the rows keep their nodes, even the edited ones.
+        let mut state = ww.state();
+        state
+            .source_root
+            .insert(source.get_id(), source.id.root_node());
+        let count = state.transactions.len();
+        let mut ww = WorldView::from_state(state);
+        ww.add_source(Box::new(SourceCsv::new(
+            source.disk.clone(),
+            &["people.csv"],
+        )))
+        .await?;
+        assert_eq!(ww.state().transactions.len(), count);
+        assert_eq!(ww.get_node(&carol).unwrap().label, "Carol");
+        assert_eq!(ww.get_node(&bob).unwrap().label, "Bob");
+        Ok(())
+    }
+}
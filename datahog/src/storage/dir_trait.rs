@@ -4,6 +4,12 @@
 use std::collections::HashMap;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use flarch::nodeids::U256;
+
+use crate::{impls::timestamp_now, structs::Timestamp};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DirectoryEntry {
@@ -11,28 +17,120 @@ pub enum DirectoryEntry {
     File(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Directory,
+    File,
+}
+
+/// Information about a file or a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: EntryKind,
+    /// The size of a file in bytes, 0 for a directory.
+    pub size: u64,
+    /// The time of the last modification, in nanoseconds since the epoch.
+    pub modified: Timestamp,
+}
+
+/// A change reported by [Reader::watch], with paths relative to the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirEvent {
+    /// A file or directory was created, modified or removed.
+    Changed(Vec<String>),
+    /// A file or directory was renamed or moved.
+    Renamed(Vec<String>, Vec<String>),
+    /// Some changes have been lost, so everything must be read again.
+    Rescan,
+}
+
+/// The changes of a directory, see [Reader::watch].
+pub struct DirWatch {
+    pub events: mpsc::UnboundedReceiver<DirEvent>,
+    /// The directory is watched until this is dropped.
+    pub guard: Box<dyn Send + Sync>,
+}
+
 #[async_trait]
 pub trait Reader {
+    /// Returns where the data is stored, e.g., the canonical root path.
+    /// It must be stable across restarts, as it is used to derive the
+    /// [crate::structs::SourceID].
+    fn location(&self) -> String;
     async fn read_directory(&self, path: &[&str]) -> anyhow::Result<Vec<DirectoryEntry>>;
-    async fn read_file(&self, path: &[&str]) -> anyhow::Result<String>;
+    async fn read_bytes(&self, path: &[&str]) -> anyhow::Result<Bytes>;
+    /// Reads a file, which must be valid UTF-8.
+    async fn read_file(&self, path: &[&str]) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.read_bytes(path).await?.to_vec())?)
+    }
+    async fn metadata(&self, path: &[&str]) -> anyhow::Result<Metadata>;
+    /// Returns the changes of the directory as they happen, or [None] if they
+    /// cannot be watched.
+    fn watch(&self) -> anyhow::Result<Option<DirWatch>> {
+        Ok(None)
+    }
 }
 
 #[async_trait]
 pub trait Writer {
     async fn clean(&mut self) -> anyhow::Result<()>;
     async fn create_directory(&mut self, path: &[&str]) -> anyhow::Result<()>;
-    async fn write_file(&mut self, path: &[&str], content: &str) -> anyhow::Result<()>;
+    /// Creates a new file, and fails if it already exists.
+    async fn write_bytes(&mut self, path: &[&str], content: &[u8]) -> anyhow::Result<()>;
+    /// Creates a new file, and fails if it already exists.
+    async fn write_file(&mut self, path: &[&str], content: &str) -> anyhow::Result<()> {
+        self.write_bytes(path, content.as_bytes()).await
+    }
+    /// Replaces the content of a file, or creates it.
+    async fn overwrite(&mut self, path: &[&str], content: &[u8]) -> anyhow::Result<()>;
+    /// Removes a file, or a directory with all its content.
+    async fn remove(&mut self, path: &[&str]) -> anyhow::Result<()>;
+    /// Renames or moves a file or a directory.
+    /// The parent directory of `to` must exist, and `to` itself must not.
+    async fn rename(&mut self, from: &[&str], to: &[&str]) -> anyhow::Result<()>;
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmulatedFile {
+    pub content: Bytes,
+    pub modified: Timestamp,
+}
+
+impl EmulatedFile {
+    pub fn new(content: impl Into<Bytes>) -> Self {
+        Self {
+            content: content.into(),
+            modified: timestamp_now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq)]
 pub struct EmulatedDir {
-    pub files: HashMap<String, String>,
+    /// A random name, so every emulated directory has its own
+    /// [Reader::location]. Clones keep the name.
+    pub name: String,
+    pub files: HashMap<String, EmulatedFile>,
     pub dirs: HashMap<String, EmulatedDir>,
 }
 
+/// Directories with the same content are equal, whatever their name.
+impl PartialEq for EmulatedDir {
+    fn eq(&self, other: &Self) -> bool {
+        self.files == other.files && self.dirs == other.dirs
+    }
+}
+
+impl Default for EmulatedDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EmulatedDir {
     pub fn new() -> Self {
         EmulatedDir {
+            name: U256::rnd().to_string(),
             files: HashMap::new(),
             dirs: HashMap::new(),
         }
@@ -51,16 +149,51 @@ impl EmulatedDir {
 
     fn store_file(&mut self, dirs: Vec<String>, file: String, content: &str) {
         if dirs.is_empty() {
-            self.files.insert(file, content.to_string());
+            self.files
+                .insert(file, EmulatedFile::new(content.to_string()));
         } else {
             let dir = dirs.first().unwrap();
             self.dirs
                 .entry(dir.clone())
-                .or_insert_with(EmulatedDir::new)
+                .or_default()
                 .store_file(dirs[1..].to_vec(), file, content);
         }
     }
 
+    /// Returns the directory at the given path, without creating it.
+    fn dir(&self, path: &[&str]) -> anyhow::Result<&EmulatedDir> {
+        match path.split_first() {
+            None => Ok(self),
+            Some((dir, rest)) => match self.dirs.get(*dir) {
+                Some(dir) => dir.dir(rest),
+                None => anyhow::bail!("Directory '{dir}' not found"),
+            },
+        }
+    }
+
+    /// Returns the directory at the given path, without creating it.
+    fn dir_mut(&mut self, path: &[&str]) -> anyhow::Result<&mut EmulatedDir> {
+        match path.split_first() {
+            None => Ok(self),
+            Some((dir, rest)) => match self.dirs.get_mut(*dir) {
+                Some(dir) => dir.dir_mut(rest),
+                None => anyhow::bail!("Directory '{dir}' not found"),
+            },
+        }
+    }
+
+    /// Returns the directory at the given path, creating it if necessary.
+    fn dir_create(&mut self, path: &[&str]) -> &mut EmulatedDir {
+        match path.split_first() {
+            None => self,
+            Some((dir, rest)) => self
+                .dirs
+                .entry(dir.to_string())
+                .or_default()
+                .dir_create(rest),
+        }
+    }
+
     fn path_to_dir_file(path: &str) -> (Vec<String>, String) {
         let mut parts = path
             .split('/')
@@ -73,39 +206,58 @@ impl EmulatedDir {
 
 #[async_trait]
 impl Reader for EmulatedDir {
-    async fn read_directory(&self, path: &[&str]) -> anyhow::Result<Vec<DirectoryEntry>> {
-        if path.is_empty() {
-            let mut entries = Vec::new();
-
-            for name in self.dirs.keys() {
-                entries.push(DirectoryEntry::Directory(name.clone()));
-            }
+    fn location(&self) -> String {
+        format!("emulated:{}", self.name)
+    }
 
-            for name in self.files.keys() {
-                entries.push(DirectoryEntry::File(name.clone()));
-            }
+    async fn read_directory(&self, path: &[&str]) -> anyhow::Result<Vec<DirectoryEntry>> {
+        let dir = self.dir(path)?;
+        let mut entries = dir
+            .dirs
+            .keys()
+            .map(|name| DirectoryEntry::Directory(name.clone()))
+            .chain(
+                dir.files
+                    .keys()
+                    .map(|name| DirectoryEntry::File(name.clone())),
+            )
+            .collect::<Vec<_>>();
+        entries.sort();
+        Ok(entries)
+    }
 
-            entries.sort();
-            Ok(entries)
-        } else {
-            match self.dirs.get(path[0]) {
-                Some(dir) => dir.read_directory(&path[1..]).await,
-                None => anyhow::bail!("Directory '{}' not found", path[0]),
-            }
+    async fn read_bytes(&self, path: &[&str]) -> anyhow::Result<Bytes> {
+        let Some((name, dir)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        match self.dir(dir)?.files.get(*name) {
+            Some(file) => Ok(file.content.clone()),
+            None => anyhow::bail!("File '{name}' not found"),
         }
     }
 
-    async fn read_file(&self, path: &[&str]) -> anyhow::Result<String> {
-        if path.len() > 1 {
-            match self.dirs.get(path[0]) {
-                Some(dir) => dir.read_file(&path[1..]).await,
-                None => anyhow::bail!("Directory '{}' not found", path[0]),
-            }
+    /// Directories have no modification time in the emulation, so it is
+    /// always 0.
+    async fn metadata(&self, path: &[&str]) -> anyhow::Result<Metadata> {
+        let dir_meta = Metadata {
+            kind: EntryKind::Directory,
+            size: 0,
+            modified: 0,
+        };
+        let Some((name, dir)) = path.split_last() else {
+            return Ok(dir_meta);
+        };
+        let dir = self.dir(dir)?;
+        if let Some(file) = dir.files.get(*name) {
+            Ok(Metadata {
+                kind: EntryKind::File,
+                size: file.content.len() as u64,
+                modified: file.modified,
+            })
+        } else if dir.dirs.contains_key(*name) {
+            Ok(dir_meta)
         } else {
-            match self.files.get(path[0]) {
-                Some(content) => Ok(content.clone()),
-                None => anyhow::bail!("File '{}' not found", path[0]),
-            }
+            anyhow::bail!("'{}' not found", path.join("/"))
         }
     }
 }
@@ -119,51 +271,113 @@ impl Writer for EmulatedDir {
     }
 
     async fn create_directory(&mut self, path: &[&str]) -> anyhow::Result<()> {
-        match path.len() {
-            0 => anyhow::bail!("Invalid path"),
-            1 => {
-                let dir = path[0];
-                match self.dirs.get(dir) {
-                    Some(dir) => anyhow::bail!("Directory '{:?}' already exists", dir),
-                    None => {
-                        self.dirs.insert(dir.to_string(), EmulatedDir::new());
-                        Ok(())
-                    }
-                }
-            }
-            _ => {
-                let dir = path[0];
-                self.dirs
-                    .entry(dir.to_string())
-                    .or_insert_with(EmulatedDir::new)
-                    .create_directory(&path[1..])
-                    .await
-            }
+        let Some((name, dir)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        let dir = self.dir_create(dir);
+        if dir.dirs.contains_key(*name) {
+            anyhow::bail!("Directory '{name}' already exists");
         }
+        dir.dirs.insert(name.to_string(), EmulatedDir::new());
+        Ok(())
     }
 
-    async fn write_file(&mut self, path: &[&str], content: &str) -> anyhow::Result<()> {
-        match path.len() {
-            0 => anyhow::bail!("Invalid path"),
-            1 => {
-                let file = path[0];
-                match self.files.get(file) {
-                    Some(file) => anyhow::bail!("File '{:?}' already exists", file),
-                    None => {
-                        self.files.insert(file.to_string(), content.to_string());
-                        Ok(())
-                    }
-                }
-            }
-            _ => {
-                let dir = path[0];
-                self.dirs
-                    .entry(dir.to_string())
-                    .or_insert_with(EmulatedDir::new)
-                    .write_file(&path[1..], content)
-                    .await
-            }
+    async fn write_bytes(&mut self, path: &[&str], content: &[u8]) -> anyhow::Result<()> {
+        let Some((name, dir)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        let dir = self.dir_create(dir);
+        if dir.files.contains_key(*name) {
+            anyhow::bail!("File '{name}' already exists");
         }
+        dir.files
+            .insert(name.to_string(), EmulatedFile::new(content.to_vec()));
+        Ok(())
+    }
+
+    async fn overwrite(&mut self, path: &[&str], content: &[u8]) -> anyhow::Result<()> {
+        let Some((name, dir)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        let dir = self.dir_create(dir);
+        if dir.dirs.contains_key(*name) {
+            anyhow::bail!("'{name}' is a directory");
+        }
+        dir.files
+            .insert(name.to_string(), EmulatedFile::new(content.to_vec()));
+        Ok(())
+    }
+
+    async fn remove(&mut self, path: &[&str]) -> anyhow::Result<()> {
+        let Some((name, dir)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        let dir = self.dir_mut(dir)?;
+        if dir.files.remove(*name).is_none() && dir.dirs.remove(*name).is_none() {
+            anyhow::bail!("'{}' not found", path.join("/"));
+        }
+        Ok(())
+    }
+
+    async fn rename(&mut self, from: &[&str], to: &[&str]) -> anyhow::Result<()> {
+        let (Some((from_name, from_dir)), Some((to_name, to_dir))) =
+            (from.split_last(), to.split_last())
+        else {
+            anyhow::bail!("Invalid path");
+        };
+        if to.starts_with(from) {
+            anyhow::bail!("Cannot move '{}' into itself", from.join("/"));
+        }
+        let dest = self.dir_mut(to_dir)?;
+        if dest.files.contains_key(*to_name) || dest.dirs.contains_key(*to_name) {
+            anyhow::bail!("'{}' already exists", to.join("/"));
+        }
+        let src = self.dir_mut(from_dir)?;
+        if let Some(file) = src.files.remove(*from_name) {
+            self.dir_mut(to_dir)?
+                .files
+                .insert(to_name.to_string(), file);
+        } else if let Some(dir) = src.dirs.remove(*from_name) {
+            self.dir_mut(to_dir)?.dirs.insert(to_name.to_string(), dir);
+        } else {
+            anyhow::bail!("'{}' not found", from.join("/"));
+        }
+        Ok(())
+    }
+}
+
+/// An [EmulatedDir] which fails to read the files named in `failing`, to
+/// test how the sources handle errors.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct FailingDir {
+    pub dir: EmulatedDir,
+    pub failing: Vec<String>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Reader for FailingDir {
+    fn location(&self) -> String {
+        self.dir.location()
+    }
+
+    async fn read_directory(&self, path: &[&str]) -> anyhow::Result<Vec<DirectoryEntry>> {
+        self.dir.read_directory(path).await
+    }
+
+    async fn read_bytes(&self, path: &[&str]) -> anyhow::Result<Bytes> {
+        if path
+            .last()
+            .is_some_and(|name| self.failing.iter().any(|f| f == name))
+        {
+            anyhow::bail!("Cannot read '{}'", path.join("/"));
+        }
+        self.dir.read_bytes(path).await
+    }
+
+    async fn metadata(&self, path: &[&str]) -> anyhow::Result<Metadata> {
+        self.dir.metadata(path).await
     }
 }
 
@@ -172,13 +386,26 @@ mod tests {
     use super::*;
 
     fn test_dir() -> EmulatedDir {
-        EmulatedDir::new_from_string(&vec![
+        EmulatedDir::new_from_string(&[
             ("file1", "content1"),
             ("dir1/file2", "content2"),
             ("dir1/dir2/file3", "content3"),
         ])
     }
 
+    #[test]
+    fn test_location() {
+        let ed = test_dir();
+        assert_eq!(ed.location(), ed.clone().location());
+        assert_ne!(ed.location(), test_dir().location());
+
+        // The name is not part of the content.
+        let mut other = EmulatedDir::new();
+        other.files = ed.files.clone();
+        other.dirs = ed.dirs.clone();
+        assert_eq!(ed, other);
+    }
+
     #[tokio::test]
     async fn test_new_from_string() {
         let ed = test_dir();
@@ -258,4 +485,57 @@ mod tests {
         );
         assert_eq!(&ed.read_file(&["new_file"]).await.unwrap(), "new_content");
     }
+
+    #[tokio::test]
+    async fn test_remove_rename() -> anyhow::Result<()> {
+        let mut ed = test_dir();
+        ed.rename(&["file1"], &["dir1", "dir2", "file4"]).await?;
+        assert_eq!(&ed.read_file(&["dir1", "dir2", "file4"]).await?, "content1");
+        assert!(ed.files.is_empty());
+        ed.rename(&["dir1", "dir2"], &["dir3"]).await?;
+        assert_eq!(&ed.read_file(&["dir3", "file3"]).await?, "content3");
+        assert!(ed.rename(&["dir3"], &["dir3", "sub"]).await.is_err());
+        assert!(
+            ed.rename(&["dir3", "file3"], &["dir1", "file2"])
+                .await
+                .is_err()
+        );
+        assert!(ed.rename(&["missing"], &["dir1", "missing"]).await.is_err());
+
+        ed.remove(&["dir1", "file2"]).await?;
+        assert!(ed.read_file(&["dir1", "file2"]).await.is_err());
+        ed.remove(&["dir3"]).await?;
+        assert_eq!(
+            ed.read_directory(&[]).await?,
+            vec![DirectoryEntry::Directory("dir1".to_string())]
+        );
+        assert!(ed.remove(&["dir3"]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bytes_metadata() -> anyhow::Result<()> {
+        let mut ed = test_dir();
+        let binary = [0u8, 159, 146, 150];
+        ed.write_bytes(&["dir1", "binary"], &binary).await?;
+        assert_eq!(&ed.read_bytes(&["dir1", "binary"]).await?[..], &binary);
+        assert!(ed.read_file(&["dir1", "binary"]).await.is_err());
+        assert!(ed.write_bytes(&["dir1", "binary"], b"").await.is_err());
+
+        let before = ed.metadata(&["file1"]).await?;
+        assert_eq!(before.kind, EntryKind::File);
+        assert_eq!(before.size, 8);
+        ed.overwrite(&["file1"], b"new").await?;
+        ed.overwrite(&["dir3", "file4"], b"created").await?;
+        assert!(ed.overwrite(&["dir1"], b"").await.is_err());
+        let after = ed.metadata(&["file1"]).await?;
+        assert_eq!(after.size, 3);
+        assert!(after.modified >= before.modified);
+        assert_eq!(&ed.read_file(&["dir3", "file4"]).await?, "created");
+
+        assert_eq!(ed.metadata(&["dir1"]).await?.kind, EntryKind::Directory);
+        assert_eq!(ed.metadata(&[]).await?.kind, EntryKind::Directory);
+        assert!(ed.metadata(&["missing"]).await.is_err());
+        Ok(())
+    }
 }
@@ -1,142 +1,1255 @@
 //! This source reads data from disk and creates a graph.
 //! V0 does the following:
-//! - reads md files as a graph
-//!   - interpreting titles and sub-titles as nodes and edges
-//! - reads other files as nodes with delayed data loading
+//! - reads files depending on their MIME type, see [crate::storage::handlers]:
+//!   md files as a tree of blocks, other text files inline, and binary files by
+//!   their hash
 //! - creates edges between the nodes based on the directory structure
+//! - resolves the wiki-links, hashtags and front-matter tags of md files into
+//!   edges
+//! - skips files and directories matching the ignore patterns of the
+//!   [DiskConfig] and of the `.datahogignore` file, and files which are too big
+//! - polls the directory for changes, or watches it if [DiskConfig::watch] is
+//!   set, and only reads the changed paths
 //! - writes the graph back to disk
 //!
 //! A lot of extensions are possible:
-//! - use git-history to integrate outside changes
 //! - read other file formats
+//!
+//! The history of a git repository is imported with
+//! [crate::storage::git::SourceGit], which uses a [SourceDisk] for each commit.
+
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::Result;
 use async_recursion::async_recursion;
 use bytes::Bytes;
+use either::Either;
+use flarch::{
+    nodeids::U256,
+    tasks::{spawn_local, wait},
+};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use tokio::sync::mpsc;
 
 use crate::{
-    storage::dir_trait::{DirectoryEntry, Reader, Writer},
-    structs::{DataBlob, Edge, Node, NodeID, Source, SourceID, Transaction},
+    impls::timestamp_now,
+    markdown::{self, front_matter},
+    objects::node_diff,
+    storage::{
+        dir_trait::{DirEvent, DirWatch, DirectoryEntry, EntryKind, Metadata, Reader, Writer},
+        handlers::{FileHandler, Handlers, detect_mime},
+    },
+    structs::{
+        DataBlob, Edge, EdgeAction, EdgeID, EdgeKind, Node, NodeID, NodeKind, NodeUpdate, Record,
+        RecordCUD, Source, SourceID, SyncMode, Timestamp, Transaction, Validity,
+    },
 };
 
+/// What the last scan found at a path.
+#[derive(Debug, Clone)]
+struct Scanned {
+    id: NodeID,
+    /// The [EdgeKind::Contains] edge to the parent directory.
+    ///
+    /// [EdgeKind::Contains]: crate::structs::EdgeKind::Contains
+    edge: Edge,
+    /// The hash of the content of a file, or [None] for a directory.
+    hash: Option<U256>,
+    meta: Metadata,
+    /// The [Node] of a file, as it has been imported and updated, or [None]
+    /// for a directory.
+    node: Option<Node>,
+}
+
+/// A file or directory with its [Metadata].
+type Found = (Vec<String>, Metadata);
+
+/// The links and tags of a markdown file, see [SourceDisk::update_links].
+#[derive(Debug, Clone, Default)]
+struct NoteLinks {
+    /// The keys of the linked files, see [link_key].
+    targets: HashSet<String>,
+    /// The [EdgeKind::Reference] edges of the links, and the
+    /// [EdgeKind::Definition] edges of the tags.
+    edges: HashMap<EdgeID, Edge>,
+    /// The placeholders of unresolved links, and the labels of the tags.
+    nodes: HashMap<NodeID, Node>,
+    /// The [EdgeKind::Reference] edges in the order of the file.
+    refs: Vec<Edge>,
+}
+
+/// Files modified less than this before the previous scan are read again,
+/// even if their size and modification time didn't change, as they might
+/// have been changed again within the granularity of the modification time.
+const RACY_NS: Timestamp = 2_000_000_000;
+
+/// The file in the root directory with additional ignore patterns.
+pub const IGNORE_FILE: &str = ".datahogignore";
+
+/// Which files and directories a [SourceDisk] imports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskConfig {
+    /// Patterns in the `.gitignore` format of the files and directories to
+    /// skip, relative to the root directory.
+    /// The patterns of the [IGNORE_FILE] are applied after these.
+    pub ignore: Vec<String>,
+    /// Files bigger than this are skipped.
+    pub max_file_size: Option<u64>,
+    /// Watches the directory instead of polling it, if the [Reader] supports
+    /// it. The changes are read once no new change happened for this long.
+    pub watch: Option<Duration>,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        Self {
+            ignore: [
+                ".git/",
+                "node_modules/",
+                "*.swp",
+                "*~",
+                ".DS_Store",
+                "*.datahog-tmp",
+            ]
+            .map(String::from)
+            .to_vec(),
+            max_file_size: None,
+            watch: None,
+        }
+    }
+}
+
+/// What the last call to [Source::get_updates] found on disk.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanSummary {
+    /// How many files and directories are imported.
+    pub imported: usize,
+    /// Files and directories matching an ignore pattern.
+    /// The content of an ignored directory is not listed.
+    pub ignored: Vec<Vec<String>>,
+    /// Files bigger than [DiskConfig::max_file_size], with their size.
+    pub too_big: Vec<(Vec<String>, u64)>,
+}
+
 #[derive(Debug)]
 pub struct SourceDisk<RW>
 where
     RW: Reader + Writer + std::fmt::Debug + Sync + Send,
 {
     disk: RW,
+    id: SourceID,
     read: bool,
+    scanned: HashMap<Vec<String>, Scanned>,
+    /// The [NodeID]s and [EdgeKind::Contains] edges of the paths before a
+    /// restart, see [Source::restore]. They are used by the first scan.
+    restored: HashMap<Vec<String>, (NodeID, Edge)>,
+    /// When the previous scan started.
+    scanned_at: Timestamp,
+    /// Nodes created in the [crate::worldview::WorldView] which are written to
+    /// disk once they're contained in a directory.
+    pending: HashMap<NodeID, Node>,
+    /// The [EdgeKind::Reference] edges of the wiki-links, and the
+    /// [EdgeKind::Definition] edges of the hashtags, of all markdown files.
+    links: HashMap<EdgeID, Edge>,
+    /// The placeholders of unresolved wiki-links, and the labels of hashtags.
+    link_nodes: HashMap<NodeID, Node>,
+    /// The links and tags of each markdown file.
+    notes: HashMap<Vec<String>, NoteLinks>,
+    /// The hashes of the files when the links were last updated.
+    linked: HashMap<Vec<String>, U256>,
+    config: DiskConfig,
+    handlers: Handlers,
+    /// The patterns of the [DiskConfig] and the [IGNORE_FILE].
+    ignore: Gitignore,
+    summary: ScanSummary,
+    /// The changes reported by the [DirWatch], if the directory is watched.
+    events: Option<Arc<Mutex<Vec<DirEvent>>>>,
 }
 
 #[async_trait::async_trait]
 impl<RW: Reader + Writer + std::fmt::Debug + Sync + Send> Source for SourceDisk<RW> {
+    /// The first call returns the whole directory, the following calls only
+    /// the changes since the previous call.
+    /// If the directory is watched, only the paths of the [DirEvent]s are
+    /// read again.
     async fn get_updates(&mut self) -> anyhow::Result<Vec<Transaction>> {
+        let mut txs = vec![];
         if !self.read {
-            self.read = true;
             // Start with root directory (empty path) and a labelled parent node.
-            let root = Node::label("root");
-            let txs = self.read_dir(&root.id, vec![]).await?;
-            Ok([vec![Transaction::create_node(root)], txs].concat())
-        } else {
-            Ok(vec![])
+            let mut root = Node::label("root");
+            root.id = self.id.root_node();
+            txs.push(Transaction::create_node(root));
         }
+        let now = timestamp_now();
+        let events = match (&self.events, self.read) {
+            (Some(events), true) => Some(std::mem::take(&mut *events.lock().unwrap())),
+            _ => None,
+        };
+        let rescan = |event: &DirEvent| match event {
+            DirEvent::Changed(path) => path == &[IGNORE_FILE],
+            DirEvent::Renamed(from, to) => from == &[IGNORE_FILE] || to == &[IGNORE_FILE],
+            DirEvent::Rescan => true,
+        };
+        match events {
+            Some(events) if !events.iter().any(rescan) => {
+                txs.extend(self.read_events(events).await?);
+            }
+            _ => {
+                self.ignore = self.read_ignore().await?;
+                let mut found = vec![];
+                let mut summary = ScanSummary::default();
+                self.read_dir(vec![], &mut found, &mut summary).await?;
+                summary.imported = found.len();
+                self.summary = summary;
+                txs.extend(self.diff(found, &[vec![]]).await?);
+            }
+        }
+        txs.extend(self.update_links());
+        self.read = true;
+        self.scanned_at = now;
+        Ok(txs)
     }
 
-    async fn add_tx(&mut self, _txs: Vec<Transaction>) -> Result<()> {
-        todo!()
+    /// Writes the changes back to the disk:
+    /// - a new [Node] is written once an [EdgeKind::Contains] edge puts it in
+    ///   a directory: [NodeKind::Label] nodes as directories, all other nodes
+    ///   as files
+    /// - changing the label renames the file or directory
+    /// - changing the [DataBlob] at index 0 rewrites the file
+    /// - changing the container of an [EdgeKind::Contains] edge moves the file
+    ///   or directory
+    /// - deleting the [Node] removes the file or directory
+    ///
+    /// The next call to [Source::get_updates] won't return these changes.
+    async fn add_tx(&mut self, txs: Vec<Transaction>) -> Result<()> {
+        for record in txs.into_iter().flat_map(|tx| tx.records) {
+            match record {
+                Record::Node(rc) => self.write_node(rc).await?,
+                Record::Edge(rc) => self.write_edge(rc).await?,
+            }
+        }
+        Ok(())
     }
 
-    /// Returns the unique ID of this source.
+    /// Returns the unique ID of this source, derived from the location of the disk.
     fn get_id(&self) -> SourceID {
-        todo!()
+        self.id.clone()
+    }
+
+    /// Collects the paths of the files and directories from the
+    /// [EdgeKind::Contains] edges below the root, so they keep their
+    /// [NodeID]s, even if they have been renamed or moved.
+    fn restore(&mut self, root: &NodeID, nodes: &HashMap<NodeID, Node>) {
+        let mut visited = HashSet::from([root.clone()]);
+        let mut dirs = vec![(vec![], root.clone())];
+        while let Some((path, id)) = dirs.pop() {
+            for edge in nodes.get(&id).map(|n| n.edges.iter()).into_iter().flatten() {
+                if let EdgeKind::Contains { container, object } = &edge.kind
+                    && container == &id
+                    && let Some(node) = nodes.get(object)
+                    && visited.insert(object.clone())
+                {
+                    let path = [path.clone(), vec![node.label.clone()]].concat();
+                    let edge = Edge {
+                        history: vec![],
+                        ..edge.clone()
+                    };
+                    self.restored.insert(path.clone(), (object.clone(), edge));
+                    dirs.push((path, object.clone()));
+                }
+            }
+        }
+    }
+
+    /// Watches the directory if [DiskConfig::watch] is set and the [Reader]
+    /// supports it, else polls it.
+    fn sync_mode(&mut self) -> SyncMode {
+        if let Some(quiet) = self.config.watch {
+            match self.disk.watch() {
+                Ok(Some(watch)) => return SyncMode::Notify(self.debounce(watch, quiet)),
+                Ok(None) => log::warn!("Cannot watch {}, polling it", self.disk.location()),
+                Err(e) => log::warn!("Watching {} failed: {e:?}", self.disk.location()),
+            }
+        }
+        SyncMode::Poll(Duration::from_secs(10))
     }
 }
 
 impl<RW: Reader + Writer + std::fmt::Debug + Sync + Send> SourceDisk<RW> {
     pub fn new(disk: RW) -> Self {
-        Self { disk, read: false }
+        Self::with_config(disk, DiskConfig::default())
     }
 
-    #[async_recursion]
-    async fn read_dir(
-        &mut self,
+    pub fn with_config(disk: RW, config: DiskConfig) -> Self {
+        let id = SourceID::hash_domain_parts("SourceDisk", &[disk.location().as_bytes()]);
+        Self {
+            disk,
+            id,
+            read: false,
+            scanned: HashMap::new(),
+            restored: HashMap::new(),
+            scanned_at: 0,
+            pending: HashMap::new(),
+            links: HashMap::new(),
+            link_nodes: HashMap::new(),
+            notes: HashMap::new(),
+            linked: HashMap::new(),
+            config,
+            handlers: Handlers::default(),
+            ignore: Gitignore::empty(),
+            summary: ScanSummary::default(),
+            events: None,
+        }
+    }
+
+    /// Returns the [FileHandler]s used to import and write the files.
+    pub fn handlers(&mut self) -> &mut Handlers {
+        &mut self.handlers
+    }
+
+    /// Returns what the last call to [Source::get_updates] imported and skipped.
+    pub fn summary(&self) -> &ScanSummary {
+        &self.summary
+    }
+
+    /// Returns the [Reader] and [Writer] of the files.
+    pub fn disk_mut(&mut self) -> &mut RW {
+        &mut self.disk
+    }
+
+    /// Returns the [NodeID] of the file or directory at `path`, if it has been
+    /// imported.
+    pub fn node_of(&self, path: &[String]) -> Option<NodeID> {
+        self.scanned.get(path).map(|s| s.id.clone())
+    }
+
+    /// Returns the ignore patterns of the [DiskConfig] and the [IGNORE_FILE].
+    async fn read_ignore(&self) -> Result<Gitignore> {
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in &self.config.ignore {
+            builder.add_line(None, pattern)?;
+        }
+        let root = self.disk.read_directory(&[]).await?;
+        if root.contains(&DirectoryEntry::File(IGNORE_FILE.into())) {
+            for line in self.disk.read_file(&[IGNORE_FILE]).await?.lines() {
+                builder.add_line(None, line)?;
+            }
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Returns true if the file or directory is ignored.
+    fn is_ignored(&self, path: &[String], kind: &EntryKind) -> bool {
+        self.ignore
+            .matched(path.join("/"), kind == &EntryKind::Directory)
+            .is_ignore()
+    }
+
+    /// Returns a stable [NodeID] for the file or directory at `path`.
+    /// Importing the same directory again gives the same [NodeID]s, so edges
+    /// pointing to these nodes are kept.
+    /// A renamed or moved entry keeps the [NodeID] of its first path, see
+    /// [SourceDisk::new_entry].
+    fn node_id(&self, path: &[&str]) -> NodeID {
+        NodeID::hash_domain_parts("SourceDisk", &[self.id.as_ref(), path.join("/").as_bytes()])
+    }
+
+    /// Returns the [NodeID] and the [EdgeKind::Contains] edge of a new entry
+    /// at `path`: the ones it had before a restart, else the ones derived from
+    /// the path.
+    /// If another entry already uses the derived [NodeID], e.g. because it
+    /// has been renamed from this path, a new [NodeID] is derived.
+    ///
+    /// [EdgeKind::Contains]: crate::structs::EdgeKind::Contains
+    fn new_entry(
+        &self,
+        path: &[String],
         parent: &NodeID,
-        path: Vec<&str>,
-    ) -> anyhow::Result<Vec<Transaction>> {
-        let mut transactions = Vec::new();
+        taken: &mut HashSet<NodeID>,
+    ) -> (NodeID, Edge) {
+        if let Some((id, edge)) = self.restored.get(path) {
+            let mut edge = edge.clone();
+            edge.kind = EdgeKind::Contains {
+                container: parent.clone(),
+                object: id.clone(),
+            };
+            return (id.clone(), edge);
+        }
+        let path_ref = path.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut id = self.node_id(&path_ref);
+        let mut count = 0u64;
+        while taken.contains(&id) {
+            count += 1;
+            id = NodeID::hash_domain_parts(
+                "SourceDisk",
+                &[
+                    self.id.as_ref(),
+                    path.join("/").as_bytes(),
+                    &count.to_le_bytes(),
+                ],
+            );
+        }
+        taken.insert(id.clone());
+        let edge = self.contains(parent, &id);
+        (id, edge)
+    }
 
-        // Read directory entries
-        let entries = self.disk.read_directory(&path).await?;
+    /// Returns a [EdgeKind::Contains] edge with an ID derived from its nodes.
+    ///
+    /// [EdgeKind::Contains]: crate::structs::EdgeKind::Contains
+    fn contains(&self, container: &NodeID, object: &NodeID) -> Edge {
+        let mut edge = Edge::contains(container.clone(), object.clone());
+        edge.id = EdgeID::hash_domain_parts("SourceDisk", &[container.as_ref(), object.as_ref()]);
+        edge
+    }
 
-        for entry in entries {
+    /// Collects all files and directories, parents before their children.
+    /// Skipped files and directories are added to the [ScanSummary].
+    #[async_recursion]
+    async fn read_dir(
+        &self,
+        path: Vec<String>,
+        found: &mut Vec<Found>,
+        summary: &mut ScanSummary,
+    ) -> anyhow::Result<()> {
+        let path_ref = path.iter().map(String::as_str).collect::<Vec<_>>();
+        for entry in self.disk.read_directory(&path_ref).await? {
             let mut entry_path = path.clone();
-            match entry {
-                DirectoryEntry::File(name) => {
-                    // Read file and process it
-                    log::debug!("Processing file: {name}");
-                    entry_path.push(&name);
-                    let content = self.disk.read_file(&entry_path).await?;
-
-                    transactions.extend(if name.ends_with(".md") {
-                        self.process_markdown(parent, name, content).await?
-                    } else {
-                        self.process_file(parent, name, content).await?
-                    });
+            let (DirectoryEntry::File(name) | DirectoryEntry::Directory(name)) = &entry;
+            log::trace!("Reading metadata: {name}");
+            entry_path.push(name.clone());
+            let entry_ref = entry_path.iter().map(String::as_str).collect::<Vec<_>>();
+            let meta = self.disk.metadata(&entry_ref).await?;
+            if self.skip(&entry_path, &meta, summary) {
+                continue;
+            }
+            found.push((entry_path.clone(), meta));
+            if let DirectoryEntry::Directory(_) = entry {
+                self.read_dir(entry_path, found, summary).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the entry is ignored or too big, and adds it to the
+    /// [ScanSummary].
+    fn skip(&self, path: &[String], meta: &Metadata, summary: &mut ScanSummary) -> bool {
+        if self.is_ignored(path, &meta.kind) {
+            log::debug!("Ignoring {path:?}");
+            summary.ignored.push(path.to_vec());
+            return true;
+        }
+        if meta.kind == EntryKind::File
+            && let Some(max) = self.config.max_file_size
+            && meta.size > max
+        {
+            log::debug!("Skipping {path:?} with {} bytes", meta.size);
+            summary.too_big.push((path.to_vec(), meta.size));
+            return true;
+        }
+        false
+    }
+
+    /// Collects the [DirEvent]s of the [DirWatch], and notifies the sync loop
+    /// once no new event arrived during `quiet`.
+    fn debounce(&mut self, watch: DirWatch, quiet: Duration) -> mpsc::Receiver<()> {
+        let events = Arc::new(Mutex::new(vec![]));
+        self.events = Some(events.clone());
+        let (tx, rx) = mpsc::channel(1);
+        let DirWatch {
+            events: mut rx_events,
+            guard,
+        } = watch;
+        spawn_local(async move {
+            let _guard = guard;
+            while let Some(event) = rx_events.recv().await {
+                events.lock().unwrap().push(event);
+                loop {
+                    wait(quiet).await;
+                    let mut more = false;
+                    while let Ok(event) = rx_events.try_recv() {
+                        events.lock().unwrap().push(event);
+                        more = true;
+                    }
+                    if !more {
+                        break;
+                    }
                 }
-                DirectoryEntry::Directory(name) => {
-                    // Create node for directory and link to parent
-                    log::debug!("Processing directory: {name}");
-                    let dir_node = Node::label(&name);
-                    let edge = Edge::contains(parent.clone(), dir_node.id.clone());
+                // A full channel means the events will be read anyway.
+                if let Err(mpsc::error::TrySendError::Closed(_)) = tx.try_send(()) {
+                    break;
+                }
+            }
+        });
+        rx
+    }
 
-                    entry_path.push(&name);
-                    transactions.extend(self.read_dir(&dir_node.id, entry_path).await?);
+    /// Reads the paths of the [DirEvent]s again, after moving the renamed
+    /// files and directories, so they keep their [NodeID]s.
+    async fn read_events(&mut self, events: Vec<DirEvent>) -> Result<Vec<Transaction>> {
+        let mut txs = vec![];
+        let mut paths = vec![];
+        for event in events {
+            match event {
+                DirEvent::Changed(path) => paths.push(path),
+                DirEvent::Renamed(from, to) => {
+                    txs.extend(self.rename(&from, &to));
+                    paths.extend([from, to]);
+                }
+                DirEvent::Rescan => {}
+            }
+        }
+        // New paths are read starting with their first ancestor which is not
+        // yet known.
+        let mut scopes: Vec<Vec<String>> = vec![];
+        for mut path in paths {
+            while path.len() > 1 && !self.scanned.contains_key(&path[..path.len() - 1]) {
+                path.pop();
+            }
+            scopes.push(path);
+        }
+        scopes.sort();
+        scopes.dedup_by(|path, prev| path.starts_with(prev));
 
-                    transactions.extend([
-                        Transaction::create_node(dir_node),
-                        Transaction::create_edge(edge),
-                    ]);
+        let mut found = vec![];
+        let mut summary = ScanSummary::default();
+        for scope in &scopes {
+            if !scope.is_empty() {
+                let scope_ref = scope.iter().map(String::as_str).collect::<Vec<_>>();
+                let Ok(meta) = self.disk.metadata(&scope_ref).await else {
+                    log::debug!("Removed: {scope:?}");
+                    continue;
+                };
+                if self.skip(scope, &meta, &mut summary) {
+                    continue;
+                }
+                let is_dir = meta.kind == EntryKind::Directory;
+                found.push((scope.clone(), meta));
+                if !is_dir {
+                    continue;
                 }
             }
+            self.read_dir(scope.clone(), &mut found, &mut summary)
+                .await?;
         }
+        txs.extend(self.diff(found, &scopes).await?);
 
-        Ok(transactions)
+        let in_scope = |path: &Vec<String>| scopes.iter().any(|s| path.starts_with(s));
+        self.summary.ignored.retain(|path| !in_scope(path));
+        self.summary.ignored.extend(summary.ignored);
+        self.summary.too_big.retain(|(path, _)| !in_scope(path));
+        self.summary.too_big.extend(summary.too_big);
+        self.summary.imported = self.scanned.len();
+        Ok(txs)
     }
 
-    async fn process_markdown(
-        &mut self,
-        parent: &NodeID,
-        file_name: String,
-        content: String,
-    ) -> anyhow::Result<Vec<Transaction>> {
-        // Placeholder — to be implemented later
-        self.process_file(parent, file_name, content).await
+    /// Moves a renamed file or directory to its new path, and returns the
+    /// [Transaction]s for its new label and parent.
+    /// Nothing is moved if the new path is ignored, or already exists, so the
+    /// entries are read again instead.
+    fn rename(&mut self, from: &[String], to: &[String]) -> Vec<Transaction> {
+        let Some(prev) = self.scanned.get(from) else {
+            return vec![];
+        };
+        if to.is_empty() || self.scanned.contains_key(to) || self.is_ignored(to, &prev.meta.kind) {
+            return vec![];
+        }
+        let parent = match to.len() {
+            1 => self.id.root_node(),
+            len => match self.scanned.get(&to[..len - 1]) {
+                Some(dir) if dir.hash.is_none() => dir.id.clone(),
+                _ => return vec![],
+            },
+        };
+        log::debug!("Renaming: {from:?} -> {to:?}");
+        self.move_scanned(from, to);
+        let entry = self.scanned.get_mut(to).unwrap();
+        let mut txs = vec![];
+        let name = to.last().cloned().unwrap_or_default();
+        if from.last() != to.last() {
+            if let Some(node) = &mut entry.node {
+                node.label = name.clone();
+            }
+            txs.push(Transaction::update_node(
+                entry.id.clone(),
+                vec![NodeUpdate::Label(name)],
+            ));
+        }
+        if !entry.edge.nodes().contains(&&parent) {
+            let update = EdgeAction::UpdateIDs(vec![parent, entry.id.clone()]);
+            entry.edge.update(update.clone());
+            txs.push(Transaction::update_edge(
+                entry.edge.id.clone(),
+                vec![update],
+            ));
+        }
+        txs
     }
 
-    async fn process_file(
+    /// Compares the entries found on disk with the previous scan, and returns
+    /// the [Transaction]s to go from one to the other.
+    /// Only the entries inside the `scopes` have been read again, all others
+    /// are kept as they are.
+    /// A removed file which reappears with the same content somewhere else is
+    /// treated as renamed or moved, so it keeps its [NodeID].
+    async fn diff(
         &mut self,
-        parent: &NodeID,
-        file_name: String,
-        content: String,
+        found: Vec<Found>,
+        scopes: &[Vec<String>],
     ) -> anyhow::Result<Vec<Transaction>> {
-        let mut file_node = Node::mime("text/plain".into(), file_name.into());
-        file_node
+        let in_scope = |path: &Vec<String>| scopes.iter().any(|s| path.starts_with(s));
+        let mut removed = self
+            .scanned
+            .iter()
+            .filter(|(path, prev)| {
+                in_scope(path)
+                    && !found
+                        .iter()
+                        .any(|(p, meta)| &p == path && meta.kind == prev.meta.kind)
+            })
+            .map(|(path, prev)| (path.clone(), prev.clone()))
+            .collect::<Vec<_>>();
+        removed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut scanned: HashMap<Vec<String>, Scanned> = self
+            .scanned
+            .iter()
+            .filter(|(path, _)| !in_scope(path))
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        // Includes the removed entries, which might be moved.
+        let mut taken = self
+            .scanned
+            .values()
+            .map(|s| s.id.clone())
+            .chain(self.restored.values().map(|(id, _)| id.clone()))
+            .collect::<HashSet<_>>();
+        let mut txs = vec![];
+        for (path, meta) in found {
+            let parent = match path.len() {
+                1 => self.id.root_node(),
+                len => scanned
+                    .get(&path[..len - 1])
+                    .map(|s| s.id.clone())
+                    .ok_or_else(|| anyhow::anyhow!("Parent of {path:?} not scanned"))?,
+            };
+            let path_ref = path.iter().map(String::as_str).collect::<Vec<_>>();
+            let name = path.last().cloned().unwrap_or_default();
+            let is_file = meta.kind == EntryKind::File;
+            let unchanged = self
+                .scanned
+                .get(&path)
+                .filter(|prev| prev.meta.kind == meta.kind);
+            // Only read files which are new, or whose size or modification
+            // time changed.
+            let content = match unchanged {
+                Some(prev) if prev.meta == meta && meta.modified < self.scanned_at - RACY_NS => {
+                    None
+                }
+                _ if is_file => Some(self.disk.read_bytes(&path_ref).await?),
+                _ => None,
+            };
+            let hash = match &content {
+                Some(content) => Some(U256::hash_data(content)),
+                None => unchanged.and_then(|prev| prev.hash),
+            };
+            let moved = (is_file && unchanged.is_none())
+                .then(|| removed.iter().position(|(_, prev)| prev.hash == hash))
+                .flatten();
+
+            let entry = if let Some(prev) = unchanged {
+                let mut node = prev.node.clone();
+                if let Some(content) = content
+                    && prev.hash != hash
+                    && let Some(old) = &prev.node
+                {
+                    log::debug!("Updating file: {path:?}");
+                    let mut new = self.file_node(old.id.clone(), &path_ref, content);
+                    new.label = old.label.clone();
+                    if is_markdown(&new) {
+                        // The references are updated by `update_links`.
+                        new.data_blob.extend(
+                            old.data_blob
+                                .iter()
+                                .filter(|(index, _)| **index >= markdown::LINK_BLOBS)
+                                .map(|(index, blob)| (*index, blob.clone())),
+                        );
+                    }
+                    // A file whose type changed is created again.
+                    txs.push(match node_diff(old, &new) {
+                        Ok(updates) => Transaction::update_node(prev.id.clone(), updates),
+                        Err(_) => Transaction::create_node(new.clone()),
+                    });
+                    node = Some(new);
+                }
+                Scanned {
+                    hash,
+                    meta,
+                    node,
+                    ..prev.clone()
+                }
+            } else if let Some(pos) = moved {
+                let (old_path, mut prev) = removed.remove(pos);
+                log::debug!("Moving file: {old_path:?} -> {path:?}");
+                if old_path.last() != path.last() {
+                    if let Some(node) = &mut prev.node {
+                        node.label = name.clone();
+                    }
+                    txs.push(Transaction::update_node(
+                        prev.id.clone(),
+                        vec![NodeUpdate::Label(name)],
+                    ));
+                }
+                if !prev.edge.nodes().contains(&&parent) {
+                    let update = EdgeAction::UpdateIDs(vec![parent, prev.id.clone()]);
+                    prev.edge.update(update.clone());
+                    txs.push(Transaction::update_edge(prev.edge.id.clone(), vec![update]));
+                }
+                Scanned { meta, ..prev }
+            } else {
+                let (id, edge) = self.new_entry(&path, &parent, &mut taken);
+                let node = match content {
+                    Some(content) => {
+                        log::debug!("Processing file: {name}");
+                        self.file_node(id.clone(), &path_ref, content)
+                    }
+                    None => {
+                        log::debug!("Processing directory: {name}");
+                        let mut dir_node = Node::label(&name);
+                        dir_node.id = id.clone();
+                        dir_node
+                    }
+                };
+                let file = is_file.then(|| node.clone());
+                txs.extend([
+                    Transaction::create_node(node),
+                    Transaction::create_edge(edge.clone()),
+                ]);
+                Scanned {
+                    id,
+                    edge,
+                    hash,
+                    meta,
+                    node: file,
+                }
+            };
+            scanned.insert(path, entry);
+        }
+
+        // The deletes come first, as a file can be replaced by a directory
+        // with the same path, and thus the same ID.
+        let mut deletes = vec![];
+        for (path, prev) in removed {
+            log::debug!("Removing: {path:?}");
+            deletes.extend([
+                Transaction::update_edge(prev.edge.id, vec![EdgeAction::Delete]),
+                Transaction::update_node(prev.id, vec![NodeUpdate::Delete]),
+            ]);
+        }
+        self.scanned = scanned;
+        self.restored.clear();
+        Ok([deletes, txs].concat())
+    }
+
+    /// Resolves the wiki-links and hashtags of the markdown files, and returns
+    /// the [Transaction]s for the edges, placeholders and labels which changed:
+    /// - `[[Note]]` and `[[Note#Section]]` become [EdgeKind::Reference] edges
+    ///   to the file, and the blob of the heading, if it is found
+    /// - a link to a missing file points to a placeholder [NodeKind::Label]
+    ///   node, until the file shows up
+    /// - a `#tag`, or a tag in the `tags` of the front-matter, becomes a
+    ///   [EdgeKind::Definition] edge to a [NodeKind::Label] node shared by all
+    ///   files with this tag
+    ///
+    /// Only the files which changed since the previous call are parsed again,
+    /// and the files linking to a file which has been added, changed, renamed
+    /// or removed are resolved again.
+    ///
+    /// As a [EdgeKind::Reference] only points to its destination, the
+    /// markdown [Node] also keeps its references as [DataBlob::Edge]s,
+    /// starting at [markdown::LINK_BLOBS].
+    fn update_links(&mut self) -> Vec<Transaction> {
+        let files = self
+            .scanned
+            .iter()
+            .filter_map(|(path, s)| s.hash.map(|hash| (path.clone(), hash)))
+            .collect::<HashMap<_, _>>();
+        let touched = files
+            .iter()
+            .filter(|(path, hash)| self.linked.get(*path) != Some(*hash))
+            .map(|(path, _)| path)
+            .chain(self.linked.keys().filter(|path| !files.contains_key(*path)))
+            .cloned()
+            .collect::<HashSet<_>>();
+        if touched.is_empty() {
+            return vec![];
+        }
+        let keys = touched
+            .iter()
+            .flat_map(|path| {
+                let key = link_key(&path.join("/"));
+                let name = key.rsplit('/').next().unwrap_or_default().to_string();
+                [key, name]
+            })
+            .collect::<HashSet<_>>();
+        self.notes.retain(|path, _| {
+            self.scanned
+                .get(path)
+                .and_then(|s| s.node.as_ref())
+                .is_some_and(is_markdown)
+        });
+        let mut dirty = files
+            .keys()
+            .filter(|path| self.scanned[*path].node.as_ref().is_some_and(is_markdown))
+            .filter(|path| {
+                touched.contains(*path)
+                    || self
+                        .notes
+                        .get(*path)
+                        .is_none_or(|note| !note.targets.is_disjoint(&keys))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        dirty.sort();
+
+        let mut paths = files.keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        // Files are linked by their path or only their name, and a full path
+        // wins over a name.
+        let mut names = HashMap::new();
+        for path in &paths {
+            let key = link_key(&path.join("/"));
+            let name = key.rsplit('/').next().unwrap_or_default().to_string();
+            names.entry(name).or_insert(path);
+            names.insert(key, path);
+        }
+        let parsed = dirty
+            .iter()
+            .map(|path| (path.clone(), self.note_links(path, &names)))
+            .collect::<Vec<_>>();
+        self.notes.extend(parsed);
+        self.linked = files;
+
+        let mut edges = HashMap::new();
+        let mut nodes = HashMap::new();
+        // The first file with a tag gives the label its spelling.
+        let mut notes = self.notes.iter().collect::<Vec<_>>();
+        notes.sort_by_key(|(path, _)| *path);
+        for (_, note) in notes {
+            edges.extend(note.edges.iter().map(|(id, e)| (id.clone(), e.clone())));
+            for (id, node) in &note.nodes {
+                nodes.entry(id.clone()).or_insert_with(|| node.clone());
+            }
+        }
+
+        let mut new_nodes = nodes
+            .values()
+            .filter(|node| !self.link_nodes.contains_key(&node.id))
+            .cloned()
+            .collect::<Vec<_>>();
+        new_nodes.sort_by(|a, b| a.label.cmp(&b.label));
+        let mut new_edges = edges
+            .values()
+            .filter(|edge| self.links.get(&edge.id) != Some(edge))
+            .cloned()
+            .collect::<Vec<_>>();
+        new_edges.sort_by_key(|edge| edge.id.to_string());
+        let mut txs = new_nodes
+            .into_iter()
+            .map(Transaction::create_node)
+            .chain(new_edges.into_iter().map(Transaction::create_edge))
+            .collect::<Vec<_>>();
+        let mut removed_edges = self
+            .links
+            .keys()
+            .filter(|id| !edges.contains_key(id))
+            .collect::<Vec<_>>();
+        removed_edges.sort_by_key(|id| id.to_string());
+        txs.extend(
+            removed_edges
+                .into_iter()
+                .map(|id| Transaction::update_edge(id.clone(), vec![EdgeAction::Delete])),
+        );
+        let mut removed_nodes = self
+            .link_nodes
+            .keys()
+            .filter(|id| !nodes.contains_key(id))
+            .collect::<Vec<_>>();
+        removed_nodes.sort_by_key(|id| id.to_string());
+        txs.extend(
+            removed_nodes
+                .into_iter()
+                .map(|id| Transaction::update_node(id.clone(), vec![NodeUpdate::Delete])),
+        );
+
+        for path in dirty {
+            let refs = self.notes[&path].refs.clone();
+            let note = self.scanned.get_mut(&path).unwrap().node.as_mut().unwrap();
+            let end = markdown::LINK_BLOBS + refs.len() as u32;
+            let mut updates = (markdown::LINK_BLOBS..)
+                .zip(refs)
+                .map(|(index, edge)| (index, DataBlob::Edge(edge)))
+                .filter(|(index, blob)| note.data_blob.get(index) != Some(blob))
+                .map(|(index, blob)| NodeUpdate::DataBlob(index, blob))
+                .collect::<Vec<_>>();
+            let mut removed = note
+                .data_blob
+                .keys()
+                .filter(|index| **index >= end)
+                .copied()
+                .collect::<Vec<_>>();
+            removed.sort();
+            updates.extend(removed.into_iter().map(NodeUpdate::DataBlobRemove));
+            if !updates.is_empty() {
+                for update in &updates {
+                    note.update(update.clone());
+                }
+                txs.push(Transaction::update_node(note.id.clone(), updates));
+            }
+        }
+        self.links = edges;
+        self.link_nodes = nodes;
+        txs
+    }
+
+    /// Parses the wiki-links and hashtags of the markdown file at `path`, and
+    /// resolves the links with the `names` of all files.
+    fn note_links(&self, path: &Vec<String>, names: &HashMap<String, &Vec<String>>) -> NoteLinks {
+        let note = self.scanned[path].node.as_ref().unwrap();
+        let mut links = NoteLinks::default();
+        let mut blocks = note
             .data_blob
-            .insert(0, DataBlob::Bytes(Bytes::from(content)));
+            .iter()
+            .filter(|(index, _)| **index < markdown::LINK_BLOBS)
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|(index, _)| **index);
+        let mut tags = note
+            .data_blob
+            .get(&markdown::FRONT_MATTER_BLOB)
+            .map(front_matter::tags)
+            .unwrap_or_default();
+        for (_, blob) in blocks {
+            let DataBlob::Text(text) = blob else {
+                continue;
+            };
+            let (wiki_links, block_tags) = markdown::links_and_tags(text);
+            tags.extend(block_tags);
+            for link in wiki_links {
+                let key = link_key(&link.target);
+                links.targets.insert(key.clone());
+                let dest = match link.target.is_empty() {
+                    true => Some(path),
+                    false => names.get(&key).copied(),
+                };
+                let (dest, blob) = match dest {
+                    Some(dest) => {
+                        let blob = link.section.as_ref().and_then(|section| {
+                            let md = self.scanned[dest].node.as_ref()?;
+                            markdown::find_heading(md, section)
+                        });
+                        (self.scanned[dest].id.clone(), blob)
+                    }
+                    None => {
+                        let id = NodeID::hash_domain_parts(
+                            "SourceDisk-link",
+                            &[self.id.as_ref(), key.as_bytes()],
+                        );
+                        let node = self.link_node(&id, &link.target);
+                        links.nodes.insert(id.clone(), node);
+                        (id, None)
+                    }
+                };
+                let section = link.section.unwrap_or_default().to_lowercase();
+                let id = EdgeID::hash_domain_parts(
+                    "SourceDisk-link",
+                    &[note.id.as_ref(), key.as_bytes(), section.as_bytes()],
+                );
+                if let Entry::Vacant(entry) = links.edges.entry(id.clone()) {
+                    let edge = self.link_edge(id, EdgeKind::Reference { dest, blob });
+                    links.refs.push(edge.clone());
+                    entry.insert(edge);
+                }
+            }
+        }
+        for tag in tags {
+            let label = NodeID::hash_domain_parts(
+                "SourceDisk-tag",
+                &[self.id.as_ref(), tag.to_lowercase().as_bytes()],
+            );
+            let node = self.link_node(&label, &tag);
+            links.nodes.entry(label.clone()).or_insert(node);
+            let id =
+                EdgeID::hash_domain_parts("SourceDisk-tag", &[note.id.as_ref(), label.as_ref()]);
+            let kind = EdgeKind::Definition {
+                object: note.id.clone(),
+                label,
+            };
+            links.edges.insert(id.clone(), self.link_edge(id, kind));
+        }
+        links
+    }
 
-        let edge = Edge::contains(parent.clone(), file_node.id.clone());
-        Ok(vec![
-            Transaction::create_node(file_node),
-            Transaction::create_edge(edge),
-        ])
+    /// Returns the placeholder or label [Node] of a link or tag, keeping the
+    /// one already created.
+    fn link_node(&self, id: &NodeID, label: &str) -> Node {
+        self.link_nodes.get(id).cloned().unwrap_or_else(|| {
+            let mut node = Node::label(label);
+            node.id = id.clone();
+            node
+        })
+    }
+
+    /// Returns the [Edge] of a link or tag, keeping the one already created if
+    /// it didn't change.
+    fn link_edge(&self, id: EdgeID, kind: EdgeKind) -> Edge {
+        match self.links.get(&id) {
+            Some(edge) if edge.kind == kind => edge.clone(),
+            _ => Edge {
+                id,
+                kind,
+                history: vec![],
+                validity: Validity::from_now(),
+            },
+        }
+    }
+
+    /// Returns the path of the file or directory with this [NodeID], or an
+    /// empty path for the root node.
+    fn path_of(&self, id: &NodeID) -> Option<Vec<String>> {
+        if id == &self.id.root_node() {
+            return Some(vec![]);
+        }
+        self.scanned
+            .iter()
+            .find(|(_, s)| &s.id == id)
+            .map(|(path, _)| path.clone())
+    }
+
+    /// Returns the path of the directory with this [NodeID].
+    fn dir_of(&self, id: &NodeID) -> Option<Vec<String>> {
+        self.path_of(id)
+            .filter(|path| path.is_empty() || self.scanned[path].hash.is_none())
+    }
+
+    /// Moves the scanned entries of `from`, and all its children, to `to`.
+    fn move_scanned(&mut self, from: &[String], to: &[String]) {
+        let moved = self
+            .scanned
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in moved {
+            let entry = self.scanned.remove(&path).unwrap();
+            self.scanned
+                .insert([to, &path[from.len()..]].concat(), entry);
+        }
+    }
+
+    async fn write_node(&mut self, rc: RecordCUD<NodeID, Node, NodeUpdate>) -> Result<()> {
+        let id = rc.get_id();
+        let Some(mut path) = self.path_of(&id) else {
+            // Not yet on disk.
+            if let Either::Right(node) = rc.base {
+                self.pending.insert(id.clone(), node);
+            }
+            if let Some(node) = self.pending.get_mut(&id) {
+                for update in rc.updates {
+                    if update == NodeUpdate::Delete {
+                        self.pending.remove(&id);
+                        break;
+                    }
+                    node.update(update);
+                }
+            }
+            return Ok(());
+        };
+        if path.is_empty() {
+            return Ok(());
+        }
+        let mut changed = false;
+        for update in rc.updates {
+            let path_ref = path.iter().map(String::as_str).collect::<Vec<_>>();
+            match update {
+                NodeUpdate::Label(label) => {
+                    let mut to = path.clone();
+                    *to.last_mut().unwrap() = label;
+                    if to == path {
+                        continue;
+                    }
+                    let to_ref = to.iter().map(String::as_str).collect::<Vec<_>>();
+                    log::debug!("Renaming {path:?} to {to:?}");
+                    self.disk.rename(&path_ref, &to_ref).await?;
+                    self.move_scanned(&path, &to);
+                    path = to;
+                }
+                NodeUpdate::Delete => {
+                    log::debug!("Removing {path:?}");
+                    self.disk.remove(&path_ref).await?;
+                    self.scanned.retain(|p, _| !p.starts_with(&path));
+                    return Ok(());
+                }
+                update => {
+                    if let Some(node) = &mut self.scanned.get_mut(&path).unwrap().node {
+                        node.update(update);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if changed && let Some(node) = &self.scanned[&path].node {
+            match self.handler(node).export(node) {
+                Some(content) => self.write_content(&path, content).await?,
+                None => log::warn!("Cannot write {path:?}, its content is not in the node"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the content of the file at `path`.
+    async fn write_content(&mut self, path: &[String], content: Bytes) -> Result<()> {
+        if self.scanned[path].hash.is_none() {
+            anyhow::bail!("Cannot write content to directory {path:?}");
+        }
+        log::debug!("Writing {path:?}");
+        let path_ref = path.iter().map(String::as_str).collect::<Vec<_>>();
+        self.disk.overwrite(&path_ref, &content).await?;
+        let meta = self.disk.metadata(&path_ref).await?;
+        let entry = self.scanned.get_mut(path).unwrap();
+        entry.hash = Some(U256::hash_data(&content));
+        entry.meta = meta;
+        Ok(())
+    }
+
+    async fn write_edge(&mut self, rc: RecordCUD<EdgeID, Edge, EdgeAction>) -> Result<()> {
+        match rc.base {
+            Either::Right(edge) => {
+                if let EdgeKind::Contains { container, object } = &edge.kind
+                    && self.pending.contains_key(object)
+                    && let Some(mut path) = self.dir_of(container)
+                {
+                    let node = self.pending.remove(object).unwrap();
+                    path.push(node.label.clone());
+                    if self.scanned.contains_key(&path) {
+                        anyhow::bail!("{path:?} already exists");
+                    }
+                    let kind = match node.kind {
+                        NodeKind::Label => EntryKind::Directory,
+                        _ => EntryKind::File,
+                    };
+                    // It would be removed again by the next scan.
+                    if self.is_ignored(&path, &kind) {
+                        anyhow::bail!("{path:?} is ignored");
+                    }
+                    let path_ref = path.iter().map(String::as_str).collect::<Vec<_>>();
+                    log::debug!("Creating {path:?}");
+                    let hash = match node.kind {
+                        NodeKind::Label => {
+                            self.disk.create_directory(&path_ref).await?;
+                            None
+                        }
+                        _ => {
+                            let Some(content) = self.handler(&node).export(&node) else {
+                                anyhow::bail!(
+                                    "Cannot write {path:?}, its content is not in the node"
+                                );
+                            };
+                            self.disk.write_bytes(&path_ref, &content).await?;
+                            Some(U256::hash_data(&content))
+                        }
+                    };
+                    let meta = self.disk.metadata(&path_ref).await?;
+                    self.scanned.insert(
+                        path,
+                        Scanned {
+                            id: node.id.clone(),
+                            edge,
+                            hash,
+                            meta,
+                            node: hash.is_some().then(|| node.clone()),
+                        },
+                    );
+                }
+            }
+            Either::Left(id) => {
+                let Some(path) = self
+                    .scanned
+                    .iter()
+                    .find(|(_, s)| s.edge.id == id)
+                    .map(|(path, _)| path.clone())
+                else {
+                    return Ok(());
+                };
+                for update in rc.updates {
+                    if let EdgeAction::UpdateIDs(ids) = &update
+                        && let [container, ..] = ids.as_slice()
+                    {
+                        let Some(mut to) = self.dir_of(container) else {
+                            anyhow::bail!(
+                                "Cannot move {path:?} to a node which is not a directory"
+                            );
+                        };
+                        to.push(path.last().unwrap().clone());
+                        let from_ref = path.iter().map(String::as_str).collect::<Vec<_>>();
+                        let to_ref = to.iter().map(String::as_str).collect::<Vec<_>>();
+                        log::debug!("Moving {path:?} to {to:?}");
+                        self.disk.rename(&from_ref, &to_ref).await?;
+                        self.move_scanned(&path, &to);
+                        self.scanned.get_mut(&to).unwrap().edge.update(update);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the [Node] for a file, with the detected MIME type, and the
+    /// [DataBlob]s of its [FileHandler].
+    fn file_node(&self, id: NodeID, path: &[&str], content: Bytes) -> Node {
+        let file_name = path.last().unwrap_or(&"").to_string();
+        let mime = detect_mime(&file_name, &content);
+        let mut node = Node::mime(mime.clone(), file_name);
+        self.handlers.get(&mime).import(&mut node, &content);
+        node.id = id;
+        node
+    }
+
+    /// Returns the [FileHandler] for the MIME type of the node.
+    fn handler(&self, node: &Node) -> &dyn FileHandler {
+        match &node.kind {
+            NodeKind::MimeType(mime) => self.handlers.get(mime),
+            _ => self.handlers.get(""),
+        }
     }
 }
 
+/// Returns the lowercase name or path of a linked file, without the `.md`
+/// extension.
+fn link_key(target: &str) -> String {
+    let key = target.trim().to_lowercase();
+    key.strip_suffix(".md").unwrap_or(&key).to_string()
+}
+
+fn is_markdown(node: &Node) -> bool {
+    matches!(&node.kind, NodeKind::MimeType(mime) if mime == "text/markdown")
+}
+
 #[cfg(test)]
 mod tests {
     use flarch::start_logging_filter_level;
 
-    use crate::{storage::dir_trait::EmulatedDir, worldview::WorldView};
+    use crate::{
+        storage::{
+            dir_trait::{EmulatedDir, EmulatedFile},
+            handlers,
+        },
+        worldview::WorldView,
+    };
 
     use super::*;
 
@@ -164,4 +1277,728 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_stable_root() -> anyhow::Result<()> {
+        let dir = EmulatedDir::new_from_string(&[("notes.md", "# Notes")]);
+        let source = SourceDisk::new(dir.clone());
+        let sid = source.get_id();
+        assert_eq!(sid, SourceDisk::new(dir.clone()).get_id());
+        assert_ne!(sid, SourceDisk::new(EmulatedDir::new()).get_id());
+
+        let mut ww = WorldView::new();
+        let root_id = ww.add_source(Box::new(source)).await?;
+        assert_eq!(root_id, sid.root_node());
+        assert_eq!(ww.root_nodes(), vec![root_id.clone()]);
+
+        // Restarting with the stored state keeps the root.
+        let state = serde_json::to_string(&ww.state())?;
+        let mut ww = WorldView::from_state(serde_json::from_str(&state)?);
+        assert_eq!(ww.root_nodes(), vec![root_id.clone()]);
+        assert!(ww.get_node(&root_id).is_some());
+        assert_eq!(
+            ww.add_source(Box::new(SourceDisk::new(dir.clone())))
+                .await?,
+            root_id
+        );
+
+        // Adding a source again after a restart doesn't store its
+        // transactions a second time.
+        let empty = EmulatedDir::new();
+        ww.add_source(Box::new(SourceDisk::new(empty.clone())))
+            .await?;
+        let count = ww.state().transactions.len();
+        let mut ww = WorldView::from_state(ww.state());
+        ww.add_source(Box::new(SourceDisk::new(empty))).await?;
+        assert_eq!(ww.state().transactions.len(), count);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stable_ids() -> anyhow::Result<()> {
+        let dir =
+            EmulatedDir::new_from_string(&[("notes.md", "# Notes"), ("dir/todo.txt", "nothing")]);
+        let ids = |txs: Vec<Transaction>| {
+            txs.into_iter()
+                .flat_map(|tx| tx.records)
+                .map(|r| match r {
+                    Record::Node(rc) => rc.get_id().to_string(),
+                    Record::Edge(rc) => rc.get_id().to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = ids(SourceDisk::new(dir.clone()).get_updates().await?);
+        assert_eq!(first.len(), 7);
+        assert_eq!(
+            first,
+            ids(SourceDisk::new(dir.clone()).get_updates().await?)
+        );
+
+        let source = SourceDisk::new(dir.clone());
+        let (notes, todo) = (
+            source.node_id(&["notes.md"]),
+            source.node_id(&["dir", "todo.txt"]),
+        );
+        let mut ww = WorldView::new();
+        ww.add_source(Box::new(source)).await?;
+        let link = Edge::contains(notes.clone(), todo.clone());
+        ww.add_transactions(
+            &SourceID::rnd(),
+            vec![Transaction::create_edge(link.clone())],
+        )
+        .await?;
+
+        // Importing again keeps the nodes and the edge added by the user,
+        // and stores nothing new.
+        let count = ww.state().transactions.len();
+        ww.add_source(Box::new(SourceDisk::new(dir))).await?;
+        let todo_node = ww.get_node(&todo).expect("todo.txt node");
+        assert_eq!(todo_node.label, "todo.txt");
+        assert!(todo_node.edges.iter().any(|e| e.id == link.id));
+        assert_eq!(todo_node.edges.len(), 2);
+        assert_eq!(ww.state().transactions.len(), count);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rescan() -> anyhow::Result<()> {
+        let dir =
+            EmulatedDir::new_from_string(&[("a.txt", "a"), ("b.txt", "b"), ("sub/c.txt", "c")]);
+        let mut source = SourceDisk::new(dir);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(source.get_updates().await?.is_empty());
+
+        let (a, b, c) = (
+            source.node_id(&["a.txt"]),
+            source.node_id(&["b.txt"]),
+            source.node_id(&["sub", "c.txt"]),
+        );
+        let b_edge = ww.get_node(&b).unwrap().edges[0].id.clone();
+
+        let disk = &mut source.disk;
+        disk.files.insert("a.txt".into(), EmulatedFile::new("a2"));
+        disk.files.remove("b.txt");
+        disk.files.insert("d.txt".into(), EmulatedFile::new("d"));
+        let content = disk
+            .dirs
+            .get_mut("sub")
+            .unwrap()
+            .files
+            .remove("c.txt")
+            .unwrap();
+        disk.files.insert("e.txt".into(), content);
+
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 7);
+        ww.process_updates(txs).await?;
+
+        let blob = |id: &NodeID| ww.get_node(id).unwrap().data_blob.get(&0).cloned();
+        assert_eq!(blob(&a), Some(DataBlob::Text("a2".into())));
+        assert!(ww.get_node(&b).is_none());
+        assert!(ww.get_edge(&b_edge).is_none());
+        assert_eq!(
+            blob(&source.node_id(&["d.txt"])),
+            Some(DataBlob::Text("d".into()))
+        );
+
+        // The moved file keeps its ID and is now in the root directory.
+        let c_node = ww.get_node(&c).unwrap();
+        assert_eq!(c_node.label, "e.txt");
+        assert_eq!(c_node.edges.len(), 1);
+        assert!(c_node.edges[0].nodes().contains(&&source.id.root_node()));
+
+        assert!(source.get_updates().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_ids() -> anyhow::Result<()> {
+        let dir = EmulatedDir::new_from_string(&[("a.txt", "a"), ("notes.txt", "n")]);
+        let mut source = SourceDisk::new(dir);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        let (a, notes) = (source.node_id(&["a.txt"]), source.node_id(&["notes.txt"]));
+        let link = Edge::contains(notes.clone(), a.clone());
+        ww.add_transactions(
+            &SourceID::rnd(),
+            vec![Transaction::create_edge(link.clone())],
+        )
+        .await?;
+
+        // A new file at the path of a renamed one gets a new ID.
+        let content = source.disk.files.remove("a.txt").unwrap();
+        source.disk.files.insert("b.txt".into(), content);
+        ww.process_updates(source.get_updates().await?).await?;
+        source
+            .disk
+            .files
+            .insert("a.txt".into(), EmulatedFile::new("new a"));
+        ww.process_updates(source.get_updates().await?).await?;
+        let scanned = |source: &SourceDisk<EmulatedDir>, path: &str| {
+            source.scanned[&vec![path.to_string()]].id.clone()
+        };
+        let new_a = scanned(&source, "a.txt");
+        assert_ne!(new_a, a);
+        assert_eq!(scanned(&source, "b.txt"), a);
+        assert_eq!(ww.get_node(&a).unwrap().label, "b.txt");
+        assert_eq!(ww.get_node(&new_a).unwrap().label, "a.txt");
+        assert_eq!(ww.get_node(&a).unwrap().edges.len(), 2);
+        assert_eq!(ww.get_node(&new_a).unwrap().edges.len(), 1);
+
+        // After a restart, the renamed file keeps its ID and the edge of
+        // the user.
+        let mut state = ww.state();
+        state
+            .source_root
+            .insert(source.get_id(), source.id.root_node());
+        let count = state.transactions.len();
+        let mut ww = WorldView::from_state(state);
+        ww.add_source(Box::new(SourceDisk::new(source.disk.clone())))
+            .await?;
+        assert_eq!(ww.state().transactions.len(), count);
+        assert!(ww.get_node(&source.node_id(&["b.txt"])).is_none());
+        let b_node = ww.get_node(&a).unwrap();
+        assert_eq!(b_node.label, "b.txt");
+        assert!(b_node.edges.iter().any(|e| e.id == link.id));
+        assert_eq!(ww.get_node(&new_a).unwrap().label, "a.txt");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_back() -> anyhow::Result<()> {
+        let dir = EmulatedDir::new_from_string(&[("notes.txt", "n"), ("sub/c.txt", "c")]);
+        let mut source = SourceDisk::new(dir);
+        source.get_updates().await?;
+        let root = source.id.root_node();
+        let (notes, sub, c) = (
+            source.node_id(&["notes.txt"]),
+            source.node_id(&["sub"]),
+            source.node_id(&["sub", "c.txt"]),
+        );
+
+        let mut file = Node::mime("text/plain".into(), "new.txt".into());
+        file.data_blob
+            .insert(0, DataBlob::Bytes(Bytes::from("hello")));
+        let dir2 = Node::label("dir2");
+        source
+            .add_tx(vec![
+                Transaction::update_node(notes.clone(), vec![NodeUpdate::Label("todo.txt".into())]),
+                Transaction::update_node(
+                    c.clone(),
+                    vec![NodeUpdate::DataBlob(0, DataBlob::Text("new".into()))],
+                ),
+                Transaction::create_node(file.clone()),
+                Transaction::create_edge(Edge::contains(sub.clone(), file.id.clone())),
+                Transaction::create_node(dir2.clone()),
+                Transaction::create_edge(Edge::contains(root.clone(), dir2.id.clone())),
+                Transaction::update_edge(
+                    source.contains(&sub, &c).id,
+                    vec![EdgeAction::UpdateIDs(vec![dir2.id.clone(), c.clone()])],
+                ),
+            ])
+            .await?;
+
+        let disk = &source.disk;
+        assert_eq!(disk.read_file(&["todo.txt"]).await?, "n");
+        assert_eq!(disk.read_file(&["sub", "new.txt"]).await?, "hello");
+        assert_eq!(disk.read_file(&["dir2", "c.txt"]).await?, "new");
+        assert!(disk.read_file(&["sub", "c.txt"]).await.is_err());
+
+        source
+            .add_tx(vec![Transaction::update_node(
+                notes,
+                vec![NodeUpdate::Delete],
+            )])
+            .await?;
+        assert!(source.disk.read_file(&["todo.txt"]).await.is_err());
+
+        // The changes written to disk are not reported back.
+        assert!(source.get_updates().await?.is_empty());
+
+        // Writing to an existing file fails.
+        let dup = Node::mime("text/plain".into(), "new.txt".into());
+        let res = source
+            .add_tx(vec![
+                Transaction::create_node(dup.clone()),
+                Transaction::create_edge(Edge::contains(sub, dup.id)),
+            ])
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rescan_metadata() -> anyhow::Result<()> {
+        let mut dir = EmulatedDir::new_from_string(&[("old.txt", "old")]);
+        dir.files.get_mut("old.txt").unwrap().modified = 0;
+        let binary = Bytes::from_static(&[0, 159, 146, 150]);
+        dir.files
+            .insert("binary".into(), EmulatedFile::new(binary.clone()));
+        let mut source = SourceDisk::new(dir);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        let (old, bin) = (source.node_id(&["old.txt"]), source.node_id(&["binary"]));
+        let blob = |ww: &WorldView, id: &NodeID| ww.get_node(id).unwrap().data_blob[&0].clone();
+        assert_eq!(
+            ww.get_node(&bin).unwrap().kind,
+            NodeKind::MimeType("application/octet-stream".into())
+        );
+        assert_eq!(blob(&ww, &bin), DataBlob::Hash(U256::hash_data(&binary)));
+
+        // Same size and modification time: the file is not read again.
+        source.disk.files.get_mut("old.txt").unwrap().content = "new".into();
+        assert!(source.get_updates().await?.is_empty());
+        source.disk.files.get_mut("old.txt").unwrap().modified = 1;
+        ww.process_updates(source.get_updates().await?).await?;
+        assert_eq!(blob(&ww, &old), DataBlob::Text("new".into()));
+
+        // Binary content is written back unchanged.
+        let binary = Bytes::from_static(&[255, 0, 1]);
+        source
+            .add_tx(vec![Transaction::update_node(
+                bin,
+                vec![NodeUpdate::DataBlob(0, DataBlob::Bytes(binary.clone()))],
+            )])
+            .await?;
+        assert_eq!(source.disk.read_bytes(&["binary"]).await?, binary);
+        assert!(source.get_updates().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_markdown() -> anyhow::Result<()> {
+        let note = "# Notes\n\n## First\n\nFirst text.\n\n## Second\n\nSecond text.\n";
+        let dir = EmulatedDir::new_from_string(&[("notes.md", note)]);
+        let mut source = SourceDisk::new(dir);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        let id = source.node_id(&["notes.md"]);
+        let node = ww.get_node(&id).unwrap();
+        assert_eq!(node.kind, NodeKind::MimeType("text/markdown".into()));
+        assert_eq!(node.data_blob.len(), 5);
+        let first = node.data_view.child.as_ref().unwrap();
+        assert_eq!(
+            node.data_blob[&first.index],
+            DataBlob::Text("## First\n\n".into())
+        );
+
+        // Only the changed block is updated.
+        let file = EmulatedFile::new(note.replace("Second text", "Changed text"));
+        source.disk.files.insert("notes.md".into(), file);
+        let txs = source.get_updates().await?;
+        let Record::Node(rc) = &txs[0].records[0] else {
+            panic!("Expected a node update");
+        };
+        assert_eq!(
+            rc.updates,
+            vec![NodeUpdate::DataBlob(
+                4,
+                DataBlob::Text("Changed text.\n".into())
+            )]
+        );
+        ww.process_updates(txs).await?;
+
+        // Adding a section updates the view.
+        let file = EmulatedFile::new(format!("{note}\n## Third\n"));
+        source.disk.files.insert("notes.md".into(), file);
+        ww.process_updates(source.get_updates().await?).await?;
+        let node = ww.get_node(&id).unwrap();
+        assert_eq!(node.data_blob.len(), 6);
+        assert!(
+            node.view_data_node_get().child[0].sibling[0].sibling[0]
+                .data
+                .contains("Third")
+        );
+
+        // Edits are written back to the markdown file.
+        let third = &node
+            .data_view
+            .child
+            .as_ref()
+            .unwrap()
+            .sibling
+            .as_ref()
+            .unwrap()
+            .sibling;
+        let tx = Transaction::update_node(
+            id.clone(),
+            vec![NodeUpdate::DataBlob(
+                third.as_ref().unwrap().index,
+                DataBlob::Text("## Third and last\n".into()),
+            )],
+        );
+        source.add_tx(vec![tx]).await?;
+        assert_eq!(
+            source.disk.read_file(&["notes.md"]).await?,
+            format!("{note}\n## Third and last\n")
+        );
+        assert!(source.get_updates().await?.is_empty());
+
+        // A new markdown file is exported from its blocks.
+        let mut new = Node::mime("text/markdown".into(), "new.md".into());
+        markdown::import(&mut new, "# New\n\nText\n");
+        source
+            .add_tx(vec![
+                Transaction::create_node(new.clone()),
+                Transaction::create_edge(Edge::contains(source.id.root_node(), new.id)),
+            ])
+            .await?;
+        assert_eq!(source.disk.read_file(&["new.md"]).await?, "# New\n\nText\n");
+        assert!(source.get_updates().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_links() -> anyhow::Result<()> {
+        let a_md = "# A\n\nSee [[B]], [[dir/b#Details]] and [[Missing]]. #todo\n";
+        let dir = EmulatedDir::new_from_string(&[
+            ("a.md", a_md),
+            ("dir/b.md", "# B\n\n## Details\n\nText #Todo\n"),
+        ]);
+        let mut source = SourceDisk::new(dir);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(source.get_updates().await?.is_empty());
+
+        let a = source.node_id(&["a.md"]);
+        let b = source.node_id(&["dir", "b.md"]);
+        let refs = |ww: &WorldView| {
+            let node = ww.get_node(&a).unwrap();
+            (markdown::LINK_BLOBS..)
+                .map_while(|index| match node.data_blob.get(&index) {
+                    Some(DataBlob::Edge(edge)) => Some(ww.get_edge(&edge.id).unwrap().kind),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let EdgeKind::Reference { dest: missing, .. } = refs(&ww)[2].clone() else {
+            panic!("Expected a reference");
+        };
+        assert_eq!(
+            refs(&ww)[..2],
+            [
+                EdgeKind::Reference {
+                    dest: b.clone(),
+                    blob: None
+                },
+                EdgeKind::Reference {
+                    dest: b.clone(),
+                    blob: Some(1)
+                },
+            ]
+        );
+        // The destination knows about the reference.
+        assert_eq!(ww.get_node(&b).unwrap().edges.len(), 4);
+        assert_eq!(ww.get_node(&missing).unwrap().label, "Missing");
+
+        // Both files share the same tag.
+        let tag = ww
+            .get_node(&a)
+            .unwrap()
+            .edges
+            .into_iter()
+            .find_map(|edge| match edge.kind {
+                EdgeKind::Definition { label, .. } => Some(label),
+                _ => None,
+            })
+            .unwrap();
+        let tag_node = ww.get_node(&tag).unwrap();
+        assert_eq!(
+            (tag_node.kind, tag_node.label, tag_node.edges.len()),
+            (NodeKind::Label, "todo".into(), 2)
+        );
+
+        // The missing file shows up, and the tags are removed.
+        let disk = &mut source.disk;
+        disk.files
+            .insert("missing.md".into(), EmulatedFile::new("# Missing\n"));
+        let file = EmulatedFile::new(a_md.replace(" #todo", ""));
+        disk.files.insert("a.md".into(), file);
+        let file = EmulatedFile::new("# B\n\n## Details\n\nText\n");
+        let dir = disk.dirs.get_mut("dir").unwrap();
+        dir.files.insert("b.md".into(), file);
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(source.get_updates().await?.is_empty());
+        assert_eq!(
+            refs(&ww)[2],
+            EdgeKind::Reference {
+                dest: source.node_id(&["missing.md"]),
+                blob: None
+            }
+        );
+        assert!(ww.get_node(&missing).is_none());
+        assert!(ww.get_node(&tag).is_none());
+        assert_eq!(ww.get_node(&a).unwrap().edges.len(), 1);
+
+        // Removing the links removes the references.
+        let file = EmulatedFile::new("# A\n");
+        source.disk.files.insert("a.md".into(), file);
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(refs(&ww).is_empty());
+        assert_eq!(ww.get_node(&b).unwrap().edges.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_links_changed() -> anyhow::Result<()> {
+        let dir = EmulatedDir::new_from_string(&[
+            ("a.md", "# A\n\nSee [[B]]\n"),
+            ("b.md", "# B\n"),
+            ("c.md", "# C\n"),
+        ]);
+        let mut source = SourceDisk::new(dir);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        let a = source.node_id(&["a.md"]);
+        let dest = |ww: &WorldView| match &ww.get_node(&a).unwrap().data_blob[&markdown::LINK_BLOBS]
+        {
+            DataBlob::Edge(edge) => match ww.get_edge(&edge.id).unwrap().kind {
+                EdgeKind::Reference { dest, .. } => dest,
+                _ => panic!("Expected a reference"),
+            },
+            _ => panic!("Expected an edge"),
+        };
+        assert_eq!(dest(&ww), source.node_id(&["b.md"]));
+
+        // Marks the files, to see which ones are parsed again.
+        let parsed = |source: &mut SourceDisk<EmulatedDir>| {
+            let mut paths = source
+                .notes
+                .iter()
+                .filter(|(_, note)| !note.targets.contains("marker"))
+                .map(|(path, _)| path.join("/"))
+                .collect::<Vec<_>>();
+            paths.sort();
+            for note in source.notes.values_mut() {
+                note.targets.insert("marker".into());
+            }
+            paths
+        };
+        parsed(&mut source);
+
+        // Only the changed file is parsed again.
+        source.disk.overwrite(&["c.md"], b"# C\n\nNew\n").await?;
+        ww.process_updates(source.get_updates().await?).await?;
+        assert_eq!(parsed(&mut source), vec!["c.md"]);
+        assert!(source.get_updates().await?.is_empty());
+        assert!(parsed(&mut source).is_empty());
+
+        // Renaming or adding a linked file resolves the linking files again.
+        source.disk.rename(&["b.md"], &["d.md"]).await?;
+        ww.process_updates(source.get_updates().await?).await?;
+        assert_eq!(parsed(&mut source), vec!["a.md", "d.md"]);
+        assert_eq!(ww.get_node(&dest(&ww)).unwrap().label, "B");
+        source.disk.write_file(&["b.md"], "# New B\n").await?;
+        ww.process_updates(source.get_updates().await?).await?;
+        assert_eq!(parsed(&mut source), vec!["a.md", "b.md"]);
+        assert_eq!(dest(&ww), source.scanned[&vec!["b.md".to_string()]].id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_front_matter() -> anyhow::Result<()> {
+        let task = "---\ntags: [work]\nstatus: open\n---\n\n# Task\n\nDo it #urgent\n";
+        let dir = EmulatedDir::new_from_string(&[("task.md", task)]);
+        let mut source = SourceDisk::new(dir);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(source.get_updates().await?.is_empty());
+
+        let id = source.node_id(&["task.md"]);
+        let node = ww.get_node(&id).unwrap();
+        let Some(DataBlob::Entry(original, mut fields)) =
+            node.data_blob.get(&markdown::FRONT_MATTER_BLOB).cloned()
+        else {
+            panic!("Expected the front-matter");
+        };
+        assert_eq!(fields["status"], DataBlob::Text("open".into()));
+        let mut tags = node
+            .edges
+            .iter()
+            .filter_map(|edge| match &edge.kind {
+                EdgeKind::Definition { label, .. } => Some(ww.get_node(label).unwrap().label),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        tags.sort();
+        assert_eq!(tags, vec!["urgent", "work"]);
+
+        // Changing a field regenerates the front-matter.
+        fields.insert("status".into(), DataBlob::Text("done".into()));
+        let tx = Transaction::update_node(
+            id,
+            vec![NodeUpdate::DataBlob(
+                markdown::FRONT_MATTER_BLOB,
+                DataBlob::Entry(original, fields),
+            )],
+        );
+        source.add_tx(vec![tx]).await?;
+        assert_eq!(
+            source.disk.read_file(&["task.md"]).await?,
+            task.replace(" [work]", "\n- work").replace("open", "done")
+        );
+        assert!(source.get_updates().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ignore() -> anyhow::Result<()> {
+        let big = "x".repeat(100);
+        let dir = EmulatedDir::new_from_string(&[
+            (".git/config", "[core]"),
+            ("node_modules/x/index.js", "js"),
+            ("notes.md", "# Notes\n"),
+            ("notes.md.swp", "swap"),
+            ("build/out.bin", "bin"),
+            ("drafts/a.md", "a"),
+            ("keep/a.log", "log"),
+            ("keep/important.log", "log"),
+            ("big.txt", &big),
+            (IGNORE_FILE, "build/\n*.log\n!keep/important.log\n"),
+        ]);
+        let mut config = DiskConfig::default();
+        config.ignore.push("/drafts".into());
+        config.max_file_size = Some(64);
+        let mut source = SourceDisk::with_config(dir, config);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+
+        let mut summary = source.summary().clone();
+        summary.ignored.sort();
+        let path = |p: &str| p.split('/').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            ScanSummary {
+                imported: 4,
+                ignored: [
+                    ".git",
+                    "build",
+                    "drafts",
+                    "keep/a.log",
+                    "node_modules",
+                    "notes.md.swp"
+                ]
+                .map(path)
+                .to_vec(),
+                too_big: vec![(path("big.txt"), 100)],
+            }
+        );
+        let notes = source.node_id(&["notes.md"]);
+        assert!(ww.get_node(&notes).is_some());
+        assert!(ww.get_node(&source.node_id(&[".git"])).is_none());
+        assert!(source.get_updates().await?.is_empty());
+
+        // Ignored files are not written.
+        let node = Node::mime("text/plain".into(), "new.log".into());
+        let tx = vec![
+            Transaction::create_node(node.clone()),
+            Transaction::create_edge(Edge::contains(source.id.root_node(), node.id)),
+        ];
+        assert!(source.add_tx(tx).await.is_err());
+
+        // Changing the ignore file removes and adds the files.
+        let file = EmulatedFile::new("*.md\n");
+        source.disk.files.insert(IGNORE_FILE.into(), file);
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(ww.get_node(&notes).is_none());
+        let out = source.node_id(&["build", "out.bin"]);
+        assert!(ww.get_node(&out).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_events() -> anyhow::Result<()> {
+        let dir = EmulatedDir::new_from_string(&[("dir/a.txt", "a"), ("b.txt", "b")]);
+        let mut source = SourceDisk::new(dir);
+        // The emulated directory cannot be watched, so the events are added
+        // directly.
+        let events = Arc::new(Mutex::new(vec![]));
+        source.events = Some(events.clone());
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        let [dir_id, a, b, c] = [&["dir"][..], &["dir", "a.txt"], &["b.txt"], &["c.txt"]]
+            .map(|path| source.node_id(path));
+
+        let disk = &mut source.disk;
+        let moved = disk.dirs.remove("dir").unwrap();
+        disk.dirs.insert("folder".into(), moved);
+        disk.files.insert("b.txt".into(), EmulatedFile::new("b2"));
+        disk.files.insert("c.txt".into(), EmulatedFile::new("c"));
+        let path = |p: &str| p.split('/').map(String::from).collect::<Vec<_>>();
+        events.lock().unwrap().extend([
+            DirEvent::Changed(path("dir")),
+            DirEvent::Changed(path("folder")),
+            DirEvent::Renamed(path("dir"), path("folder")),
+            DirEvent::Changed(path("b.txt")),
+        ]);
+
+        // The renamed directory only gets a new label, and c.txt is not read.
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 2, "{txs:?}");
+        ww.process_updates(txs).await?;
+        assert_eq!(ww.get_node(&dir_id).unwrap().label, "folder");
+        assert_eq!(
+            ww.get_node(&b).unwrap().data_blob[&0],
+            DataBlob::Text("b2".into())
+        );
+        assert!(ww.get_node(&c).is_none());
+        assert_eq!(source.path_of(&a), Some(path("folder/a.txt")));
+        assert_eq!(source.summary().imported, 3);
+
+        events.lock().unwrap().push(DirEvent::Rescan);
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(ww.get_node(&c).is_some());
+        assert!(ww.get_node(&a).is_some());
+        assert!(source.get_updates().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mime() -> anyhow::Result<()> {
+        let png = Bytes::from_static(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x10\0\0\0\x08");
+        let mut dir = EmulatedDir::new_from_string(&[
+            ("data.json", "{}"),
+            ("file", "text"),
+            ("log.txt", "log"),
+        ]);
+        dir.files
+            .insert("image".into(), EmulatedFile::new(png.clone()));
+        let mut source = SourceDisk::new(dir);
+        source
+            .handlers()
+            .register("text/plain", handlers::BinaryHandler);
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+
+        let [json, file, image, log] =
+            ["data.json", "file", "image", "log.txt"].map(|name| source.node_id(&[name]));
+        let node = |ww: &WorldView, id: &NodeID| ww.get_node(id).unwrap();
+        let kind = |mime: &str| NodeKind::MimeType(mime.into());
+        assert_eq!(node(&ww, &json).kind, kind("application/json"));
+        assert_eq!(node(&ww, &json).data_blob[&0], DataBlob::Text("{}".into()));
+        assert_eq!(node(&ww, &image).kind, kind("image/png"));
+        assert!(matches!(&node(&ww, &image).data_blob[&1],
+            DataBlob::Entry(name, fields) if name == "image" && fields["width"] == DataBlob::Int(16.into())));
+        assert_eq!(
+            node(&ww, &log).data_blob[&0],
+            DataBlob::Hash(U256::hash_data(b"log"))
+        );
+
+        // A file whose type changes is replaced, and keeps its edges and
+        // the name it has been moved to.
+        let moved = source.disk.files.remove("file").unwrap();
+        source.disk.files.insert("other".into(), moved);
+        ww.process_updates(source.get_updates().await?).await?;
+        source
+            .disk
+            .files
+            .insert("other".into(), EmulatedFile::new(png));
+        ww.process_updates(source.get_updates().await?).await?;
+        assert_eq!(node(&ww, &file).kind, kind("image/png"));
+        assert_eq!(node(&ww, &file).label, "other");
+        assert_eq!(node(&ww, &file).edges.len(), 1);
+        assert!(source.get_updates().await?.is_empty());
+        Ok(())
+    }
 }
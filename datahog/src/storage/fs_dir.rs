@@ -0,0 +1,438 @@
+//! A [Reader] and [Writer] for a directory of the local filesystem.
+//!
+//! All paths are relative to the root directory, and cannot escape it:
+//! `..`, `.` and absolute components are refused, as well as symlinks which
+//! resolve to something outside of the root.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use notify::{
+    EventKind, RecursiveMode, Watcher,
+    event::{ModifyKind, RenameMode},
+};
+use tokio::{fs, io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    storage::dir_trait::{DirEvent, DirWatch, DirectoryEntry, EntryKind, Metadata, Reader, Writer},
+    structs::Timestamp,
+};
+
+#[derive(Debug, Clone)]
+pub struct FsDir {
+    root: PathBuf,
+}
+
+impl FsDir {
+    /// Opens an existing directory as root.
+    pub async fn new(root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let root = fs::canonicalize(root.as_ref())
+            .await
+            .with_context(|| format!("Opening {}", root.as_ref().display()))?;
+        if !fs::metadata(&root).await?.is_dir() {
+            anyhow::bail!("{} is not a directory", root.display());
+        }
+        Ok(Self { root })
+    }
+
+    /// Returns the absolute path, after checking that every component is a
+    /// plain name, and that the deepest existing ancestor is inside the root
+    /// once all symlinks are resolved.
+    async fn resolve(&self, path: &[&str]) -> anyhow::Result<PathBuf> {
+        let mut full = self.root.clone();
+        for part in path {
+            let mut components = Path::new(part).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) if !part.contains('/') => full.push(part),
+                _ => anyhow::bail!("Invalid path component '{part}'"),
+            }
+        }
+        let mut existing = full.as_path();
+        loop {
+            match fs::canonicalize(existing).await {
+                Ok(canonical) => {
+                    if !canonical.starts_with(&self.root) {
+                        anyhow::bail!("'{}' is outside of the root", path.join("/"));
+                    }
+                    break;
+                }
+                Err(_) => match existing.parent() {
+                    Some(parent) => existing = parent,
+                    None => anyhow::bail!("Root directory disappeared"),
+                },
+            }
+        }
+        Ok(full)
+    }
+}
+
+#[async_trait]
+impl Reader for FsDir {
+    fn location(&self) -> String {
+        self.root.to_string_lossy().into()
+    }
+
+    /// Entries which resolve to something outside of the root are skipped.
+    async fn read_directory(&self, path: &[&str]) -> anyhow::Result<Vec<DirectoryEntry>> {
+        let dir = self.resolve(path).await?;
+        let mut read_dir = fs::read_dir(&dir).await?;
+        let mut entries = vec![];
+        while let Some(entry) = read_dir.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                log::warn!("Skipping non UTF-8 name in {}", dir.display());
+                continue;
+            };
+            match fs::canonicalize(entry.path()).await {
+                Ok(canonical) if canonical.starts_with(&self.root) => {}
+                _ => {
+                    log::warn!("Skipping {name} which is outside of the root");
+                    continue;
+                }
+            }
+            if fs::metadata(entry.path()).await?.is_dir() {
+                entries.push(DirectoryEntry::Directory(name));
+            } else {
+                entries.push(DirectoryEntry::File(name));
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    async fn read_bytes(&self, path: &[&str]) -> anyhow::Result<Bytes> {
+        let file = self.resolve(path).await?;
+        Ok(fs::read(&file)
+            .await
+            .with_context(|| format!("Reading {}", file.display()))?
+            .into())
+    }
+
+    async fn metadata(&self, path: &[&str]) -> anyhow::Result<Metadata> {
+        let target = self.resolve(path).await?;
+        let meta = fs::metadata(&target)
+            .await
+            .with_context(|| format!("Reading metadata of {}", target.display()))?;
+        let modified = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as Timestamp)
+            .unwrap_or(0);
+        Ok(if meta.is_dir() {
+            Metadata {
+                kind: EntryKind::Directory,
+                size: 0,
+                modified,
+            }
+        } else {
+            Metadata {
+                kind: EntryKind::File,
+                size: meta.len(),
+                modified,
+            }
+        })
+    }
+
+    /// Uses the watcher of the platform, e.g., inotify on Linux.
+    fn watch(&self) -> anyhow::Result<Option<DirWatch>> {
+        let (tx, events) = mpsc::unbounded_channel();
+        let root = self.root.clone();
+        let mut watcher = notify::recommended_watcher(move |res| {
+            for event in dir_events(&root, res) {
+                let _ = tx.send(event);
+            }
+        })?;
+        watcher.watch(&self.root, RecursiveMode::Recursive)?;
+        Ok(Some(DirWatch {
+            events,
+            guard: Box::new(watcher),
+        }))
+    }
+}
+
+/// Converts an event of the watcher to [DirEvent]s relative to the root.
+/// Paths outside of the root are dropped.
+fn dir_events(root: &Path, res: notify::Result<notify::Event>) -> Vec<DirEvent> {
+    let event = match res {
+        Ok(event) => event,
+        Err(e) => {
+            log::warn!("Watching {} failed: {e}", root.display());
+            return vec![DirEvent::Rescan];
+        }
+    };
+    if event.need_rescan() {
+        return vec![DirEvent::Rescan];
+    }
+    let relative = |path: &Path| -> Option<Vec<String>> {
+        path.strip_prefix(root)
+            .ok()?
+            .components()
+            .map(|c| c.as_os_str().to_str().map(String::from))
+            .collect()
+    };
+    let paths = event.paths.iter().map(|p| relative(p)).collect::<Vec<_>>();
+    match (event.kind, paths.as_slice()) {
+        (EventKind::Access(_), _) => vec![],
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [Some(from), Some(to)]) => {
+            vec![DirEvent::Renamed(from.clone(), to.clone())]
+        }
+        _ => paths.into_iter().flatten().map(DirEvent::Changed).collect(),
+    }
+}
+
+#[async_trait]
+impl Writer for FsDir {
+    /// Removes everything inside the root, but not the root itself.
+    async fn clean(&mut self) -> anyhow::Result<()> {
+        let mut read_dir = fs::read_dir(&self.root).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                fs::remove_dir_all(entry.path()).await?;
+            } else {
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_directory(&mut self, path: &[&str]) -> anyhow::Result<()> {
+        let Some((_, parent)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        let dir = self.resolve(path).await?;
+        fs::create_dir_all(self.resolve(parent).await?).await?;
+        fs::create_dir(&dir)
+            .await
+            .with_context(|| format!("Creating {}", dir.display()))
+    }
+
+    async fn write_bytes(&mut self, path: &[&str], content: &[u8]) -> anyhow::Result<()> {
+        let Some((_, parent)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        let file = self.resolve(path).await?;
+        fs::create_dir_all(self.resolve(parent).await?).await?;
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file)
+            .await
+            .with_context(|| format!("Creating {}", file.display()))?;
+        f.write_all(content).await?;
+        Ok(f.flush().await?)
+    }
+
+    /// The content is first written to a temporary file next to the target,
+    /// which then replaces the target, so a crash doesn't leave a half-written
+    /// file.
+    async fn overwrite(&mut self, path: &[&str], content: &[u8]) -> anyhow::Result<()> {
+        let Some((name, parent)) = path.split_last() else {
+            anyhow::bail!("Invalid path");
+        };
+        let file = self.resolve(path).await?;
+        if fs::metadata(&file).await.is_ok_and(|m| m.is_dir()) {
+            anyhow::bail!("'{}' is a directory", path.join("/"));
+        }
+        let parent = self.resolve(parent).await?;
+        fs::create_dir_all(&parent).await?;
+        let tmp = parent.join(format!(".{name}.datahog-tmp"));
+        fs::write(&tmp, content)
+            .await
+            .with_context(|| format!("Writing {}", tmp.display()))?;
+        Ok(fs::rename(&tmp, &file).await?)
+    }
+
+    async fn remove(&mut self, path: &[&str]) -> anyhow::Result<()> {
+        if path.is_empty() {
+            anyhow::bail!("Invalid path");
+        }
+        let target = self.resolve(path).await?;
+        if fs::symlink_metadata(&target).await?.is_dir() {
+            fs::remove_dir_all(&target).await?;
+        } else {
+            fs::remove_file(&target).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&mut self, from: &[&str], to: &[&str]) -> anyhow::Result<()> {
+        if from.is_empty() || to.is_empty() {
+            anyhow::bail!("Invalid path");
+        }
+        if to.starts_with(from) {
+            anyhow::bail!("Cannot move '{}' into itself", from.join("/"));
+        }
+        let (src, dst) = (self.resolve(from).await?, self.resolve(to).await?);
+        if fs::symlink_metadata(&dst).await.is_ok() {
+            anyhow::bail!("'{}' already exists", to.join("/"));
+        }
+        if !fs::metadata(self.resolve(&to[..to.len() - 1]).await?)
+            .await?
+            .is_dir()
+        {
+            anyhow::bail!("Parent of '{}' is not a directory", to.join("/"));
+        }
+        Ok(fs::rename(&src, &dst).await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fs_dir() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut dir = FsDir::new(tmp.path()).await?;
+
+        dir.write_file(&["file1"], "content1").await?;
+        dir.write_file(&["dir1", "file2"], "content2").await?;
+        dir.create_directory(&["dir1", "dir2"]).await?;
+        assert!(dir.write_file(&["file1"], "again").await.is_err());
+        assert!(dir.create_directory(&["dir1"]).await.is_err());
+        assert_eq!(
+            dir.read_directory(&[]).await?,
+            vec![
+                DirectoryEntry::Directory("dir1".into()),
+                DirectoryEntry::File("file1".into())
+            ]
+        );
+        assert_eq!(dir.read_file(&["dir1", "file2"]).await?, "content2");
+
+        dir.rename(&["file1"], &["dir1", "dir2", "file3"]).await?;
+        assert_eq!(dir.read_file(&["dir1", "dir2", "file3"]).await?, "content1");
+        assert!(
+            dir.rename(&["dir1"], &["dir1", "dir2", "dir1"])
+                .await
+                .is_err()
+        );
+        dir.remove(&["dir1", "dir2"]).await?;
+        assert_eq!(
+            dir.read_directory(&["dir1"]).await?,
+            vec![DirectoryEntry::File("file2".into())]
+        );
+
+        let binary = [0u8, 159, 146, 150];
+        dir.write_bytes(&["binary"], &binary).await?;
+        assert_eq!(&dir.read_bytes(&["binary"]).await?[..], &binary);
+        assert!(dir.read_file(&["binary"]).await.is_err());
+        dir.overwrite(&["binary"], b"text").await?;
+        dir.overwrite(&["dir3", "new"], b"new").await?;
+        assert!(dir.overwrite(&["dir1"], b"").await.is_err());
+        assert_eq!(dir.read_file(&["binary"]).await?, "text");
+        assert_eq!(dir.read_file(&["dir3", "new"]).await?, "new");
+        let meta = dir.metadata(&["binary"]).await?;
+        assert_eq!((meta.kind, meta.size), (EntryKind::File, 4));
+        assert!(meta.modified > 0);
+        assert_eq!(dir.metadata(&["dir1"]).await?.kind, EntryKind::Directory);
+        assert!(dir.metadata(&["missing"]).await.is_err());
+
+        dir.clean().await?;
+        assert!(dir.read_directory(&[]).await?.is_empty());
+        assert!(tmp.path().exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_confinement() -> anyhow::Result<()> {
+        let outside = tempfile::tempdir()?;
+        std::fs::write(outside.path().join("secret"), "secret")?;
+        let tmp = tempfile::tempdir()?;
+        std::fs::create_dir(tmp.path().join("root"))?;
+        std::fs::write(tmp.path().join("sibling"), "sibling")?;
+        let mut dir = FsDir::new(tmp.path().join("root")).await?;
+
+        for path in [
+            vec![".."],
+            vec!["..", "sibling"],
+            vec!["a", "..", "..", "sibling"],
+            vec!["."],
+            vec!["/etc"],
+            vec!["a/../../sibling"],
+            vec![""],
+        ] {
+            assert!(dir.read_file(&path).await.is_err(), "{path:?}");
+            assert!(dir.write_file(&path, "x").await.is_err(), "{path:?}");
+        }
+        assert!(!tmp.path().join("x").exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            let root = tmp.path().join("root");
+            symlink(outside.path(), root.join("link_dir"))?;
+            symlink(outside.path().join("secret"), root.join("link_file"))?;
+            std::fs::write(root.join("inside"), "inside")?;
+            symlink(root.join("inside"), root.join("link_inside"))?;
+
+            assert!(dir.read_file(&["link_file"]).await.is_err());
+            assert!(dir.read_file(&["link_dir", "secret"]).await.is_err());
+            assert!(dir.write_file(&["link_dir", "new"], "x").await.is_err());
+            assert!(dir.overwrite(&["link_dir", "secret"], b"x").await.is_err());
+            assert!(dir.overwrite(&["link_file"], b"x").await.is_err());
+            assert!(dir.create_directory(&["link_dir", "new"]).await.is_err());
+            assert!(
+                dir.rename(&["inside"], &["link_dir", "inside"])
+                    .await
+                    .is_err()
+            );
+            assert!(!outside.path().join("new").exists());
+            assert_eq!(std::fs::read(outside.path().join("secret"))?, b"secret");
+            assert_eq!(dir.read_file(&["link_inside"]).await?, "inside");
+            assert_eq!(
+                dir.read_directory(&[]).await?,
+                vec![
+                    DirectoryEntry::File("inside".into()),
+                    DirectoryEntry::File("link_inside".into())
+                ]
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch() -> anyhow::Result<()> {
+        use crate::{
+            storage::disk::{DiskConfig, SourceDisk},
+            structs::{NodeUpdate, Record, Source, SyncMode},
+        };
+        use std::time::Duration;
+
+        let tmp = tempfile::tempdir()?;
+        let config = DiskConfig {
+            watch: Some(Duration::from_millis(50)),
+            ..DiskConfig::default()
+        };
+        let mut source = SourceDisk::with_config(FsDir::new(tmp.path()).await?, config);
+        let SyncMode::Notify(mut notify) = source.sync_mode() else {
+            panic!("Expected a watched directory");
+        };
+        assert_eq!(source.get_updates().await?.len(), 1);
+
+        let mut dir = FsDir::new(tmp.path()).await?;
+        dir.write_file(&["notes.txt"], "notes").await?;
+        tokio::time::timeout(Duration::from_secs(5), notify.recv()).await?;
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 2, "{txs:?}");
+
+        // A rename only updates the label.
+        dir.rename(&["notes.txt"], &["todo.txt"]).await?;
+        tokio::time::timeout(Duration::from_secs(5), notify.recv()).await?;
+        let records = source
+            .get_updates()
+            .await?
+            .into_iter()
+            .flat_map(|tx| tx.records)
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 1, "{records:?}");
+        let Record::Node(rc) = &records[0] else {
+            panic!("Expected a node update");
+        };
+        assert_eq!(rc.updates, vec![NodeUpdate::Label("todo.txt".into())]);
+        Ok(())
+    }
+}
@@ -0,0 +1,607 @@
+//! Imports the history of a local git repository.
+//! Every commit on the first-parent line of `HEAD` becomes one [Transaction]
+//! with the time of the commit:
+//! - the files of the commit are mapped to nodes like [SourceDisk] does, so
+//!   the same rules for the MIME types, links and ignored files apply
+//! - the author becomes a [NodeKind::Label] node with a `person`
+//!   [DataBlob::Entry], and an [EdgeKind::Using] edge to every file they
+//!   changed
+//!
+//! The last imported commit is stored in the root node, so after a restart
+//! only the newer commits are imported.
+//! The repository is only read, changes to the nodes cannot be written back.
+//!
+//! [NodeKind::Label]: crate::structs::NodeKind::Label
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use bytes::Bytes;
+use either::Either;
+use git2::{Delta, ObjectType, Oid, Repository, Sort};
+
+use crate::{
+    storage::{
+        dir_trait::{DirectoryEntry, EntryKind, Metadata, Reader, Writer},
+        disk::{DiskConfig, SourceDisk},
+    },
+    structs::{
+        DataBlob, Edge, EdgeID, EdgeKind, Node, NodeID, NodeUpdate, Record, Source, SourceID,
+        Timestamp, Transaction, Validity,
+    },
+};
+
+/// The blob of the root node with the last imported commit.
+pub const HEAD_BLOB: u32 = 0;
+
+/// A read-only [Reader] of the tree of a commit.
+#[derive(Clone)]
+pub struct GitTree {
+    location: String,
+    repo: Arc<Mutex<Repository>>,
+    /// The tree which is read, or [None] before the first commit.
+    tree: Option<Oid>,
+    /// When the files were changed, used as their modification time.
+    modified: HashMap<Vec<String>, Timestamp>,
+}
+
+impl std::fmt::Debug for GitTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitTree")
+            .field("location", &self.location)
+            .field("tree", &self.tree)
+            .finish()
+    }
+}
+
+impl GitTree {
+    fn new(location: String, repo: Arc<Mutex<Repository>>) -> Self {
+        Self {
+            location,
+            repo,
+            tree: None,
+            modified: HashMap::new(),
+        }
+    }
+
+    /// Moves to the tree of `commit`, and returns the files which changed
+    /// since the previous tree, with `true` if they still exist.
+    fn checkout(&mut self, commit: Oid, modified: Timestamp) -> Result<Vec<(Vec<String>, bool)>> {
+        let repo = self.repo.lock().unwrap();
+        let tree = repo.find_commit(commit)?.tree()?;
+        let previous = self.tree.map(|oid| repo.find_tree(oid)).transpose()?;
+        let diff = repo.diff_tree_to_tree(previous.as_ref(), Some(&tree), None)?;
+        let mut changed = vec![];
+        for delta in diff.deltas() {
+            let exists = delta.status() != Delta::Deleted;
+            let file = match exists {
+                true => delta.new_file(),
+                false => delta.old_file(),
+            };
+            let Some(path) = file.path().and_then(components) else {
+                continue;
+            };
+            match exists {
+                true => self.modified.insert(path.clone(), modified),
+                false => self.modified.remove(&path),
+            };
+            changed.push((path, exists));
+        }
+        self.tree = Some(tree.id());
+        Ok(changed)
+    }
+
+    fn read_only(&self) -> Result<()> {
+        bail!("The history of {} is read-only", self.location)
+    }
+
+    /// Returns the object at `path`, or the tree itself for an empty path.
+    fn object<'r>(&self, repo: &'r Repository, path: &[&str]) -> Result<git2::Object<'r>> {
+        let Some(tree) = self.tree else {
+            bail!("No commit in {}", self.location);
+        };
+        let tree = repo.find_tree(tree)?;
+        if path.is_empty() {
+            return Ok(tree.into_object());
+        }
+        Ok(tree.get_path(Path::new(&path.join("/")))?.to_object(repo)?)
+    }
+}
+
+#[async_trait]
+impl Reader for GitTree {
+    fn location(&self) -> String {
+        self.location.clone()
+    }
+
+    /// Submodules and non UTF-8 names are skipped.
+    async fn read_directory(&self, path: &[&str]) -> Result<Vec<DirectoryEntry>> {
+        if self.tree.is_none() && path.is_empty() {
+            return Ok(vec![]);
+        }
+        let repo = self.repo.lock().unwrap();
+        let object = self.object(&repo, path)?;
+        let Some(tree) = object.as_tree() else {
+            bail!("{} is not a directory", path.join("/"));
+        };
+        let mut entries = tree
+            .iter()
+            .filter_map(|entry| {
+                let name = entry.name()?.to_string();
+                match entry.kind()? {
+                    ObjectType::Tree => Some(DirectoryEntry::Directory(name)),
+                    ObjectType::Blob => Some(DirectoryEntry::File(name)),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        Ok(entries)
+    }
+
+    async fn read_bytes(&self, path: &[&str]) -> Result<Bytes> {
+        let repo = self.repo.lock().unwrap();
+        let object = self.object(&repo, path)?;
+        let Some(blob) = object.as_blob() else {
+            bail!("{} is not a file", path.join("/"));
+        };
+        Ok(Bytes::copy_from_slice(blob.content()))
+    }
+
+    /// The modification time of a file is the time of the commit which
+    /// changed it last.
+    async fn metadata(&self, path: &[&str]) -> Result<Metadata> {
+        let repo = self.repo.lock().unwrap();
+        let object = self.object(&repo, path)?;
+        Ok(match object.as_blob() {
+            Some(blob) => {
+                let path = path.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                Metadata {
+                    kind: EntryKind::File,
+                    size: blob.size() as u64,
+                    modified: self.modified.get(&path).copied().unwrap_or(0),
+                }
+            }
+            None => Metadata {
+                kind: EntryKind::Directory,
+                size: 0,
+                modified: 0,
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl Writer for GitTree {
+    async fn clean(&mut self) -> Result<()> {
+        self.read_only()
+    }
+
+    async fn create_directory(&mut self, _path: &[&str]) -> Result<()> {
+        self.read_only()
+    }
+
+    async fn write_bytes(&mut self, _path: &[&str], _content: &[u8]) -> Result<()> {
+        self.read_only()
+    }
+
+    async fn overwrite(&mut self, _path: &[&str], _content: &[u8]) -> Result<()> {
+        self.read_only()
+    }
+
+    async fn remove(&mut self, _path: &[&str]) -> Result<()> {
+        self.read_only()
+    }
+
+    async fn rename(&mut self, _from: &[&str], _to: &[&str]) -> Result<()> {
+        self.read_only()
+    }
+}
+
+#[derive(Debug)]
+pub struct SourceGit {
+    disk: SourceDisk<GitTree>,
+    read: bool,
+    /// The last imported commit.
+    head: Option<Oid>,
+    /// The modification time given to the files of the last commit.
+    modified: Timestamp,
+    /// The authors which have been created.
+    authors: HashSet<NodeID>,
+    /// The [EdgeKind::Using] edges from the authors, with the file they point to.
+    using: HashMap<EdgeID, NodeID>,
+}
+
+#[async_trait]
+impl Source for SourceGit {
+    /// Returns one [Transaction] for every new commit, starting with the
+    /// oldest one.
+    /// Commits which only change ignored files are skipped.
+    async fn get_updates(&mut self) -> Result<Vec<Transaction>> {
+        let commits = self.new_commits()?;
+        if commits.is_empty() && !self.read {
+            // Creates the root node of an empty repository, or reads the
+            // tree of the last imported commit after a restart.
+            self.read = true;
+            let mut txs = self.disk.get_updates().await?;
+            for tx in &mut txs {
+                self.head_in_root(&mut tx.records);
+            }
+            return Ok(txs);
+        }
+        let mut txs = vec![];
+        for oid in commits {
+            let (name, email, seconds) = {
+                let repo = self.repo();
+                let repo = repo.lock().unwrap();
+                let commit = repo.find_commit(oid)?;
+                let author = commit.author();
+                (
+                    author.name().unwrap_or_default().to_string(),
+                    author.email().unwrap_or_default().to_string(),
+                    commit.time().seconds(),
+                )
+            };
+            let timestamp = seconds as Timestamp * 1_000_000_000;
+            // Commits can have the same time, but the files need to be read
+            // again if they changed.
+            self.modified = timestamp.max(self.modified + 1);
+            let changed = self.disk.disk_mut().checkout(oid, self.modified)?;
+            for (path, _) in changed.iter().filter(|(_, exists)| !exists) {
+                if let Some(id) = self.disk.node_of(path) {
+                    self.using.retain(|_, file| file != &id);
+                }
+            }
+
+            let mut records = self
+                .disk
+                .get_updates()
+                .await?
+                .into_iter()
+                .flat_map(|tx| tx.records)
+                .collect::<Vec<_>>();
+            let files = changed
+                .iter()
+                .filter(|(_, exists)| *exists)
+                .filter_map(|(path, _)| self.disk.node_of(path))
+                .collect::<Vec<_>>();
+            if !files.is_empty() {
+                records.extend(self.author_records(&name, &email, files, timestamp));
+            }
+            self.head = Some(oid);
+            if !records.is_empty() {
+                if !self.head_in_root(&mut records) {
+                    records.extend(
+                        Transaction::update_node(
+                            self.disk.get_id().root_node(),
+                            vec![NodeUpdate::DataBlob(
+                                HEAD_BLOB,
+                                DataBlob::Text(oid.to_string()),
+                            )],
+                        )
+                        .records,
+                    );
+                }
+                txs.push(Transaction { timestamp, records });
+            }
+        }
+        self.read = true;
+        Ok(txs)
+    }
+
+    async fn add_tx(&mut self, _txs: Vec<Transaction>) -> Result<()> {
+        self.disk.disk_mut().read_only()
+    }
+
+    /// Returns the unique ID of this source, derived from the path of the
+    /// repository.
+    fn get_id(&self) -> SourceID {
+        self.disk.get_id()
+    }
+
+    /// Continues after the last imported commit, and collects the authors
+    /// and their [EdgeKind::Using] edges, so they are not created again.
+    fn restore(&mut self, root: &NodeID, nodes: &HashMap<NodeID, Node>) {
+        self.disk.restore(root, nodes);
+        for edge in nodes.values().flat_map(|node| &node.edges) {
+            if let EdgeKind::Using { client, object } = &edge.kind
+                && let Some(DataBlob::Entry(_, fields)) =
+                    nodes.get(client).and_then(|n| n.data_blob.get(&0))
+                && let Some(DataBlob::Text(email)) = fields.get("email")
+                && &self.author_id(email) == client
+            {
+                self.authors.insert(client.clone());
+                self.using.insert(edge.id.clone(), object.clone());
+            }
+        }
+        let Some(DataBlob::Text(head)) = nodes.get(root).and_then(|n| n.data_blob.get(&HEAD_BLOB))
+        else {
+            return;
+        };
+        if let Err(e) = self.restore_head(head) {
+            log::warn!("Importing the whole history, as {head} is not found: {e:?}");
+        }
+    }
+}
+
+impl SourceGit {
+    /// Opens the repository at `path`, or in one of its parents.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_config(path, DiskConfig::default())
+    }
+
+    pub fn with_config(path: impl AsRef<Path>, config: DiskConfig) -> Result<Self> {
+        let repo = Repository::discover(path)?;
+        let location = format!("git:{}", repo.path().display());
+        let tree = GitTree::new(location, Arc::new(Mutex::new(repo)));
+        Ok(Self {
+            disk: SourceDisk::with_config(tree, config),
+            read: false,
+            head: None,
+            modified: 0,
+            authors: HashSet::new(),
+            using: HashMap::new(),
+        })
+    }
+
+    /// Returns the commits since the last imported one, oldest first.
+    /// Only the first parent of merge commits is followed, as the merge
+    /// commit contains the changes of the other parents.
+    fn new_commits(&mut self) -> Result<Vec<Oid>> {
+        let repo = self.repo();
+        let repo = repo.lock().unwrap();
+        let head = match repo.head() {
+            Ok(head) => head.peel_to_commit()?.id(),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        if Some(head) == self.head {
+            return Ok(vec![]);
+        }
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        walk.simplify_first_parent()?;
+        walk.push(head)?;
+        if let Some(previous) = self.head {
+            // A rewritten history is compared to the last imported tree.
+            if repo.graph_descendant_of(head, previous)? {
+                walk.hide(previous)?;
+            }
+        }
+        Ok(walk.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Moves to the tree of the last imported commit.
+    fn restore_head(&mut self, head: &str) -> Result<()> {
+        let head = Oid::from_str(head)?;
+        let seconds = {
+            let repo = self.repo();
+            let repo = repo.lock().unwrap();
+            repo.find_commit(head)?.time().seconds()
+        };
+        self.modified = seconds as Timestamp * 1_000_000_000;
+        self.disk.disk_mut().checkout(head, self.modified)?;
+        self.head = Some(head);
+        Ok(())
+    }
+
+    /// Adds the last imported commit to the root node, if it is created by
+    /// the records, and returns true if it has been found.
+    fn head_in_root(&self, records: &mut [Record]) -> bool {
+        let root = self.disk.get_id().root_node();
+        for record in records {
+            if let (Record::Node(rc), Some(head)) = (record, self.head)
+                && let Either::Right(node) = &mut rc.base
+                && node.id == root
+            {
+                node.data_blob
+                    .insert(HEAD_BLOB, DataBlob::Text(head.to_string()));
+                return true;
+            }
+        }
+        false
+    }
+
+    fn repo(&mut self) -> Arc<Mutex<Repository>> {
+        self.disk.disk_mut().repo.clone()
+    }
+
+    /// Returns the records to create the author, if it's new, and the
+    /// [EdgeKind::Using] edges to the files it changed for the first time.
+    fn author_records(
+        &mut self,
+        name: &str,
+        email: &str,
+        files: Vec<NodeID>,
+        timestamp: Timestamp,
+    ) -> Vec<Record> {
+        let id = self.author_id(email);
+        let mut txs = vec![];
+        if self.authors.insert(id.clone()) {
+            let mut author = Node::label(name);
+            author.id = id.clone();
+            author.data_blob.insert(
+                0,
+                DataBlob::Entry(
+                    "person".into(),
+                    HashMap::from([
+                        ("name".into(), DataBlob::Text(name.into())),
+                        ("email".into(), DataBlob::Text(email.into())),
+                    ]),
+                ),
+            );
+            txs.push(Transaction::create_node(author));
+        }
+        for file in files {
+            let edge_id =
+                EdgeID::hash_domain_parts("SourceGit-author", &[id.as_ref(), file.as_ref()]);
+            if self.using.contains_key(&edge_id) {
+                continue;
+            }
+            self.using.insert(edge_id.clone(), file.clone());
+            txs.push(Transaction::create_edge(Edge {
+                id: edge_id,
+                kind: EdgeKind::Using {
+                    client: id.clone(),
+                    object: file,
+                },
+                validity: Validity::From(timestamp),
+                history: vec![],
+            }));
+        }
+        txs.into_iter().flat_map(|tx| tx.records).collect()
+    }
+
+    fn author_id(&self, email: &str) -> NodeID {
+        NodeID::hash_domain_parts(
+            "SourceGit-author",
+            &[self.disk.get_id().as_ref(), email.to_lowercase().as_bytes()],
+        )
+    }
+}
+
+/// Splits a relative path into its components, or returns [None] if one of
+/// them is not UTF-8.
+fn components(path: &Path) -> Option<Vec<String>> {
+    path.components()
+        .map(|c| c.as_os_str().to_str().map(String::from))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use git2::{IndexAddOption, Signature, Time};
+
+    use crate::{
+        structs::{NodeUpdate, Record},
+        worldview::WorldView,
+    };
+
+    use super::*;
+
+    /// Commits all files of the working directory.
+    fn commit(repo: &Repository, author: &str, seconds: i64) -> Result<Oid> {
+        let mut index = repo.index()?;
+        index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+        index.update_all(["*"], None)?;
+        index.write()?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let email = format!("{}@example.com", author.to_lowercase());
+        let sig = Signature::new(author, &email, &Time::new(seconds, 0))?;
+        let parents = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit()?],
+            Err(_) => vec![],
+        };
+        let parents = parents.iter().collect::<Vec<_>>();
+        Ok(repo.commit(Some("HEAD"), &sig, &sig, "Update", &tree, &parents)?)
+    }
+
+    #[tokio::test]
+    async fn test_history() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = Repository::init(tmp.path())?;
+        let mut source = SourceGit::new(tmp.path())?;
+        assert_eq!(source.get_updates().await?.len(), 1);
+        assert!(source.get_updates().await?.is_empty());
+
+        let write = |path: &str, content: &str| {
+            let path = tmp.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write("notes.md", "# Notes\n");
+        write("dir/a.txt", "a");
+        commit(&repo, "Alice", 1_000)?;
+        write("dir/a.txt", "b");
+        commit(&repo, "Bob", 1_000)?;
+        fs::rename(tmp.path().join("notes.md"), tmp.path().join("todo.md"))?;
+        commit(&repo, "Alice", 2_000)?;
+
+        let txs = source.get_updates().await?;
+        assert_eq!(
+            txs.iter().map(|tx| tx.timestamp).collect::<Vec<_>>(),
+            vec![1_000_000_000_000, 1_000_000_000_000, 2_000_000_000_000]
+        );
+        let mut ww = WorldView::new();
+        ww.process_updates(txs).await?;
+
+        let path = |p: &str| p.split('/').map(String::from).collect::<Vec<_>>();
+        let a = source.disk.node_of(&path("dir/a.txt")).unwrap();
+        let todo = source.disk.node_of(&path("todo.md")).unwrap();
+        assert_eq!(
+            ww.get_node(&a).unwrap().data_blob[&0],
+            DataBlob::Text("b".into())
+        );
+        // The renamed file keeps its node.
+        let todo_node = ww.get_node(&todo).unwrap();
+        assert_eq!(todo_node.label, "todo.md");
+
+        // Alice and Bob both changed a.txt, Alice also the notes.
+        let authors = |id: &NodeID| {
+            let mut authors = ww
+                .get_node(id)
+                .unwrap()
+                .edges
+                .iter()
+                .filter_map(|e| match &e.kind {
+                    EdgeKind::Using { client, .. } => Some(ww.get_node(client).unwrap().label),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            authors.sort();
+            authors
+        };
+        assert_eq!(authors(&a), vec!["Alice", "Bob"]);
+        assert_eq!(authors(&todo), vec!["Alice"]);
+        assert!(source.get_updates().await?.is_empty());
+
+        fs::remove_file(tmp.path().join("dir/a.txt"))?;
+        commit(&repo, "Bob", 3_000)?;
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 1);
+        assert!(txs[0].records.iter().any(|r| matches!(r,
+            Record::Node(rc) if rc.get_id() == a && rc.updates == vec![NodeUpdate::Delete])));
+        ww.process_updates(txs).await?;
+        assert!(ww.get_node(&a).is_none());
+
+        assert!(source.add_tx(vec![]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = Repository::init(tmp.path())?;
+        fs::write(tmp.path().join("a.txt"), "a")?;
+        commit(&repo, "Alice", 1_000)?;
+        fs::write(tmp.path().join("a.txt"), "b")?;
+        commit(&repo, "Bob", 2_000)?;
+        let mut ww = WorldView::new();
+        let root = ww.add_source(Box::new(SourceGit::new(tmp.path())?)).await?;
+
+        // The history is not imported again.
+        let count = ww.state().transactions.len();
+        let mut ww = WorldView::from_state(ww.state());
+        ww.add_source(Box::new(SourceGit::new(tmp.path())?)).await?;
+        assert_eq!(ww.state().transactions.len(), count);
+
+        // A new commit of a known author only changes the file.
+        fs::write(tmp.path().join("a.txt"), "c")?;
+        let head = commit(&repo, "Alice", 3_000)?;
+        let (txs, _, _) = ww.fetch().await?;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].records.len(), 2);
+        assert_eq!(
+            ww.get_node(&root).unwrap().data_blob[&HEAD_BLOB],
+            DataBlob::Text(head.to_string())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,523 @@
+//! Stores the [Transaction]s of a [WorldView] in a bare git repository, so
+//! the data can be shared, and changes done in different clones merged:
+//! - every call to [GitStore::save] commits the new [Transaction]s, with a
+//!   message summarizing them
+//! - the [Transaction]s are stored one per line, as JSON, in a file per
+//!   month: `transactions/2025-01.jsonl`
+//! - the root [NodeID] of each [crate::structs::Source] is stored in
+//!   `roots.json`
+//!
+//! As [Transaction]s are only ever added, [GitStore::pull] merges two clones
+//! by taking the lines added on both sides, and ordering all [Transaction]s
+//! by their timestamp.
+//! The `.gitattributes` file uses the `union` merge driver for the
+//! [Transaction] files, so merging them with the git command line also keeps
+//! the lines of both sides.
+//!
+//! [WorldView]: crate::worldview::WorldView
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{Result, bail};
+use chrono::DateTime;
+use git2::{FileMode, ObjectType, Oid, Repository, Signature, Tree};
+
+use crate::{
+    structs::{EdgeAction, NodeID, NodeUpdate, Record, SourceID, Transaction},
+    worldview::WorldViewState,
+};
+
+/// The directory with the [Transaction] files.
+pub const TRANSACTIONS_DIR: &str = "transactions";
+/// The file with the root [NodeID]s.
+pub const ROOTS_FILE: &str = "roots.json";
+const ATTRIBUTES: &str = "transactions/*.jsonl merge=union\n";
+
+/// The result of [GitStore::pull].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pulled {
+    /// The other clone has nothing new.
+    UpToDate,
+    /// Only the other clone had new commits.
+    FastForward(Oid),
+    /// Both clones had new commits, which are joined in a merge commit.
+    Merged(Oid),
+}
+
+/// The files of a commit, by their path.
+type Files = BTreeMap<String, Vec<u8>>;
+
+pub struct GitStore {
+    repo: Repository,
+}
+
+impl std::fmt::Debug for GitStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitStore")
+            .field("path", &self.repo.path())
+            .finish()
+    }
+}
+
+impl GitStore {
+    /// Opens the bare repository at `path`, or creates it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let repo = match Repository::open_bare(path.as_ref()) {
+            Ok(repo) => repo,
+            Err(_) => Repository::init_bare(path.as_ref())?,
+        };
+        Ok(Self { repo })
+    }
+
+    /// Returns the stored state, with the [Transaction]s ordered by their
+    /// timestamp.
+    pub fn load(&self) -> Result<WorldViewState> {
+        let files = self.files(self.head()?)?;
+        let mut transactions = vec![];
+        for (path, content) in &files {
+            if !is_transactions(path) {
+                continue;
+            }
+            for line in std::str::from_utf8(content)?.lines() {
+                if !line.trim().is_empty() {
+                    transactions.push(serde_json::from_str::<Transaction>(line)?);
+                }
+            }
+        }
+        transactions.sort_by_key(|tx| tx.timestamp);
+        let source_root = match files.get(ROOTS_FILE) {
+            Some(roots) => serde_json::from_slice::<Vec<(SourceID, NodeID)>>(roots)?
+                .into_iter()
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(WorldViewState {
+            transactions,
+            source_root,
+        })
+    }
+
+    /// Commits the [Transaction]s of the state which are not yet stored, and
+    /// the root [NodeID]s.
+    /// Returns [None] if nothing changed.
+    pub fn save(&mut self, state: &WorldViewState) -> Result<Option<Oid>> {
+        let head = self.head()?;
+        let mut files = self.files(head)?;
+        let mut stored: HashMap<String, usize> = HashMap::new();
+        for (path, content) in &files {
+            if is_transactions(path) {
+                for line in String::from_utf8_lossy(content).lines() {
+                    *stored.entry(line.to_string()).or_default() += 1;
+                }
+            }
+        }
+
+        let mut new = vec![];
+        for tx in &state.transactions {
+            let line = to_line(tx)?;
+            match stored.get_mut(&line) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => {
+                    let file = files.entry(transactions_file(tx)).or_default();
+                    file.extend(line.as_bytes());
+                    file.push(b'\n');
+                    new.push(tx);
+                }
+            }
+        }
+        let mut roots = state
+            .source_root
+            .iter()
+            .map(|(sid, root)| (sid.clone(), root.clone()))
+            .collect::<Vec<_>>();
+        roots.sort_by_key(|(sid, _)| sid.to_string());
+        let roots = serde_json::to_vec_pretty(&roots)?;
+        if new.is_empty() && files.get(ROOTS_FILE) == Some(&roots) {
+            return Ok(None);
+        }
+        files.insert(ROOTS_FILE.into(), roots);
+        files.insert(".gitattributes".into(), ATTRIBUTES.into());
+
+        let tree = self.write_tree(&files)?;
+        let parents = head.into_iter().collect::<Vec<_>>();
+        Ok(Some(self.commit(&message(&new), tree, &parents)?))
+    }
+
+    /// Fetches the commits of another clone, given by its path or URL, and
+    /// merges them.
+    pub fn pull(&mut self, url: &str) -> Result<Pulled> {
+        const FETCHED: &str = "refs/datahog/fetched";
+        let mut remote = self.repo.remote_anonymous(url)?;
+        remote.fetch(&[&format!("+HEAD:{FETCHED}")], None, None)?;
+        let theirs = self.repo.refname_to_id(FETCHED)?;
+        let Some(ours) = self.head()? else {
+            self.set_head(theirs)?;
+            return Ok(Pulled::FastForward(theirs));
+        };
+        if ours == theirs || self.repo.graph_descendant_of(ours, theirs)? {
+            return Ok(Pulled::UpToDate);
+        }
+        if self.repo.graph_descendant_of(theirs, ours)? {
+            self.set_head(theirs)?;
+            return Ok(Pulled::FastForward(theirs));
+        }
+
+        let base = self.repo.merge_base(ours, theirs).ok();
+        let base = self.files(base)?;
+        let (ours_files, theirs_files) = (self.files(Some(ours))?, self.files(Some(theirs))?);
+        let mut merged = ours_files.clone();
+        for (path, content) in theirs_files {
+            let file = match merged.get(&path) {
+                None => content,
+                Some(ours) if is_transactions(&path) => {
+                    merge_lines(base.get(&path), ours, &content)?
+                }
+                Some(ours) if path == ROOTS_FILE => merge_roots(ours, &content)?,
+                Some(ours) => ours.clone(),
+            };
+            merged.insert(path, file);
+        }
+        let tree = self.write_tree(&merged)?;
+        let message = format!("Merge transactions from {url}");
+        let oid = self.commit(&message, tree, &[ours, theirs])?;
+        Ok(Pulled::Merged(oid))
+    }
+
+    /// Returns the commit of `HEAD`, or [None] if there is none yet.
+    fn head(&self) -> Result<Option<Oid>> {
+        match self.repo.head() {
+            Ok(head) => Ok(Some(head.peel_to_commit()?.id())),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Points the branch of `HEAD` to the commit.
+    fn set_head(&self, commit: Oid) -> Result<()> {
+        let head = self.repo.find_reference("HEAD")?;
+        let Some(branch) = head.symbolic_target() else {
+            bail!("HEAD of {} is not a branch", self.repo.path().display());
+        };
+        self.repo
+            .reference(branch, commit, true, "datahog: fast-forward")?;
+        Ok(())
+    }
+
+    fn commit(&self, message: &str, tree: Oid, parents: &[Oid]) -> Result<Oid> {
+        let signature = self
+            .repo
+            .signature()
+            .or_else(|_| Signature::now("DataHog", "datahog@localhost"))?;
+        let tree = self.repo.find_tree(tree)?;
+        let parents = parents
+            .iter()
+            .map(|oid| self.repo.find_commit(*oid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let parents = parents.iter().collect::<Vec<_>>();
+        Ok(self.repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?)
+    }
+
+    /// Returns all files of the commit, or no files for [None].
+    fn files(&self, commit: Option<Oid>) -> Result<Files> {
+        let mut files = Files::new();
+        if let Some(commit) = commit {
+            let tree = self.repo.find_commit(commit)?.tree()?;
+            self.read_tree(&tree, "", &mut files)?;
+        }
+        Ok(files)
+    }
+
+    fn read_tree(&self, tree: &Tree, prefix: &str, files: &mut Files) -> Result<()> {
+        for entry in tree {
+            let Some(name) = entry.name() else {
+                continue;
+            };
+            let path = format!("{prefix}{name}");
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    let tree = entry.to_object(&self.repo)?.peel_to_tree()?;
+                    self.read_tree(&tree, &format!("{path}/"), files)?;
+                }
+                Some(ObjectType::Blob) => {
+                    let blob = entry.to_object(&self.repo)?.peel_to_blob()?;
+                    files.insert(path, blob.content().to_vec());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the files, whose paths are separated by `/`, as a tree.
+    fn write_tree(&self, files: &Files) -> Result<Oid> {
+        let mut dirs: BTreeMap<&str, Files> = BTreeMap::new();
+        let mut builder = self.repo.treebuilder(None)?;
+        for (path, content) in files {
+            match path.split_once('/') {
+                Some((dir, rest)) => {
+                    dirs.entry(dir)
+                        .or_default()
+                        .insert(rest.to_string(), content.clone());
+                }
+                None => {
+                    let blob = self.repo.blob(content)?;
+                    builder.insert(path, blob, FileMode::Blob.into())?;
+                }
+            }
+        }
+        for (dir, files) in dirs {
+            let tree = self.write_tree(&files)?;
+            builder.insert(dir, tree, FileMode::Tree.into())?;
+        }
+        Ok(builder.write()?)
+    }
+}
+
+fn is_transactions(path: &str) -> bool {
+    path.starts_with(&format!("{TRANSACTIONS_DIR}/")) && path.ends_with(".jsonl")
+}
+
+/// Returns the file for the month of the [Transaction].
+fn transactions_file(tx: &Transaction) -> String {
+    let nanos = i64::try_from(tx.timestamp).unwrap_or(i64::MAX);
+    let month = DateTime::from_timestamp_nanos(nanos).format("%Y-%m");
+    format!("{TRANSACTIONS_DIR}/{month}.jsonl")
+}
+
+/// Returns the JSON of the [Transaction] with sorted keys, so the same
+/// [Transaction] always gives the same line.
+fn to_line(tx: &Transaction) -> Result<String> {
+    Ok(serde_json::to_string(&serde_json::to_value(tx)?)?)
+}
+
+/// Returns the lines of the base, followed by the lines added by both
+/// sides, ordered by the timestamp of their [Transaction].
+fn merge_lines(base: Option<&Vec<u8>>, ours: &[u8], theirs: &[u8]) -> Result<Vec<u8>> {
+    let lines = |content: &[u8]| {
+        String::from_utf8_lossy(content)
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    let mut lines_base: HashMap<String, usize> = HashMap::new();
+    for line in lines(base.map(|b| b.as_slice()).unwrap_or_default()) {
+        *lines_base.entry(line).or_default() += 1;
+    }
+    let mut merged = lines(ours);
+    for line in lines(theirs) {
+        match lines_base.get_mut(&line) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => merged.push(line),
+        }
+    }
+    let mut timestamped = merged
+        .into_iter()
+        .map(|line| Ok((serde_json::from_str::<Transaction>(&line)?.timestamp, line)))
+        .collect::<Result<Vec<_>>>()?;
+    timestamped.sort_by_key(|(timestamp, _)| *timestamp);
+    Ok(timestamped
+        .into_iter()
+        .flat_map(|(_, line)| [line.into_bytes(), b"\n".to_vec()])
+        .flatten()
+        .collect())
+}
+
+/// Returns the roots of both sides, where our root wins if a
+/// [crate::structs::Source] has two.
+fn merge_roots(ours: &[u8], theirs: &[u8]) -> Result<Vec<u8>> {
+    let mut roots = serde_json::from_slice::<Vec<(SourceID, NodeID)>>(ours)?;
+    for (sid, root) in serde_json::from_slice::<Vec<(SourceID, NodeID)>>(theirs)? {
+        if !roots.iter().any(|(s, _)| s == &sid) {
+            roots.push((sid, root));
+        }
+    }
+    roots.sort_by_key(|(sid, _)| sid.to_string());
+    Ok(serde_json::to_vec_pretty(&roots)?)
+}
+
+/// Summarizes the [Transaction]s, e.g.,
+/// `Store 2 transactions: 3 nodes created, 1 edge deleted`, followed by the
+/// labels of the created nodes.
+fn message(txs: &[&Transaction]) -> String {
+    let mut counts: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    let mut labels = vec![];
+    for record in txs.iter().flat_map(|tx| &tx.records) {
+        let (kind, created, deleted) = match record {
+            Record::Node(rc) => {
+                if let either::Either::Right(node) = &rc.base {
+                    labels.push(node.label.clone());
+                }
+                let deleted = rc.updates.contains(&NodeUpdate::Delete);
+                ("node", rc.base.is_right(), deleted)
+            }
+            Record::Edge(rc) => {
+                let deleted = rc.updates.contains(&EdgeAction::Delete);
+                ("edge", rc.base.is_right(), deleted)
+            }
+        };
+        let action = match (created, deleted) {
+            (_, true) => "deleted",
+            (true, _) => "created",
+            _ => "updated",
+        };
+        *counts.entry((kind, action)).or_default() += 1;
+    }
+
+    let plural = |count: usize, word: &str| match count {
+        1 => format!("1 {word}"),
+        _ => format!("{count} {word}s"),
+    };
+    let mut subject = match txs.len() {
+        0 => "Update the roots".to_string(),
+        n => format!("Store {}", plural(n, "transaction")),
+    };
+    let counts = ["node", "edge"]
+        .iter()
+        .flat_map(|kind| ["created", "updated", "deleted"].map(|action| (*kind, action)))
+        .filter_map(|key| Some(format!("{} {}", plural(*counts.get(&key)?, key.0), key.1)))
+        .collect::<Vec<_>>();
+    if !counts.is_empty() {
+        subject = format!("{subject}: {}", counts.join(", "));
+    }
+    if labels.is_empty() {
+        return subject;
+    }
+    const MAX_LABELS: usize = 10;
+    let mut body = labels
+        .iter()
+        .take(MAX_LABELS)
+        .map(|label| format!("- {label}"))
+        .collect::<Vec<_>>();
+    if labels.len() > MAX_LABELS {
+        body.push(format!("- and {} more", labels.len() - MAX_LABELS));
+    }
+    format!("{subject}\n\nCreated nodes:\n{}\n", body.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use git2::build::RepoBuilder;
+
+    use crate::{
+        storage::{dir_trait::EmulatedDir, disk::SourceDisk},
+        structs::{Edge, Node},
+        worldview::WorldView,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_load() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut store = GitStore::open(tmp.path())?;
+        assert_eq!(store.load()?, WorldViewState::default());
+
+        let dir = EmulatedDir::new_from_string(&[("notes.md", "# Notes\n")]);
+        let mut ww = WorldView::new();
+        ww.add_source(Box::new(SourceDisk::new(dir))).await?;
+        let oid = store.save(&ww.state())?.expect("a commit");
+        let message = store.repo.find_commit(oid)?.message().unwrap().to_string();
+        assert!(
+            message.starts_with("Store 3 transactions: 2 nodes created, 1 edge created"),
+            "{message}"
+        );
+        assert!(message.contains("- notes.md"));
+        assert_eq!(store.save(&ww.state())?, None);
+
+        // The stored state recreates the same WorldView.
+        let store = GitStore::open(tmp.path())?;
+        assert_eq!(store.load()?, ww.state());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut store = GitStore::open(tmp.path())?;
+        let dir = EmulatedDir::new_from_string(&[("notes.md", "# Notes\n"), ("todo.txt", "")]);
+        let mut ww = WorldView::new();
+        ww.add_source(Box::new(SourceDisk::new(dir.clone())))
+            .await?;
+        store.save(&ww.state())?;
+        let count = ww.state().transactions.len();
+        // Adding the source again after a restart doesn't store its
+        // transactions a second time.
+        for _ in 0..2 {
+            let mut ww = WorldView::from_state(store.load()?);
+            ww.add_source(Box::new(SourceDisk::new(dir.clone())))
+                .await?;
+            assert_eq!(store.save(&ww.state())?, None);
+            assert_eq!(store.load()?.transactions.len(), count);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let (path_a, path_b) = (tmp.path().join("a"), tmp.path().join("b"));
+        let mut a = GitStore::open(&path_a)?;
+        let mut ww_a = WorldView::new();
+        let dir = EmulatedDir::new_from_string(&[("notes.md", "# Notes\n")]);
+        let root = ww_a.add_source(Box::new(SourceDisk::new(dir))).await?;
+        a.save(&ww_a.state())?;
+
+        RepoBuilder::new()
+            .bare(true)
+            .clone(path_a.to_str().unwrap(), &path_b)?;
+        let mut b = GitStore::open(&path_b)?;
+        let mut ww_b = WorldView::from_state(b.load()?);
+        assert_eq!(ww_b.state(), ww_a.state());
+
+        // Both sides add a node to the root.
+        let add = async |ww: &mut WorldView, label: &str| -> Result<NodeID> {
+            let node = Node::label(label);
+            let txs = vec![
+                Transaction::create_node(node.clone()),
+                Transaction::create_edge(Edge::contains(root.clone(), node.id.clone())),
+            ];
+            ww.add_transactions(&SourceID::rnd(), txs).await?;
+            Ok(node.id)
+        };
+        let from_a = add(&mut ww_a, "from a").await?;
+        a.save(&ww_a.state())?;
+        let from_b = add(&mut ww_b, "from b").await?;
+        b.save(&ww_b.state())?;
+
+        assert!(matches!(
+            b.pull(path_a.to_str().unwrap())?,
+            Pulled::Merged(_)
+        ));
+        assert_eq!(b.pull(path_a.to_str().unwrap())?, Pulled::UpToDate);
+        assert!(matches!(
+            a.pull(path_b.to_str().unwrap())?,
+            Pulled::FastForward(_)
+        ));
+        let (state_a, state_b) = (a.load()?, b.load()?);
+        assert_eq!(state_a, state_b);
+        assert_eq!(state_a.transactions.len(), 7);
+        assert!(
+            state_a
+                .transactions
+                .windows(2)
+                .all(|txs| txs[0].timestamp <= txs[1].timestamp)
+        );
+
+        let ww = WorldView::from_state(state_a);
+        assert!(ww.get_node(&from_a).is_some());
+        assert!(ww.get_node(&from_b).is_some());
+        assert_eq!(ww.get_node(&root).unwrap().edges.len(), 3);
+        Ok(())
+    }
+}
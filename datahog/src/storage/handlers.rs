@@ -0,0 +1,288 @@
+//! Turns the content of files into [DataBlob]s, depending on their MIME type.
+//! [detect_mime] finds the type from the magic bytes and the name of the file,
+//! and [Handlers] selects the [FileHandler] for it:
+//! - markdown files are split into blocks, see [crate::markdown]
+//! - text files are stored inline as [DataBlob::Text]
+//! - images are stored by their [DataBlob::Hash], with their size and
+//!   dimensions
+//! - all other files are stored by their [DataBlob::Hash] and size
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use flarch::nodeids::U256;
+
+use crate::{
+    markdown,
+    structs::{DataBlob, Node},
+};
+
+/// Returns the MIME type of a file.
+/// Binary formats are recognized by their magic bytes, whatever the name of
+/// the file, all other files by their extension.
+/// Text files which are not valid UTF-8 are `application/octet-stream`.
+pub fn detect_mime(name: &str, content: &[u8]) -> String {
+    let magic = infer::get(content).map(|t| t.mime_type());
+    let extension = mime_guess::from_path(name).first_raw();
+    let text = std::str::from_utf8(content).is_ok();
+    let mime = match (magic, extension) {
+        (Some(magic), _) if !magic.starts_with("text/") => magic,
+        (_, Some(extension)) => extension,
+        (Some(magic), None) => magic,
+        (None, None) if text => "text/plain",
+        (None, None) => "application/octet-stream",
+    };
+    match mime.starts_with("text/") && !text {
+        true => "application/octet-stream".into(),
+        false => mime.into(),
+    }
+}
+
+/// Converts between the content of a file and the [DataBlob]s of its [Node].
+pub trait FileHandler: std::fmt::Debug + Send + Sync {
+    /// Replaces the [DataBlob]s and the [crate::structs::DataView] of the
+    /// node with the content of the file.
+    fn import(&self, node: &mut Node, content: &Bytes);
+
+    /// Returns the content of the file, or [None] if the node doesn't hold
+    /// it, e.g., if only its hash is known.
+    fn export(&self, node: &Node) -> Option<Bytes>;
+}
+
+/// Splits markdown files into blocks, see [crate::markdown].
+#[derive(Debug)]
+pub struct MarkdownHandler;
+
+impl FileHandler for MarkdownHandler {
+    fn import(&self, node: &mut Node, content: &Bytes) {
+        markdown::import(node, &String::from_utf8_lossy(content));
+    }
+
+    fn export(&self, node: &Node) -> Option<Bytes> {
+        Some(Bytes::from(markdown::export(node)))
+    }
+}
+
+/// Stores the file as a [DataBlob::Text] at index 0, or as [DataBlob::Bytes]
+/// if it is not valid UTF-8.
+#[derive(Debug)]
+pub struct TextHandler;
+
+impl FileHandler for TextHandler {
+    fn import(&self, node: &mut Node, content: &Bytes) {
+        let blob = match std::str::from_utf8(content) {
+            Ok(text) => DataBlob::Text(text.into()),
+            Err(_) => DataBlob::Bytes(content.clone()),
+        };
+        node.data_blob = HashMap::from([(0, blob)]);
+    }
+
+    fn export(&self, node: &Node) -> Option<Bytes> {
+        blob_content(node)
+    }
+}
+
+/// Stores the [DataBlob::Hash] of the file at index 0, and its size in a
+/// `file` [DataBlob::Entry] at index 1.
+#[derive(Debug)]
+pub struct BinaryHandler;
+
+impl FileHandler for BinaryHandler {
+    fn import(&self, node: &mut Node, content: &Bytes) {
+        node.data_blob = HashMap::from([
+            (0, DataBlob::Hash(U256::hash_data(content))),
+            (1, entry("file", [("size", content.len() as u64)])),
+        ]);
+    }
+
+    /// The content is only available if it has been set as [DataBlob::Bytes].
+    fn export(&self, node: &Node) -> Option<Bytes> {
+        blob_content(node)
+    }
+}
+
+/// Stores images like the [BinaryHandler], with an `image` [DataBlob::Entry]
+/// which also has the `width` and `height` of PNG, GIF and JPEG images.
+#[derive(Debug)]
+pub struct ImageHandler;
+
+impl FileHandler for ImageHandler {
+    fn import(&self, node: &mut Node, content: &Bytes) {
+        let mut fields = vec![("size", content.len() as u64)];
+        if let Some((width, height)) = image_size(content) {
+            fields.extend([("width", width.into()), ("height", height.into())]);
+        }
+        node.data_blob = HashMap::from([
+            (0, DataBlob::Hash(U256::hash_data(content))),
+            (1, entry("image", fields)),
+        ]);
+    }
+
+    fn export(&self, node: &Node) -> Option<Bytes> {
+        blob_content(node)
+    }
+}
+
+/// The [FileHandler]s for the MIME types.
+#[derive(Debug)]
+pub struct Handlers {
+    /// Types like `image/png`, or all subtypes of a type like `image/*`.
+    handlers: HashMap<String, Box<dyn FileHandler>>,
+}
+
+impl Default for Handlers {
+    fn default() -> Self {
+        let mut handlers = Self {
+            handlers: HashMap::new(),
+        };
+        handlers.register("text/markdown", MarkdownHandler);
+        handlers.register("text/*", TextHandler);
+        for mime in [
+            "application/json",
+            "application/javascript",
+            "application/toml",
+            "application/xml",
+            "application/yaml",
+            "image/svg+xml",
+        ] {
+            handlers.register(mime, TextHandler);
+        }
+        handlers.register("image/*", ImageHandler);
+        handlers
+    }
+}
+
+impl Handlers {
+    /// Sets the [FileHandler] for a type like `image/png`, or for all subtypes
+    /// of a type with `image/*`.
+    pub fn register(&mut self, mime: &str, handler: impl FileHandler + 'static) {
+        self.handlers.insert(mime.into(), Box::new(handler));
+    }
+
+    /// Returns the [FileHandler] registered for the type, or for all its
+    /// subtypes, or the [BinaryHandler].
+    pub fn get(&self, mime: &str) -> &dyn FileHandler {
+        let all = format!("{}/*", mime.split('/').next().unwrap_or_default());
+        self.handlers
+            .get(mime)
+            .or_else(|| self.handlers.get(&all))
+            .map(|handler| handler.as_ref())
+            .unwrap_or(&BinaryHandler)
+    }
+}
+
+fn entry<'a>(name: &str, fields: impl IntoIterator<Item = (&'a str, u64)>) -> DataBlob {
+    DataBlob::Entry(
+        name.into(),
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), DataBlob::Int(v.into())))
+            .collect(),
+    )
+}
+
+/// Returns the content of the [DataBlob::Text] or [DataBlob::Bytes] at index 0.
+fn blob_content(node: &Node) -> Option<Bytes> {
+    match node.data_blob.get(&0)? {
+        DataBlob::Text(text) => Some(Bytes::from(text.clone())),
+        DataBlob::Bytes(bytes) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+/// Returns the width and height of PNG, GIF and JPEG images.
+fn image_size(content: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| u16::from_be_bytes([content[i], content[i + 1]]) as u32;
+    let be32 = |i: usize| u32::from_be_bytes(content[i..i + 4].try_into().unwrap());
+    if content.starts_with(b"\x89PNG\r\n\x1a\n") && content.len() >= 24 {
+        return Some((be32(16), be32(20)));
+    }
+    if content.starts_with(b"GIF8") && content.len() >= 10 {
+        let le16 = |i: usize| u16::from_le_bytes([content[i], content[i + 1]]) as u32;
+        return Some((le16(6), le16(8)));
+    }
+    if content.starts_with(&[0xff, 0xd8]) {
+        let mut pos = 2;
+        while pos + 9 <= content.len() && content[pos] == 0xff {
+            let marker = content[pos + 1];
+            // Start of frame, except for the huffman and arithmetic tables.
+            if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+                return Some((be16(pos + 7), be16(pos + 5)));
+            }
+            pos += 2 + be16(pos + 2) as usize;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\x01\x00\0\0\0\x20rest";
+    const JPEG: &[u8] = &[
+        0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x00, 0x00, 0xff, 0xc0, 0x00, 0x11, 0x08, 0x00, 0x40,
+        0x00, 0x80, 0x03,
+    ];
+
+    #[test]
+    fn test_detect_mime() {
+        assert_eq!(detect_mime("notes.md", b"# Notes"), "text/markdown");
+        assert_eq!(detect_mime("main.rs", b"fn main() {}"), "text/x-rust");
+        assert_eq!(detect_mime("README", b"Read me"), "text/plain");
+        assert_eq!(
+            detect_mime("data", &[0, 159, 146, 150]),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            detect_mime("broken.txt", &[0, 159, 146, 150]),
+            "application/octet-stream"
+        );
+        // The content wins over the extension.
+        assert_eq!(detect_mime("image.txt", PNG), "image/png");
+        assert_eq!(detect_mime("photo", JPEG), "image/jpeg");
+    }
+
+    #[test]
+    fn test_handlers() {
+        let handlers = Handlers::default();
+        let import = |mime: &str, content: &[u8]| {
+            let mut node = Node::mime(mime.into(), "file".into());
+            handlers
+                .get(mime)
+                .import(&mut node, &Bytes::copy_from_slice(content));
+            node
+        };
+
+        let node = import("text/x-rust", b"fn main() {}");
+        assert_eq!(node.data_blob[&0], DataBlob::Text("fn main() {}".into()));
+        assert_eq!(
+            handlers.get("text/x-rust").export(&node),
+            Some(Bytes::from("fn main() {}"))
+        );
+
+        let node = import("image/png", PNG);
+        assert_eq!(node.data_blob[&0], DataBlob::Hash(U256::hash_data(PNG)));
+        assert_eq!(
+            node.data_blob[&1],
+            entry("image", [("size", 28), ("width", 256), ("height", 32)])
+        );
+        assert_eq!(handlers.get("image/png").export(&node), None);
+        assert_eq!(image_size(JPEG), Some((128, 64)));
+
+        let node = import("application/pdf", b"%PDF");
+        assert_eq!(node.data_blob[&1], entry("file", [("size", 4)]));
+
+        // Registered handlers replace the default ones.
+        let mut handlers = Handlers::default();
+        handlers.register("image/png", TextHandler);
+        assert_eq!(
+            format!("{:?}", handlers.get("image/png")),
+            format!("{TextHandler:?}")
+        );
+        assert_eq!(
+            format!("{:?}", handlers.get("image/gif")),
+            format!("{ImageHandler:?}")
+        );
+    }
+}
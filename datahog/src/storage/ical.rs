@@ -0,0 +1,1094 @@
+//! Reads the iCalendar files of a directory, like the calendars exported by a
+//! calendar server:
+//! - every `.ics` file is a calendar, a [NodeKind::Label] node labelled with
+//!   its `X-WR-CALNAME`, or the name of the file, contained in the root
+//! - every `VEVENT` and `VTODO` becomes a node of the type [EVENT_MIME],
+//!   labelled with its summary, with its main properties in an `event` or
+//!   `todo` [DataBlob::Entry] at [EVENT_BLOB], the dates being `date` and
+//!   `datetime` entries as in the front matter, and its description at
+//!   [DESCRIPTION_BLOB]
+//! - every occurrence of an event is an [EdgeKind::Contains] edge from its
+//!   calendar, valid for the [Validity::Period] from `DTSTART` to `DTEND`, or
+//!   to `DUE` for a todo
+//! - the `RRULE` of a recurring event is expanded within the window of the
+//!   [IcalConfig], the `RDATE`s are added, and the `EXDATE`s removed, as well
+//!   as the instances moved to another time by a `RECURRENCE-ID`
+//! - the attendees and the organizer become the same `person` nodes as the
+//!   senders of e-mails, see [person], with an [EdgeKind::Using] edge to the
+//!   event, and the locations become [NodeKind::Label] nodes used by their
+//!   events
+//!
+//! The events are identified by their `UID`, so changing an event updates its
+//! node, and the validity of the edges whose period changed.
+//! Only the files which changed since the previous call are read again, and
+//! removed events and calendars are deleted.
+//! The source cannot write to the files.
+//!
+//! [NodeKind::Label]: crate::structs::NodeKind::Label
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Result, bail};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use chrono::{
+    Datelike, Days, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use ical::{IcalParser, property::Property};
+
+use crate::{
+    impls::timestamp_now,
+    objects::node_diff,
+    storage::{
+        dir_trait::{DirectoryEntry, Metadata, Reader},
+        threads::person,
+    },
+    structs::{
+        DataBlob, Edge, EdgeAction, EdgeID, EdgeKind, Node, NodeID, NodeUpdate, Source, SourceID,
+        Timestamp, Transaction, Validity,
+    },
+};
+
+/// The MIME type of the event and todo [Node]s.
+pub const EVENT_MIME: &str = "text/calendar";
+pub const EVENT_BLOB: u32 = 0;
+pub const DESCRIPTION_BLOB: u32 = 1;
+
+const DOMAIN: &str = "SourceIcal";
+const NANOS_PER_SECOND: Timestamp = 1_000_000_000;
+const NANOS_PER_DAY: Timestamp = 86_400 * NANOS_PER_SECOND;
+/// Stops expanding a rule after this many periods, e.g., for a `SECONDLY`
+/// rule starting long before the window.
+const MAX_PERIODS: u32 = 1_000_000;
+
+/// Which occurrences of the recurring events are created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IcalConfig {
+    /// The start of the window, in nanoseconds since the epoch.
+    /// The first occurrence of an event is always created, the following
+    /// ones only if they start within the window.
+    pub from: Timestamp,
+    pub to: Timestamp,
+    /// The maximum number of occurrences of one event.
+    pub max_occurrences: usize,
+}
+
+impl IcalConfig {
+    /// The window starts `past` days before now, and ends `future` days
+    /// after now.
+    pub fn around_now(past: u32, future: u32) -> Self {
+        let now = timestamp_now();
+        Self {
+            from: now - past as Timestamp * NANOS_PER_DAY,
+            to: now + future as Timestamp * NANOS_PER_DAY,
+            max_occurrences: 1000,
+        }
+    }
+}
+
+impl Default for IcalConfig {
+    /// The window goes from a year before now to a year after now.
+    fn default() -> Self {
+        Self::around_now(365, 365)
+    }
+}
+
+/// The nodes and edges of the calendars.
+#[derive(Debug, Clone, Default)]
+struct Graph {
+    nodes: HashMap<NodeID, Node>,
+    edges: HashMap<EdgeID, Edge>,
+}
+
+impl Graph {
+    /// Adds the nodes and edges of `other` which are not yet present.
+    fn merge(&mut self, other: &Graph) {
+        for (id, node) in &other.nodes {
+            self.nodes.entry(id.clone()).or_insert_with(|| node.clone());
+        }
+        for (id, edge) in &other.edges {
+            self.edges.entry(id.clone()).or_insert_with(|| edge.clone());
+        }
+    }
+
+    /// Returns the transactions going from this graph to `new`.
+    fn diff(&self, new: &Graph) -> Result<Vec<Transaction>> {
+        let mut txs = vec![];
+        for id in sorted(new.nodes.keys()) {
+            let node = &new.nodes[id];
+            match self.nodes.get(id) {
+                None => txs.push(Transaction::create_node(node.clone())),
+                Some(old) => {
+                    let updates = node_diff(old, node)?;
+                    if !updates.is_empty() {
+                        txs.push(Transaction::update_node(id.clone(), updates));
+                    }
+                }
+            }
+        }
+        for id in sorted(new.edges.keys()) {
+            let edge = &new.edges[id];
+            match self.edges.get(id) {
+                None => txs.push(Transaction::create_edge(edge.clone())),
+                Some(old) if old.validity != edge.validity => txs.push(Transaction::update_edge(
+                    id.clone(),
+                    vec![EdgeAction::Validity(edge.validity.clone())],
+                )),
+                Some(_) => {}
+            }
+        }
+        for id in sorted(self.edges.keys().filter(|id| !new.edges.contains_key(id))) {
+            txs.push(Transaction::update_edge(
+                id.clone(),
+                vec![EdgeAction::Delete],
+            ));
+        }
+        for id in sorted(self.nodes.keys().filter(|id| !new.nodes.contains_key(id))) {
+            txs.push(Transaction::update_node(
+                id.clone(),
+                vec![NodeUpdate::Delete],
+            ));
+        }
+        Ok(txs)
+    }
+
+    fn add_edge(&mut self, kind: EdgeKind, validity: Validity, parts: &[&[u8]]) {
+        let id = EdgeID::hash_domain_parts(DOMAIN, parts);
+        let edge = Edge {
+            id: id.clone(),
+            kind,
+            validity,
+            history: vec![],
+        };
+        self.edges.insert(id, edge);
+    }
+}
+
+#[derive(Debug)]
+pub struct SourceIcal<R>
+where
+    R: Reader + std::fmt::Debug + Sync + Send,
+{
+    reader: R,
+    id: SourceID,
+    config: IcalConfig,
+    /// The [Metadata] of the files when they were read, with their nodes and
+    /// edges, by their path.
+    files: HashMap<String, (Metadata, Graph)>,
+    /// The nodes and edges as they are in the [crate::worldview::WorldView].
+    applied: Graph,
+}
+
+#[async_trait]
+impl<R: Reader + std::fmt::Debug + Sync + Send> Source for SourceIcal<R> {
+    /// The first call returns all calendars and events, the following calls
+    /// only the changes.
+    async fn get_updates(&mut self) -> Result<Vec<Transaction>> {
+        let mut paths = vec![];
+        self.find(vec![], &mut paths).await?;
+        let names = paths.iter().map(|path| path.join("/")).collect::<Vec<_>>();
+        self.files.retain(|name, _| names.contains(name));
+        for (name, path) in names.into_iter().zip(paths) {
+            let path = path.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+            let meta = self.reader.metadata(&path).await?;
+            if self.files.get(&name).is_some_and(|(read, _)| read == &meta) {
+                continue;
+            }
+            let content = self.reader.read_bytes(&path).await?;
+            let graph = match self.read_file(&name, &content) {
+                Ok(graph) => graph,
+                Err(e) => {
+                    log::warn!("Couldn't read the calendar {name}: {e}");
+                    self.files
+                        .remove(&name)
+                        .map(|(_, graph)| graph)
+                        .unwrap_or_default()
+                }
+            };
+            self.files.insert(name, (meta, graph));
+        }
+
+        let mut graph = Graph::default();
+        let mut root = Node::label(&self.reader.location());
+        root.id = self.id.root_node();
+        graph.nodes.insert(root.id.clone(), root);
+        for name in sorted(self.files.keys()) {
+            graph.merge(&self.files[name].1);
+        }
+        let txs = self.applied.diff(&graph)?;
+        self.applied = graph;
+        Ok(txs)
+    }
+
+    async fn add_tx(&mut self, _txs: Vec<Transaction>) -> Result<()> {
+        bail!("Writing to iCalendar files is not supported")
+    }
+
+    /// Returns the unique ID of this source, derived from the location of the
+    /// directory.
+    fn get_id(&self) -> SourceID {
+        self.id.clone()
+    }
+}
+
+impl<R: Reader + std::fmt::Debug + Sync + Send> SourceIcal<R> {
+    pub fn new(reader: R, config: IcalConfig) -> Self {
+        let id = SourceID::hash_domain_parts(DOMAIN, &[reader.location().as_bytes()]);
+        Self {
+            reader,
+            id,
+            config,
+            files: HashMap::new(),
+            applied: Graph::default(),
+        }
+    }
+
+    /// Returns the [NodeID] of the calendar in the file at `path`.
+    pub fn calendar_id(&self, path: &str) -> NodeID {
+        NodeID::hash_domain_parts(DOMAIN, &[self.id.as_ref(), b"calendar", path.as_bytes()])
+    }
+
+    /// Returns the [NodeID] of the event or todo with this `UID`.
+    pub fn event_id(&self, uid: &str) -> NodeID {
+        NodeID::hash_domain_parts(DOMAIN, &[self.id.as_ref(), b"event", uid.as_bytes()])
+    }
+
+    /// Returns the [NodeID] of the location.
+    pub fn location_id(&self, location: &str) -> NodeID {
+        NodeID::hash_domain_parts(
+            DOMAIN,
+            &[self.id.as_ref(), b"location", location.as_bytes()],
+        )
+    }
+
+    /// Collects the paths of the `.ics` files.
+    #[async_recursion]
+    async fn find(&self, path: Vec<String>, files: &mut Vec<Vec<String>>) -> Result<()> {
+        let dir = path.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+        for entry in self.reader.read_directory(&dir).await? {
+            let (DirectoryEntry::Directory(name) | DirectoryEntry::File(name)) = &entry;
+            if name.starts_with('.') {
+                continue;
+            }
+            let mut entry_path = path.clone();
+            entry_path.push(name.clone());
+            match entry {
+                DirectoryEntry::Directory(_) => self.find(entry_path, files).await?,
+                DirectoryEntry::File(name) if name.to_lowercase().ends_with(".ics") => {
+                    files.push(entry_path)
+                }
+                DirectoryEntry::File(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the nodes and edges of the calendar in the file at `path`.
+    fn read_file(&self, path: &str, content: &[u8]) -> Result<Graph> {
+        let calendars = IcalParser::new(content).collect::<Result<Vec<_>, _>>()?;
+        let mut graph = Graph::default();
+        let file = path.rsplit('/').next().unwrap_or(path);
+        let label = calendars
+            .iter()
+            .find_map(|calendar| text(&calendar.properties, "X-WR-CALNAME"))
+            .unwrap_or_else(|| file.strip_suffix(".ics").unwrap_or(file).to_string());
+        let mut node = Node::label(&label);
+        node.id = self.calendar_id(path);
+        let calendar = node.id.clone();
+        graph.nodes.insert(calendar.clone(), node);
+        let root = self.id.root_node();
+        graph.add_edge(
+            EdgeKind::Contains {
+                container: root.clone(),
+                object: calendar.clone(),
+            },
+            Validity::From(0),
+            &[root.as_ref(), calendar.as_ref()],
+        );
+
+        let components = calendars.iter().flat_map(|calendar| {
+            let events = calendar.events.iter().map(|e| ("event", &e.properties));
+            events.chain(calendar.todos.iter().map(|t| ("todo", &t.properties)))
+        });
+        let mut events = vec![];
+        let mut moved: HashMap<String, Vec<&[Property]>> = HashMap::new();
+        for (index, (kind, props)) in components.enumerate() {
+            let uid = text(props, "UID").unwrap_or_else(|| format!("{path}#{index}"));
+            match property(props, "RECURRENCE-ID") {
+                Some(_) => moved.entry(uid).or_default().push(props),
+                None => events.push((kind, uid, props)),
+            }
+        }
+        for (kind, uid, props) in events {
+            let moved = moved.get(&uid).map(|m| m.as_slice()).unwrap_or_default();
+            self.add_event(&mut graph, &calendar, kind, &uid, props, moved);
+        }
+        Ok(graph)
+    }
+
+    /// Adds the node of the event, the edges of its occurrences, and its
+    /// attendees and location.
+    fn add_event(
+        &self,
+        graph: &mut Graph,
+        calendar: &NodeID,
+        kind: &str,
+        uid: &str,
+        props: &[Property],
+        moved: &[&[Property]],
+    ) {
+        let event = self.event_id(uid);
+        let summary = text(props, "SUMMARY").unwrap_or_else(|| uid.to_string());
+        let mut node = Node::mime(EVENT_MIME.into(), summary);
+        node.id = event.clone();
+        let mut fields = HashMap::from([("uid".to_string(), DataBlob::Text(uid.into()))]);
+        for (field, name) in [
+            ("summary", "SUMMARY"),
+            ("status", "STATUS"),
+            ("location", "LOCATION"),
+            ("rrule", "RRULE"),
+        ] {
+            if let Some(value) = text(props, name) {
+                fields.insert(field.into(), DataBlob::Text(value));
+            }
+        }
+        for (field, name) in [
+            ("start", "DTSTART"),
+            ("end", "DTEND"),
+            ("due", "DUE"),
+            ("completed", "COMPLETED"),
+        ] {
+            if let Some(date) = time(props, name).and_then(|time| time.blob()) {
+                fields.insert(field.into(), date);
+            }
+        }
+        node.data_blob = HashMap::from([(EVENT_BLOB, DataBlob::Entry(kind.into(), fields))]);
+        if let Some(description) = text(props, "DESCRIPTION") {
+            node.data_blob
+                .insert(DESCRIPTION_BLOB, DataBlob::Text(description));
+        }
+        graph.nodes.insert(event.clone(), node);
+
+        for (start, validity) in self.occurrences(kind, props, moved) {
+            let kind = EdgeKind::Contains {
+                container: calendar.clone(),
+                object: event.clone(),
+            };
+            let start = start.to_be_bytes();
+            graph.add_edge(kind, validity, &[calendar.as_ref(), event.as_ref(), &start]);
+        }
+
+        let (_, validity) = span(kind, props);
+        let people = props.iter().filter(|p| {
+            p.name.eq_ignore_ascii_case("ATTENDEE") || p.name.eq_ignore_ascii_case("ORGANIZER")
+        });
+        for prop in people {
+            let Some(value) = &prop.value else {
+                continue;
+            };
+            let address = match value.get(..7) {
+                Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &value[7..],
+                _ => value,
+            };
+            let node = person(DOMAIN, &self.id, param(prop, "CN"), address);
+            let kind = EdgeKind::Using {
+                client: node.id.clone(),
+                object: event.clone(),
+            };
+            graph.add_edge(kind, validity.clone(), &[node.id.as_ref(), event.as_ref()]);
+            graph.nodes.entry(node.id.clone()).or_insert(node);
+        }
+        if let Some(location) = text(props, "LOCATION") {
+            let mut node = Node::label(&location);
+            node.id = self.location_id(&location);
+            let kind = EdgeKind::Using {
+                client: event.clone(),
+                object: node.id.clone(),
+            };
+            graph.add_edge(kind, validity, &[event.as_ref(), node.id.as_ref()]);
+            graph.nodes.insert(node.id.clone(), node);
+        }
+    }
+
+    /// Returns the start of the occurrences of the event, with their
+    /// validity.
+    fn occurrences(
+        &self,
+        kind: &str,
+        props: &[Property],
+        moved: &[&[Property]],
+    ) -> Vec<(Timestamp, Validity)> {
+        let Some(start) = time(props, "DTSTART") else {
+            return vec![span(kind, props)];
+        };
+        let length = length(kind, props, &start);
+        let excluded = times(props, "EXDATE")
+            .into_iter()
+            .chain(moved.iter().filter_map(|m| time(m, "RECURRENCE-ID")))
+            .filter_map(|time| time.timestamp())
+            .collect::<HashSet<_>>();
+        let mut starts = match text(props, "RRULE").map(|rule| Rule::parse(&rule)) {
+            None => vec![start],
+            Some(Ok(rule)) => rule.expand(start, &self.config),
+            Some(Err(e)) => {
+                log::warn!("Only the first occurrence of an event is used: {e}");
+                vec![start]
+            }
+        };
+        starts.extend(times(props, "RDATE"));
+        let mut occurrences = starts
+            .into_iter()
+            .filter_map(|time| time.timestamp())
+            .filter(|start| !excluded.contains(start))
+            .map(|start| match length {
+                Some(length) => (start, Validity::Period(start, start.saturating_add(length))),
+                None => (start, Validity::From(start)),
+            })
+            .collect::<Vec<_>>();
+        occurrences.extend(moved.iter().map(|m| span(kind, m)));
+        occurrences.sort_by_key(|(start, _)| *start);
+        occurrences.dedup_by_key(|(start, _)| *start);
+        occurrences
+    }
+}
+
+/// A `DATE` or `DATE-TIME` value, with the time zone it is in, or [None] for
+/// UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Time {
+    local: NaiveDateTime,
+    zone: Option<Tz>,
+    date: bool,
+}
+
+impl Time {
+    /// Parses a date, or a date-time in UTC if it ends with a `Z`, else in
+    /// the time zone `tzid`.
+    /// Unknown time zones and floating times are taken as UTC.
+    fn parse(value: &str, tzid: Option<&str>) -> Option<Self> {
+        if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+            return Some(Self {
+                local: date.and_time(NaiveTime::MIN),
+                zone: None,
+                date: true,
+            });
+        }
+        let (value, utc) = match value.strip_suffix('Z') {
+            Some(value) => (value, true),
+            None => (value, false),
+        };
+        let local = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+        let zone = match tzid.filter(|_| !utc) {
+            Some(tzid) => {
+                let zone = tzid.parse::<Tz>().ok();
+                if zone.is_none() {
+                    log::warn!("Unknown time zone {tzid}, using UTC");
+                }
+                zone
+            }
+            None => None,
+        };
+        Some(Self {
+            local,
+            zone,
+            date: false,
+        })
+    }
+
+    /// Returns the time in nanoseconds since the epoch.
+    /// A local time skipped by a change to daylight saving time is moved an
+    /// hour later.
+    fn timestamp(&self) -> Option<Timestamp> {
+        let time = match self.zone {
+            None => self.local.and_utc(),
+            Some(zone) => zone
+                .from_local_datetime(&self.local)
+                .earliest()
+                .or_else(|| {
+                    let later = self.local + TimeDelta::hours(1);
+                    zone.from_local_datetime(&later).earliest()
+                })?
+                .with_timezone(&Utc),
+        };
+        Some(time.timestamp_nanos_opt()? as Timestamp)
+    }
+
+    fn with_local(&self, local: NaiveDateTime) -> Self {
+        Self { local, ..*self }
+    }
+
+    /// Returns the `date` or `datetime` entry of the front matter.
+    fn blob(&self) -> Option<DataBlob> {
+        let name = if self.date { "date" } else { "datetime" };
+        Some(DataBlob::Entry(
+            name.into(),
+            HashMap::from([("timestamp".into(), DataBlob::Int(self.timestamp()?.into()))]),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frequency {
+    Secondly,
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// The supported parts of an `RRULE`: `BYDAY` only without an ordinal, and
+/// only for the `DAILY` and `WEEKLY` rules.
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<usize>,
+    until: Option<Timestamp>,
+    by_day: Vec<Weekday>,
+    week_start: Weekday,
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self> {
+        let mut frequency = None;
+        let mut parsed = Rule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            week_start: Weekday::Mon,
+        };
+        for part in rule.split(';').filter(|part| !part.is_empty()) {
+            let Some((key, value)) = part.split_once('=') else {
+                bail!("Invalid part {part} of the rule {rule}");
+            };
+            match key.to_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match value.to_uppercase().as_str() {
+                        "SECONDLY" => Frequency::Secondly,
+                        "MINUTELY" => Frequency::Minutely,
+                        "HOURLY" => Frequency::Hourly,
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => bail!("Unknown frequency {value}"),
+                    })
+                }
+                "INTERVAL" => parsed.interval = value.parse()?,
+                "COUNT" => parsed.count = Some(value.parse()?),
+                "UNTIL" => match Time::parse(value, None).and_then(|time| time.timestamp()) {
+                    Some(until) => parsed.until = Some(until),
+                    None => bail!("Invalid date {value}"),
+                },
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        parsed.by_day.push(weekday(day)?);
+                    }
+                }
+                "WKST" => parsed.week_start = weekday(value)?,
+                _ => bail!("Unsupported part {part} of the rule {rule}"),
+            }
+        }
+        let Some(frequency) = frequency else {
+            bail!("The rule {rule} has no frequency");
+        };
+        if parsed.interval == 0 {
+            bail!("The interval of the rule {rule} is 0");
+        }
+        if !parsed.by_day.is_empty() && ![Frequency::Daily, Frequency::Weekly].contains(&frequency)
+        {
+            bail!("Unsupported BYDAY in the rule {rule}");
+        }
+        parsed.frequency = frequency;
+        Ok(parsed)
+    }
+
+    /// Returns the start of the occurrences, beginning with `start`, which
+    /// start within the window of the config.
+    fn expand(&self, start: Time, config: &IcalConfig) -> Vec<Time> {
+        let mut starts = vec![start];
+        let mut count = 1;
+        let end = config.to.min(self.until.unwrap_or(Timestamp::MAX));
+        'periods: for period in 0..MAX_PERIODS {
+            let Some((base, candidates)) = self.period(start.local, period) else {
+                return starts;
+            };
+            if start
+                .with_local(base)
+                .timestamp()
+                .is_none_or(|base| base > end)
+            {
+                return starts;
+            }
+            for local in candidates {
+                if local <= start.local {
+                    continue;
+                }
+                let time = start.with_local(local);
+                let Some(timestamp) = time.timestamp() else {
+                    continue;
+                };
+                if timestamp > end || self.count.is_some_and(|max| count >= max) {
+                    return starts;
+                }
+                count += 1;
+                if timestamp >= config.from {
+                    starts.push(time);
+                    if starts.len() >= config.max_occurrences {
+                        break 'periods;
+                    }
+                }
+            }
+        }
+        log::warn!(
+            "Stopped expanding the rule {self:?} after {} occurrences",
+            starts.len()
+        );
+        starts
+    }
+
+    /// Returns the beginning of the `index`th period of the rule starting at
+    /// `start`, and the times of the occurrences in it.
+    /// Returns `None` if the period is beyond the supported dates.
+    fn period(
+        &self,
+        start: NaiveDateTime,
+        index: u32,
+    ) -> Option<(NaiveDateTime, Vec<NaiveDateTime>)> {
+        let steps = index as u64 * self.interval as u64;
+        let delta = |unit: fn(i64) -> Option<TimeDelta>| unit(i64::try_from(steps).ok()?);
+        let time = start.time();
+        let base = match self.frequency {
+            Frequency::Secondly => start.checked_add_signed(delta(TimeDelta::try_seconds)?)?,
+            Frequency::Minutely => start.checked_add_signed(delta(TimeDelta::try_minutes)?)?,
+            Frequency::Hourly => start.checked_add_signed(delta(TimeDelta::try_hours)?)?,
+            Frequency::Daily => start.checked_add_days(Days::new(steps))?,
+            Frequency::Weekly => {
+                let offset = start.weekday().days_since(self.week_start);
+                start
+                    .checked_sub_days(Days::new(offset as u64))?
+                    .checked_add_days(Days::new(steps.checked_mul(7)?))?
+            }
+            Frequency::Monthly | Frequency::Yearly => {
+                let months = match self.frequency {
+                    Frequency::Yearly => steps.checked_mul(12)?,
+                    _ => steps,
+                };
+                let first = start.date().with_day(1).unwrap_or(start.date());
+                let month = first.checked_add_months(Months::new(u32::try_from(months).ok()?))?;
+                let day = month.with_day(start.day());
+                return Some((
+                    month.and_time(time),
+                    day.map(|day| day.and_time(time)).into_iter().collect(),
+                ));
+            }
+        };
+        let candidates = match (self.frequency, self.by_day.is_empty()) {
+            (_, true) => vec![base],
+            (Frequency::Weekly, false) => {
+                let mut days = self
+                    .by_day
+                    .iter()
+                    .map(|day| day.days_since(self.week_start))
+                    .collect::<Vec<_>>();
+                days.sort();
+                days.dedup();
+                days.into_iter()
+                    .filter_map(|day| base.checked_add_days(Days::new(day as u64)))
+                    .collect()
+            }
+            (_, false) => vec![base]
+                .into_iter()
+                .filter(|time| self.by_day.contains(&time.weekday()))
+                .collect(),
+        };
+        Some((base, candidates))
+    }
+}
+
+/// Returns the day of a `BYDAY` or `WKST` part.
+fn weekday(day: &str) -> Result<Weekday> {
+    Ok(match day.to_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => bail!("Unsupported day {day}"),
+    })
+}
+
+fn property<'a>(props: &'a [Property], name: &str) -> Option<&'a Property> {
+    props.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+fn param<'a>(prop: &'a Property, name: &str) -> Option<&'a str> {
+    let params = prop.params.as_ref()?;
+    let (_, values) = params.iter().find(|(key, _)| key == name)?;
+    values.first().map(|value| value.as_str())
+}
+
+/// Returns the unescaped text of the property.
+fn text(props: &[Property], name: &str) -> Option<String> {
+    let value = property(props, name)?.value.as_deref()?;
+    let mut text = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n' | 'N') => text.push('\n'),
+                Some(c) => text.push(c),
+                None => text.push('\\'),
+            },
+            c => text.push(c),
+        }
+    }
+    Some(text)
+}
+
+fn time(props: &[Property], name: &str) -> Option<Time> {
+    let prop = property(props, name)?;
+    Time::parse(prop.value.as_deref()?, param(prop, "TZID"))
+}
+
+/// Returns the times of all the properties with this name, which can each
+/// hold a list of times.
+fn times(props: &[Property], name: &str) -> Vec<Time> {
+    props
+        .iter()
+        .filter(|prop| prop.name.eq_ignore_ascii_case(name))
+        .flat_map(|prop| {
+            let values = prop.value.iter().flat_map(|value| value.split(','));
+            values.filter_map(|value| Time::parse(value, param(prop, "TZID")))
+        })
+        .collect()
+}
+
+/// Returns how long the event starting at `start` lasts, or [None] for a
+/// todo without a due date.
+fn length(kind: &str, props: &[Property], start: &Time) -> Option<Timestamp> {
+    let end = time(props, "DTEND").or_else(|| time(props, "DUE"));
+    if let Some(end) = end.and_then(|end| end.timestamp()) {
+        return Some(end - start.timestamp()?);
+    }
+    if let Some(duration) = text(props, "DURATION").and_then(|d| duration(&d)) {
+        return Some(duration);
+    }
+    match (kind, start.date) {
+        ("todo", _) => None,
+        (_, true) => Some(NANOS_PER_DAY),
+        (_, false) => Some(0),
+    }
+}
+
+/// Returns the start of the event, without its recurrences, and its
+/// validity.
+fn span(kind: &str, props: &[Property]) -> (Timestamp, Validity) {
+    let start = time(props, "DTSTART");
+    match start.and_then(|start| Some((start.timestamp()?, length(kind, props, &start)))) {
+        Some((start, Some(length))) => {
+            (start, Validity::Period(start, start.saturating_add(length)))
+        }
+        Some((start, None)) => (start, Validity::From(start)),
+        None => match time(props, "DUE").and_then(|due| due.timestamp()) {
+            Some(due) => (due, Validity::To(due)),
+            None => (0, Validity::From(0)),
+        },
+    }
+}
+
+/// Parses a `DURATION` like `P1W` or `-PT1H30M`, in nanoseconds.
+fn duration(value: &str) -> Option<Timestamp> {
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(value) => (-1, value),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let mut total = 0;
+    let mut number = String::new();
+    for c in value.strip_prefix('P')?.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => {}
+            unit => {
+                let unit = match unit {
+                    'W' => 7 * NANOS_PER_DAY,
+                    'D' => NANOS_PER_DAY,
+                    'H' => 3_600 * NANOS_PER_SECOND,
+                    'M' => 60 * NANOS_PER_SECOND,
+                    'S' => NANOS_PER_SECOND,
+                    _ => return None,
+                };
+                let part = number.parse::<Timestamp>().ok()?.checked_mul(unit)?;
+                total = part.checked_add(total)?;
+                number.clear();
+            }
+        }
+    }
+    number.is_empty().then_some(sign * total)
+}
+
+fn sorted<'a, T: ToString + 'a>(ids: impl Iterator<Item = &'a T>) -> Vec<&'a T> {
+    let mut ids = ids.collect::<Vec<_>>();
+    ids.sort_by_key(|id| id.to_string());
+    ids
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+
+    use crate::{
+        storage::dir_trait::{EmulatedDir, Writer},
+        worldview::WorldView,
+    };
+
+    use super::*;
+
+    const TEAM: &str = "BEGIN:VCALENDAR
+VERSION:2.0
+X-WR-CALNAME:Team
+BEGIN:VEVENT
+UID:standup
+SUMMARY:Standup
+DESCRIPTION:Short\\, and\\nsweet
+DTSTART;TZID=Europe/Zurich:20250106T090000
+DTEND;TZID=Europe/Zurich:20250106T091500
+RRULE:FREQ=WEEKLY;BYDAY=MO,WE;COUNT=6
+EXDATE;TZID=Europe/Zurich:20250108T090000
+LOCATION:Room 1
+ORGANIZER;CN=Alice:mailto:alice@example.com
+ATTENDEE;CN=\"Doe, Bob\":MAILTO:bob@example.com
+END:VEVENT
+BEGIN:VEVENT
+UID:standup
+RECURRENCE-ID;TZID=Europe/Zurich:20250113T090000
+SUMMARY:Standup
+DTSTART;TZID=Europe/Zurich:20250113T100000
+DTEND;TZID=Europe/Zurich:20250113T101500
+END:VEVENT
+BEGIN:VEVENT
+UID:holiday
+SUMMARY:New year
+DTSTART;VALUE=DATE:20250101
+END:VEVENT
+BEGIN:VTODO
+UID:report
+SUMMARY:Report
+DUE;VALUE=DATE:20250131
+END:VTODO
+END:VCALENDAR
+";
+
+    fn ts(time: &str) -> Timestamp {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .timestamp_nanos_opt()
+            .unwrap() as Timestamp
+    }
+
+    fn config() -> IcalConfig {
+        IcalConfig {
+            from: ts("2025-01-01T00:00:00Z"),
+            to: ts("2025-03-01T00:00:00Z"),
+            max_occurrences: 1000,
+        }
+    }
+
+    /// Returns the validity of the occurrences of the event, sorted by their
+    /// start.
+    fn occurrences(ww: &WorldView, event: &NodeID) -> Vec<Validity> {
+        let mut validities = ww
+            .get_node(event)
+            .unwrap()
+            .edges
+            .iter()
+            .filter(|e| matches!(e.kind, EdgeKind::Contains { .. }))
+            .map(|e| e.validity.clone())
+            .collect::<Vec<_>>();
+        validities.sort_by_key(|v| match v {
+            Validity::From(t) | Validity::To(t) | Validity::Period(t, _) => *t,
+        });
+        validities
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(duration("PT15M"), Some(15 * 60 * NANOS_PER_SECOND));
+        assert_eq!(duration("P1W"), Some(7 * NANOS_PER_DAY));
+        assert_eq!(
+            duration("-P1DT2H"),
+            Some(-(NANOS_PER_DAY + 7_200 * NANOS_PER_SECOND))
+        );
+        assert_eq!(duration("P1Y"), None);
+        assert_eq!(duration("P99999999999999999999999999999999999W"), None);
+    }
+
+    #[tokio::test]
+    async fn test_ical() -> Result<()> {
+        let dir = EmulatedDir::new_from_string(&[("team.ics", TEAM), ("notes.txt", "")]);
+        let mut source = SourceIcal::new(dir, config());
+        let txs = source.get_updates().await?;
+        // The root, the calendar, 3 events, 2 people and a location, with the
+        // edge of the calendar, 5 occurrences of the standup, one of the
+        // others, and the edges of the people and the location.
+        assert_eq!(txs.len(), 8 + 1 + 5 + 2 + 3);
+        let mut ww = WorldView::new();
+        ww.process_updates(txs).await?;
+
+        let calendar = ww.get_node(&source.calendar_id("team.ics")).unwrap();
+        assert_eq!(calendar.label, "Team");
+        let standup = ww.get_node(&source.event_id("standup")).unwrap();
+        assert_eq!(standup.label, "Standup");
+        assert_eq!(
+            standup.data_blob[&DESCRIPTION_BLOB],
+            DataBlob::Text("Short, and\nsweet".into())
+        );
+        assert!(
+            matches!(&standup.data_blob[&EVENT_BLOB], DataBlob::Entry(name, fields)
+                if name == "event" && fields["rrule"] == DataBlob::Text("FREQ=WEEKLY;BYDAY=MO,WE;COUNT=6".into()))
+        );
+        let quarter = 15 * 60 * NANOS_PER_SECOND;
+        let period = |start: &str| Validity::Period(ts(start), ts(start) + quarter);
+        assert_eq!(
+            occurrences(&ww, &standup.id),
+            vec![
+                period("2025-01-06T08:00:00Z"),
+                period("2025-01-13T09:00:00Z"),
+                period("2025-01-15T08:00:00Z"),
+                period("2025-01-20T08:00:00Z"),
+                period("2025-01-22T08:00:00Z"),
+            ]
+        );
+        assert_eq!(
+            occurrences(&ww, &source.event_id("holiday")),
+            vec![Validity::Period(
+                ts("2025-01-01T00:00:00Z"),
+                ts("2025-01-02T00:00:00Z")
+            )]
+        );
+        assert_eq!(
+            occurrences(&ww, &source.event_id("report")),
+            vec![Validity::To(ts("2025-01-31T00:00:00Z"))]
+        );
+
+        let bob = person(DOMAIN, &source.id, None, "bob@example.com");
+        let bob = ww.get_node(&bob.id).unwrap();
+        assert_eq!(bob.label, "Doe, Bob");
+        assert!(bob.edges.iter().any(|e| e.nodes().contains(&&standup.id)));
+        let room = ww.get_node(&source.location_id("Room 1")).unwrap();
+        assert_eq!(room.label, "Room 1");
+        assert!(room.edges.iter().any(|e| e.nodes().contains(&&standup.id)));
+        assert!(source.get_updates().await?.is_empty());
+
+        // Longer standups only change the validity of the edges, and the
+        // event is updated.
+        let holiday = TEAM.find("BEGIN:VEVENT\nUID:holiday").unwrap();
+        let todo = TEAM.find("BEGIN:VTODO").unwrap();
+        let changed = format!("{}{}", &TEAM[..holiday], &TEAM[todo..])
+            .replacen("091500", "093000", 1)
+            .replacen("SUMMARY:Standup", "SUMMARY:Daily standup", 1);
+        source
+            .reader
+            .overwrite(&["team.ics"], changed.as_bytes())
+            .await?;
+        let txs = source.get_updates().await?;
+        // The standup is updated, as well as 4 of its occurrences and its 3
+        // other edges, and the holiday is deleted with its edge.
+        assert_eq!(txs.len(), 1 + 4 + 3 + 2);
+        ww.process_updates(txs).await?;
+        let standup = ww.get_node(&standup.id).unwrap();
+        assert_eq!(standup.label, "Daily standup");
+        let half = 2 * quarter;
+        assert_eq!(
+            occurrences(&ww, &standup.id)[..2],
+            [
+                Validity::Period(
+                    ts("2025-01-06T08:00:00Z"),
+                    ts("2025-01-06T08:00:00Z") + half
+                ),
+                period("2025-01-13T09:00:00Z"),
+            ]
+        );
+        assert!(ww.get_node(&source.event_id("holiday")).is_none());
+
+        source.reader.remove(&["team.ics"]).await?;
+        ww.process_updates(source.get_updates().await?).await?;
+        assert!(ww.get_node(&calendar.id).is_none());
+        assert!(ww.get_node(&standup.id).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_window() -> Result<()> {
+        let ics = "BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:backup
+DTSTART:20200101T120000Z
+RRULE:FREQ=DAILY;INTERVAL=10
+END:VEVENT
+BEGIN:VEVENT
+UID:review
+DTSTART:20250115T120000Z
+RRULE:FREQ=MONTHLY;UNTIL=20250501T000000Z
+RDATE:20250120T120000Z
+END:VEVENT
+BEGIN:VEVENT
+UID:unsupported
+DTSTART:20250115T120000Z
+RRULE:FREQ=MONTHLY;BYSETPOS=-1
+END:VEVENT
+BEGIN:VEVENT
+UID:centuries
+DTSTART:20250115T120000Z
+RRULE:FREQ=YEARLY;INTERVAL=1000000
+END:VEVENT
+BEGIN:VEVENT
+UID:hours
+DTSTART:20250115T120000Z
+DURATION:P99999999999999999999999999999999999W
+RRULE:FREQ=HOURLY;INTERVAL=4000000000
+END:VEVENT
+END:VCALENDAR
+";
+        let dir = EmulatedDir::new_from_string(&[("work/plan.ics", ics)]);
+        let mut source = SourceIcal::new(dir, config());
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        let calendar = ww.get_node(&source.calendar_id("work/plan.ics")).unwrap();
+        assert_eq!(calendar.label, "plan");
+
+        // The first backup, and those of the 4th of January to the 28th of
+        // February.
+        let backups = occurrences(&ww, &source.event_id("backup"));
+        assert_eq!(backups.len(), 1 + 6);
+        assert_eq!(
+            backups[0],
+            Validity::Period(ts("2020-01-01T12:00:00Z"), ts("2020-01-01T12:00:00Z"))
+        );
+        assert_eq!(
+            backups[1],
+            Validity::Period(ts("2025-01-04T12:00:00Z"), ts("2025-01-04T12:00:00Z"))
+        );
+        // The reviews of January, with the additional one, and February.
+        assert_eq!(occurrences(&ww, &source.event_id("review")).len(), 3);
+        assert_eq!(occurrences(&ww, &source.event_id("unsupported")).len(), 1);
+        // Intervals and durations beyond the supported dates stop the
+        // expansion.
+        assert_eq!(occurrences(&ww, &source.event_id("centuries")).len(), 1);
+        assert_eq!(
+            occurrences(&ww, &source.event_id("hours")),
+            vec![Validity::Period(
+                ts("2025-01-15T12:00:00Z"),
+                ts("2025-01-15T12:00:00Z")
+            )]
+        );
+        Ok(())
+    }
+}
@@ -1,29 +1,602 @@
-use anyhow::Result;
+//! Reads the e-mails of an IMAP account:
+//! - every folder becomes a [NodeKind::Label] node, contained in its parent
+//!   folder, or in the root of the source
+//! - every e-mail becomes a node in its folder, see [crate::storage::mail]
+//!
+//! The UIDVALIDITY and UIDNEXT of each folder are kept, so
+//! [Source::get_updates] only fetches the new e-mails.
+//! If the UIDVALIDITY of a folder changes, all its e-mails are fetched again.
+//! E-mails removed from the server are not removed, and the source cannot
+//! write to the server.
+//!
+//! [NodeKind::Label]: crate::structs::NodeKind::Label
 
-use crate::structs::{Source, SourceID, Transaction};
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Result, bail};
+use imap_proto::{AttributeValue, MailboxDatum, NameAttribute, Response, ResponseCode, Status};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{ClientConfig, RootCertStore, crypto::ring, pki_types::ServerName},
+};
+
+use crate::{
+    storage::mail::Mailbox,
+    structs::{Source, SourceID, Transaction},
+};
+
+/// How to connect to the IMAP server.
+#[derive(Clone, PartialEq)]
+pub struct ImapConfig {
+    pub host: String,
+    pub port: u16,
+    /// Connects with TLS, else in plain text.
+    pub tls: bool,
+    pub user: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for ImapConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImapConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ImapConfig {
+    /// Connects with TLS on port 993.
+    pub fn new(host: &str, user: &str, password: &str) -> Self {
+        Self {
+            host: host.into(),
+            port: 993,
+            tls: true,
+            user: user.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// The UIDs of a folder.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Uids {
+    validity: u32,
+    /// The UID of the next e-mail to fetch.
+    next: u32,
+}
 
 #[derive(Debug)]
-pub struct SourceIMAP {}
+pub struct SourceIMAP {
+    config: ImapConfig,
+    id: SourceID,
+    mailbox: Mailbox,
+    uids: HashMap<String, Uids>,
+}
 
 #[async_trait::async_trait]
 impl Source for SourceIMAP {
+    /// The first call returns all folders and e-mails, the following calls
+    /// only the new ones.
+    /// If fetching fails, the state is kept as it was, so the e-mails of the
+    /// folders fetched before the error are returned by the next call.
     async fn get_updates(&mut self) -> Result<Vec<Transaction>> {
-        todo!()
+        let (mailbox, uids) = (self.mailbox.clone(), self.uids.clone());
+        let res = self.fetch().await;
+        if res.is_err() {
+            (self.mailbox, self.uids) = (mailbox, uids);
+        }
+        res
     }
 
-    /// Adds one or more [Transaction]s to this source.
     async fn add_tx(&mut self, _txs: Vec<Transaction>) -> Result<()> {
-        todo!()
+        bail!("Writing to IMAP is not supported")
     }
 
-    /// Returns the unique ID of this source.
+    /// Returns the unique ID of this source, derived from the server and the
+    /// user.
     fn get_id(&self) -> SourceID {
-        todo!()
+        self.id.clone()
     }
 }
 
 impl SourceIMAP {
-    pub fn new() -> Self {
-        todo!()
+    pub fn new(config: ImapConfig) -> Self {
+        let id = SourceID::hash_domain_parts(
+            "SourceIMAP",
+            &[
+                config.host.as_bytes(),
+                &config.port.to_be_bytes(),
+                config.user.as_bytes(),
+            ],
+        );
+        Self {
+            config,
+            mailbox: Mailbox::new("SourceIMAP", id.clone()),
+            id,
+            uids: HashMap::new(),
+        }
+    }
+
+    /// Fetches the folders and their new e-mails.
+    async fn fetch(&mut self) -> Result<Vec<Transaction>> {
+        let mut client = Client::connect(&self.config).await?;
+        client
+            .command(&format!(
+                "LOGIN {} {}",
+                quote(&self.config.user),
+                quote(&self.config.password)
+            ))
+            .await?;
+        let mut txs = self
+            .mailbox
+            .root(&format!("{}@{}", self.config.user, self.config.host));
+
+        let list = client.list().await?;
+        let folders = list
+            .iter()
+            .map(|f| (f.name.clone(), f.delimiter.clone()))
+            .collect::<Vec<_>>();
+        txs.extend(self.mailbox.set_folders(&folders));
+        self.uids
+            .retain(|name, _| list.iter().any(|f| &f.name == name));
+        for listed in list.iter().filter(|f| f.selectable) {
+            txs.extend(self.fetch_folder(&mut client, &listed.name).await?);
+        }
+        client.command("LOGOUT").await?;
+        txs.extend(self.mailbox.update_threads());
+        Ok(txs)
+    }
+
+    /// Fetches the new e-mails of the folder.
+    /// The e-mails are stored with the key `UIDVALIDITY/UID`.
+    async fn fetch_folder(&mut self, client: &mut Client, name: &str) -> Result<Vec<Transaction>> {
+        let (validity, next) = client.select(name).await?;
+        let uids = self.uids.entry(name.into()).or_insert(Uids {
+            validity: 0,
+            next: 1,
+        });
+        let mut txs = vec![];
+        if uids.validity != validity {
+            if uids.validity != 0 {
+                log::info!("UIDVALIDITY of {name} changed, fetching all e-mails");
+            }
+            txs.extend(self.mailbox.clear_folder(name));
+            *uids = Uids { validity, next: 1 };
+        }
+        if next.is_some_and(|next| next <= uids.next) {
+            return Ok(txs);
+        }
+
+        let from = uids.next;
+        for (uid, raw) in client.fetch(from).await? {
+            // `from:*` always returns the last e-mail, even if it is older.
+            if uid < from {
+                continue;
+            }
+            let key = format!("{validity}/{uid}");
+            txs.extend(self.mailbox.add_message(name, &key, &raw));
+            uids.next = uids.next.max(uid + 1);
+        }
+        Ok(txs)
+    }
+}
+
+/// Quotes a string for an IMAP command.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A folder returned by the `LIST` command.
+#[derive(Debug)]
+struct Listed {
+    name: String,
+    delimiter: Option<String>,
+    selectable: bool,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Sends the commands needed by [SourceIMAP], and parses the responses with
+/// [imap_proto].
+struct Client {
+    stream: BufReader<Box<dyn Stream>>,
+    tag: u32,
+}
+
+impl Client {
+    async fn connect(config: &ImapConfig) -> Result<Self> {
+        let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        let stream: Box<dyn Stream> = match config.tls {
+            true => {
+                let mut roots = RootCertStore::empty();
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                let tls = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_safe_default_protocol_versions()?
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let name = ServerName::try_from(config.host.clone())?;
+                Box::new(TlsConnector::from(Arc::new(tls)).connect(name, tcp).await?)
+            }
+            false => Box::new(tcp),
+        };
+        let mut client = Self {
+            stream: BufReader::new(stream),
+            tag: 0,
+        };
+        let greeting = client.read_response().await?;
+        if let Ok((_, Response::Data { status, .. })) = Response::from_bytes(&greeting)
+            && status == Status::Bye
+        {
+            bail!("{} refused the connection", config.host);
+        }
+        Ok(client)
+    }
+
+    /// Sends the command, and returns the untagged responses once it
+    /// succeeded.
+    async fn command(&mut self, command: &str) -> Result<Vec<Vec<u8>>> {
+        self.tag += 1;
+        let tag = format!("A{}", self.tag);
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{tag} {command}\r\n").as_bytes())
+            .await?;
+        stream.flush().await?;
+        // The arguments are not logged, as they might contain the password.
+        let verb = command.split(' ').next().unwrap_or_default();
+        let mut responses = vec![];
+        loop {
+            let response = self.read_response().await?;
+            match Response::from_bytes(&response) {
+                Ok((
+                    _,
+                    Response::Done {
+                        tag: done,
+                        status,
+                        information,
+                        ..
+                    },
+                )) if done.0 == tag => {
+                    if status != Status::Ok {
+                        bail!("{verb} failed: {}", information.unwrap_or_default());
+                    }
+                    return Ok(responses);
+                }
+                Ok(_) => responses.push(response),
+                Err(_) => log::warn!("Cannot parse the response to {verb}"),
+            }
+        }
+    }
+
+    /// Reads a response, including the literals it contains.
+    async fn read_response(&mut self) -> Result<Vec<u8>> {
+        let mut response = vec![];
+        loop {
+            let start = response.len();
+            if self.stream.read_until(b'\n', &mut response).await? == 0 {
+                bail!("The server closed the connection");
+            }
+            let line = &response[start..];
+            let literal = line.strip_suffix(b"}\r\n").and_then(|line| {
+                let open = line.iter().rposition(|b| *b == b'{')?;
+                std::str::from_utf8(&line[open + 1..]).ok()?.parse().ok()
+            });
+            match literal {
+                Some(len) => {
+                    let mut data = vec![0; len];
+                    self.stream.read_exact(&mut data).await?;
+                    response.extend(data);
+                }
+                None => return Ok(response),
+            }
+        }
+    }
+
+    async fn list(&mut self) -> Result<Vec<Listed>> {
+        let mut folders = vec![];
+        for response in self.command("LIST \"\" \"*\"").await? {
+            if let Ok((
+                _,
+                Response::MailboxData(MailboxDatum::List {
+                    name_attributes,
+                    delimiter,
+                    name,
+                }),
+            )) = Response::from_bytes(&response)
+            {
+                folders.push(Listed {
+                    name: name.to_string(),
+                    delimiter: delimiter.map(|d| d.to_string()),
+                    selectable: !name_attributes.contains(&NameAttribute::NoSelect),
+                });
+            }
+        }
+        Ok(folders)
+    }
+
+    /// Selects the folder, and returns its UIDVALIDITY and UIDNEXT.
+    async fn select(&mut self, name: &str) -> Result<(u32, Option<u32>)> {
+        let (mut uid_validity, mut uid_next) = (None, None);
+        for response in self.command(&format!("EXAMINE {}", quote(name))).await? {
+            match Response::from_bytes(&response) {
+                Ok((
+                    _,
+                    Response::Data {
+                        code: Some(ResponseCode::UidValidity(v)),
+                        ..
+                    },
+                )) => uid_validity = Some(v),
+                Ok((
+                    _,
+                    Response::Data {
+                        code: Some(ResponseCode::UidNext(n)),
+                        ..
+                    },
+                )) => uid_next = Some(n),
+                _ => {}
+            }
+        }
+        match uid_validity {
+            Some(uid_validity) => Ok((uid_validity, uid_next)),
+            None => bail!("{name} has no UIDVALIDITY"),
+        }
+    }
+
+    /// Returns the UID and content of the e-mails starting at `from`.
+    async fn fetch(&mut self, from: u32) -> Result<Vec<(u32, Vec<u8>)>> {
+        let mut messages = vec![];
+        let command = format!("UID FETCH {from}:* (UID BODY.PEEK[])");
+        for response in self.command(&command).await? {
+            let Ok((_, Response::Fetch(_, attributes))) = Response::from_bytes(&response) else {
+                continue;
+            };
+            let (mut uid, mut body) = (None, None);
+            for attribute in attributes {
+                match attribute {
+                    AttributeValue::Uid(u) => uid = Some(u),
+                    AttributeValue::BodySection {
+                        data: Some(data), ..
+                    } => body = Some(data.to_vec()),
+                    _ => {}
+                }
+            }
+            if let (Some(uid), Some(body)) = (uid, body) {
+                messages.push((uid, body));
+            }
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        storage::mail::{BODY_BLOB, MESSAGE_MIME},
+        structs::{DataBlob, NodeKind},
+        worldview::WorldView,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct Mailbox {
+        name: String,
+        uid_validity: u32,
+        /// The UIDs and contents of the e-mails.
+        messages: Vec<(u32, String)>,
+        /// Refuses to be selected.
+        broken: bool,
+    }
+
+    impl Mailbox {
+        fn new(name: &str, messages: &[&str]) -> Self {
+            Self {
+                name: name.into(),
+                uid_validity: 1,
+                messages: messages
+                    .iter()
+                    .enumerate()
+                    .map(|(i, m)| (i as u32 + 1, m.to_string()))
+                    .collect(),
+                broken: false,
+            }
+        }
+    }
+
+    type Mailboxes = Arc<Mutex<Vec<Mailbox>>>;
+
+    fn mail(subject: &str) -> String {
+        format!("From: alice@example.com\r\nSubject: {subject}\r\n\r\nAbout {subject}.\r\n")
+    }
+
+    /// Starts an IMAP stand-in on a local port, which supports just enough
+    /// commands for [SourceIMAP].
+    async fn serve(mailboxes: Mailboxes) -> Result<u16> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(session(stream, mailboxes.clone()));
+            }
+        });
+        Ok(port)
+    }
+
+    async fn session(stream: TcpStream, mailboxes: Mailboxes) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"* OK IMAP stand-in ready\r\n").await?;
+        let mut selected = None;
+        while let Some(line) = lines.next_line().await? {
+            let mut parts = line.splitn(3, ' ');
+            let (tag, command, args) = (
+                parts.next().unwrap_or_default(),
+                parts.next().unwrap_or_default(),
+                parts.next().unwrap_or_default(),
+            );
+            let mut out = String::new();
+            let mailboxes = mailboxes.lock().unwrap().clone();
+            let status = match command {
+                "LOGIN" if args == "\"alice\" \"secret\"" => "OK",
+                "LOGIN" => "NO",
+                "LIST" => {
+                    out.push_str("* LIST (\\Noselect) \"/\" \"Archive\"\r\n");
+                    for mailbox in &mailboxes {
+                        out.push_str(&format!("* LIST () \"/\" \"{}\"\r\n", mailbox.name));
+                    }
+                    "OK"
+                }
+                "EXAMINE" => {
+                    let name = args.trim_matches('"');
+                    match mailboxes.iter().find(|m| m.name == name && !m.broken) {
+                        Some(mailbox) => {
+                            let next = mailbox.messages.last().map(|(uid, _)| uid + 1);
+                            out.push_str(&format!(
+                                "* {} EXISTS\r\n* OK [UIDVALIDITY {}] UIDs valid\r\n\
+                                 * OK [UIDNEXT {}] Predicted next UID\r\n",
+                                mailbox.messages.len(),
+                                mailbox.uid_validity,
+                                next.unwrap_or(1)
+                            ));
+                            selected = Some(name.to_string());
+                            "OK"
+                        }
+                        None => "NO",
+                    }
+                }
+                "UID" => {
+                    let from = args
+                        .strip_prefix("FETCH ")
+                        .and_then(|a| a.split(':').next())
+                        .and_then(|from| from.parse::<u32>().ok())
+                        .unwrap_or(1);
+                    let mailbox = mailboxes
+                        .iter()
+                        .find(|m| Some(&m.name) == selected.as_ref());
+                    let messages = &mailbox.unwrap().messages;
+                    for (seq, (uid, content)) in messages.iter().enumerate() {
+                        if *uid >= from || seq + 1 == messages.len() {
+                            out.push_str(&format!(
+                                "* {} FETCH (UID {uid} BODY[] {{{}}}\r\n{content})\r\n",
+                                seq + 1,
+                                content.len()
+                            ));
+                        }
+                    }
+                    "OK"
+                }
+                "LOGOUT" => {
+                    out.push_str("* BYE Logging out\r\n");
+                    "OK"
+                }
+                _ => "BAD",
+            };
+            out.push_str(&format!("{tag} {status} {command} done\r\n"));
+            write.write_all(out.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_imap() -> Result<()> {
+        let mailboxes = Arc::new(Mutex::new(vec![
+            Mailbox::new("INBOX", &[&mail("Hello"), &mail("Lunch")]),
+            Mailbox::new("Archive/2024", &[&mail("Old")]),
+        ]));
+        let port = serve(mailboxes.clone()).await?;
+        let mut config = ImapConfig::new("127.0.0.1", "alice", "secret");
+        config.port = port;
+        config.tls = false;
+        let mut source = SourceIMAP::new(config.clone());
+        let mut ww = WorldView::new();
+        let root = ww
+            .add_source(Box::new(SourceIMAP::new(config.clone())))
+            .await?;
+        assert_eq!(root, source.get_id().root_node());
+
+        let txs = source.get_updates().await?;
+        // The root, 3 folders and 3 e-mails, with their edges, and the
+        // sender with an edge to each e-mail.
+        assert_eq!(txs.len(), 1 + 2 * 3 + 2 * 3 + 1 + 3);
+        let mut ww = WorldView::new();
+        ww.process_updates(txs).await?;
+        let archive = ww
+            .get_node(&source.mailbox.folder("Archive").unwrap())
+            .unwrap();
+        assert_eq!(archive.label, "Archive");
+        let year = ww
+            .get_node(&source.mailbox.folder("Archive/2024").unwrap())
+            .unwrap();
+        assert_eq!(year.label, "2024");
+        assert!(year.edges.iter().any(|e| e.nodes().contains(&&archive.id)));
+        assert_eq!(
+            source.uids["INBOX"],
+            Uids {
+                validity: 1,
+                next: 3
+            }
+        );
+        let hello = source.mailbox.message("INBOX", "1/1").unwrap();
+        let hello = ww.get_node(&hello).unwrap();
+        assert_eq!(hello.label, "Hello");
+        assert_eq!(hello.kind, NodeKind::MimeType(MESSAGE_MIME.into()));
+        assert_eq!(
+            hello.data_blob[&BODY_BLOB],
+            DataBlob::Text("About Hello.\r\n".into())
+        );
+
+        // Only new e-mails are returned.
+        assert!(source.get_updates().await?.is_empty());
+        mailboxes.lock().unwrap()[0]
+            .messages
+            .push((3, mail("Dinner")));
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 2 + 1);
+        ww.process_updates(txs).await?;
+        let dinner = source.mailbox.message("INBOX", "1/3").unwrap();
+        assert_eq!(ww.get_node(&dinner).unwrap().label, "Dinner");
+
+        // If a folder fails, the folders and e-mails fetched before are
+        // returned by the next call.
+        mailboxes
+            .lock()
+            .unwrap()
+            .push(Mailbox::new("Drafts", &[&mail("Breakfast")]));
+        mailboxes.lock().unwrap()[0].broken = true;
+        assert!(source.get_updates().await.is_err());
+        mailboxes.lock().unwrap()[0].broken = false;
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 2 + 2 + 1);
+        ww.process_updates(txs).await?;
+        let breakfast = source.mailbox.message("Drafts", "1/1").unwrap();
+        assert_eq!(ww.get_node(&breakfast).unwrap().label, "Breakfast");
+
+        // A new UIDVALIDITY replaces all e-mails of the folder.
+        mailboxes.lock().unwrap()[1].uid_validity = 2;
+        let old = source.mailbox.message("Archive/2024", "1/1").unwrap();
+        let txs = source.get_updates().await?;
+        // The old e-mail is deleted with the edge from its sender.
+        assert_eq!(txs.len(), 2 + 2 + 1);
+        ww.process_updates(txs).await?;
+        assert!(ww.get_node(&old).is_none());
+        let new = source.mailbox.message("Archive/2024", "2/1").unwrap();
+        assert_eq!(ww.get_node(&new).unwrap().label, "Old");
+
+        config.password = "wrong".into();
+        let err = SourceIMAP::new(config).get_updates().await.unwrap_err();
+        assert!(err.to_string().contains("LOGIN failed"), "{err}");
+        Ok(())
     }
 }
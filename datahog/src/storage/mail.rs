@@ -0,0 +1,454 @@
+//! Converts e-mails into [Node]s of the type [MESSAGE_MIME], for the sources
+//! reading e-mails:
+//! - the headers are stored in a `headers` [DataBlob::Entry] at
+//!   [HEADERS_BLOB], with the date as a `datetime` entry
+//! - the text of the body, or its HTML if it has no text, is stored as a
+//!   [DataBlob::Text] at [BODY_BLOB]
+//! - each attachment is stored by its [DataBlob::Hash], followed by an
+//!   `attachment` entry with its name, type and size, starting at
+//!   [ATTACHMENT_BLOBS]
+//!
+//! The folders of the sources are kept in a [Mailbox], so e-mails look the
+//! same in the graph, whichever source they come from, and are threaded into
+//! conversations by [Threads].
+
+use std::collections::HashMap;
+
+use flarch::nodeids::U256;
+use mail_parser::{Address, HeaderValue, MessageParser, MimeHeaders};
+
+use crate::{
+    storage::threads::{Envelope, Threads},
+    structs::{
+        DataBlob, Edge, EdgeAction, EdgeID, Node, NodeID, NodeUpdate, SourceID, Transaction,
+    },
+};
+
+/// The MIME type of the e-mail [Node]s.
+pub const MESSAGE_MIME: &str = "message/rfc822";
+pub const HEADERS_BLOB: u32 = 0;
+pub const BODY_BLOB: u32 = 1;
+pub const ATTACHMENT_BLOBS: u32 = 2;
+
+/// Returns the [Node] of the e-mail, labelled with its subject.
+/// Content which cannot be parsed as an e-mail is stored as the body.
+pub fn message_node(id: NodeID, raw: &[u8]) -> Node {
+    parse_message(id, raw).0
+}
+
+/// Returns the [Node] of the e-mail, and its [Envelope] for the [Threads].
+fn parse_message(id: NodeID, raw: &[u8]) -> (Node, Envelope) {
+    let Some(message) = MessageParser::default().parse(raw) else {
+        let mut node = Node::mime(MESSAGE_MIME.into(), "(invalid message)".into());
+        node.id = id;
+        let body = String::from_utf8_lossy(raw).to_string();
+        node.data_blob.insert(BODY_BLOB, DataBlob::Text(body));
+        return (node, Envelope::default());
+    };
+    let subject = message.subject().unwrap_or("(no subject)");
+    let mut node = Node::mime(MESSAGE_MIME.into(), subject.into());
+    node.id = id;
+    let date = message
+        .date()
+        .map(|date| date.to_timestamp() as i128 * 1_000_000_000);
+
+    let mut headers = HashMap::new();
+    let mut text = |name: &str, value: Option<String>| {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            headers.insert(name.to_string(), DataBlob::Text(value));
+        }
+    };
+    text("subject", message.subject().map(String::from));
+    text("from", message.from().map(addresses));
+    text("to", message.to().map(addresses));
+    text("cc", message.cc().map(addresses));
+    text(
+        "message-id",
+        message.message_id().map(|id| format!("<{id}>")),
+    );
+    text(
+        "in-reply-to",
+        ids(message.in_reply_to()).map(|ids| brackets(&ids)),
+    );
+    text(
+        "references",
+        ids(message.references()).map(|ids| brackets(&ids)),
+    );
+    if let Some(nanos) = date {
+        headers.insert(
+            "date".into(),
+            DataBlob::Entry(
+                "datetime".into(),
+                HashMap::from([("timestamp".into(), DataBlob::Int(nanos.into()))]),
+            ),
+        );
+    }
+    node.data_blob
+        .insert(HEADERS_BLOB, DataBlob::Entry("headers".into(), headers));
+
+    if let Some(body) = message.body_text(0).or_else(|| message.body_html(0)) {
+        node.data_blob
+            .insert(BODY_BLOB, DataBlob::Text(body.to_string()));
+    }
+    for (i, attachment) in message.attachments().enumerate() {
+        let index = ATTACHMENT_BLOBS + 2 * i as u32;
+        let content = attachment.contents();
+        let mime = attachment
+            .content_type()
+            .map(|ct| match ct.subtype() {
+                Some(subtype) => format!("{}/{subtype}", ct.ctype()),
+                None => ct.ctype().to_string(),
+            })
+            .unwrap_or_else(|| "application/octet-stream".into());
+        let name = attachment.attachment_name().unwrap_or_default();
+        node.data_blob
+            .insert(index, DataBlob::Hash(U256::hash_data(content)));
+        node.data_blob.insert(
+            index + 1,
+            DataBlob::Entry(
+                "attachment".into(),
+                HashMap::from([
+                    ("name".into(), DataBlob::Text(name.into())),
+                    ("mime".into(), DataBlob::Text(mime)),
+                    ("size".into(), DataBlob::Int(content.len().into())),
+                ]),
+            ),
+        );
+    }
+
+    // The `In-Reply-To` is the parent, if the `References` are missing or
+    // end elsewhere.
+    let mut references = ids(message.references()).unwrap_or_default();
+    if let Some(parent) = ids(message.in_reply_to()).and_then(|ids| ids.first().cloned())
+        && references.last() != Some(&parent)
+    {
+        references.retain(|id| id != &parent);
+        references.push(parent);
+    }
+    let participants = [message.from(), message.to(), message.cc()]
+        .into_iter()
+        .flatten()
+        .flat_map(|address| address.iter())
+        .filter_map(|addr| {
+            let address = addr.address()?.to_string();
+            Some((addr.name().map(String::from), address))
+        })
+        .collect();
+    let envelope = Envelope {
+        subject: message.subject().map(String::from),
+        message_id: message.message_id().map(String::from),
+        references,
+        participants,
+        date,
+    };
+    (node, envelope)
+}
+
+/// Returns the addresses as `Name <address>`, separated by commas.
+fn addresses(address: &Address) -> String {
+    address
+        .iter()
+        .map(|addr| match (addr.name(), addr.address()) {
+            (Some(name), Some(address)) => format!("{name} <{address}>"),
+            (name, address) => name.or(address).unwrap_or_default().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the message IDs of the header.
+fn ids(value: &HeaderValue) -> Option<Vec<String>> {
+    match value {
+        HeaderValue::Text(id) => Some(vec![id.to_string()]),
+        HeaderValue::TextList(ids) => Some(ids.iter().map(|id| id.to_string()).collect()),
+        _ => None,
+    }
+}
+
+/// Returns the message IDs as `<id>`, separated by spaces, like the
+/// `message-id`.
+fn brackets(ids: &[String]) -> String {
+    ids.iter()
+        .map(|id| format!("<{id}>"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The folders and e-mails imported by a mail source:
+/// - the root of the source is a [NodeKind::Label] node with the name of the
+///   account
+/// - every folder is a [NodeKind::Label] node, contained in its parent
+///   folder, or in the root
+/// - every e-mail is a [message_node], contained in its folder
+/// - the conversations and the people writing the e-mails are added by
+///   [Mailbox::update_threads]
+///
+/// The IDs of the nodes and edges are derived from the [SourceID], the name
+/// of the folder, and the key the source gives to the e-mail.
+///
+/// [NodeKind::Label]: crate::structs::NodeKind::Label
+#[derive(Debug, Clone)]
+pub struct Mailbox {
+    domain: &'static str,
+    id: SourceID,
+    root: bool,
+    folders: HashMap<String, MailFolder>,
+    threads: Threads,
+}
+
+#[derive(Debug, Clone)]
+struct MailFolder {
+    id: NodeID,
+    /// The [EdgeKind::Contains] edge to the parent.
+    ///
+    /// [EdgeKind::Contains]: crate::structs::EdgeKind::Contains
+    edge: EdgeID,
+    /// The nodes of the e-mails and their edges, by their key.
+    messages: HashMap<String, (NodeID, EdgeID)>,
+}
+
+impl Mailbox {
+    /// The `domain` is used to derive the IDs, e.g., the name of the source.
+    pub fn new(domain: &'static str, id: SourceID) -> Self {
+        Self {
+            domain,
+            threads: Threads::new(domain, id.clone()),
+            id,
+            root: false,
+            folders: HashMap::new(),
+        }
+    }
+
+    /// Creates the root node on the first call, and does nothing afterwards.
+    pub fn root(&mut self, label: &str) -> Vec<Transaction> {
+        if self.root {
+            return vec![];
+        }
+        self.root = true;
+        let mut root = Node::label(label);
+        root.id = self.id.root_node();
+        vec![Transaction::create_node(root)]
+    }
+
+    /// Updates the folders to the given names, with the delimiter separating
+    /// the name of their parent, if any.
+    /// Removed folders are deleted with their e-mails.
+    pub fn set_folders(&mut self, folders: &[(String, Option<String>)]) -> Vec<Transaction> {
+        let mut txs = vec![];
+        let mut removed = self
+            .folders
+            .keys()
+            .filter(|name| !folders.iter().any(|(f, _)| &f == name))
+            .cloned()
+            .collect::<Vec<_>>();
+        removed.sort();
+        for name in removed.into_iter().rev() {
+            log::debug!("Removing folder {name}");
+            txs.extend(self.clear_folder(&name));
+            let folder = self.folders.remove(&name).unwrap();
+            txs.extend(delete(folder.id, folder.edge));
+        }
+        let mut new = folders
+            .iter()
+            .filter(|(name, _)| !self.folders.contains_key(name))
+            .collect::<Vec<_>>();
+        // Parents come before their children.
+        new.sort();
+        for (name, delimiter) in new {
+            txs.extend(self.create_folder(name, delimiter.as_deref()));
+        }
+        txs
+    }
+
+    /// Returns the node of the folder.
+    pub fn folder(&self, name: &str) -> Option<NodeID> {
+        self.folders.get(name).map(|f| f.id.clone())
+    }
+
+    /// Returns the keys of the e-mails of the folder.
+    pub fn keys(&self, folder: &str) -> Vec<String> {
+        self.folders
+            .get(folder)
+            .map(|f| f.messages.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the node of the e-mail.
+    pub fn message(&self, folder: &str, key: &str) -> Option<NodeID> {
+        let folder = self.folders.get(folder)?;
+        folder.messages.get(key).map(|(id, _)| id.clone())
+    }
+
+    /// Creates the node of the e-mail in the folder, replacing the one with
+    /// the same key.
+    pub fn add_message(&mut self, folder: &str, key: &str, raw: &[u8]) -> Vec<Transaction> {
+        let mut txs = self.remove_message(folder, key);
+        let Some(container) = self.folder(folder) else {
+            log::warn!("Folder {folder} of e-mail {key} is unknown");
+            return txs;
+        };
+        let id = NodeID::hash_domain_parts(
+            self.domain,
+            &[self.id.as_ref(), folder.as_bytes(), key.as_bytes()],
+        );
+        let (node, envelope) = parse_message(id, raw);
+        self.threads.add(node.id.clone(), envelope);
+        let edge = self.contains(&container, &node.id);
+        self.folders
+            .get_mut(folder)
+            .unwrap()
+            .messages
+            .insert(key.into(), (node.id.clone(), edge.id.clone()));
+        txs.extend([
+            Transaction::create_node(node),
+            Transaction::create_edge(edge),
+        ]);
+        txs
+    }
+
+    /// Deletes the node of the e-mail, if it exists.
+    pub fn remove_message(&mut self, folder: &str, key: &str) -> Vec<Transaction> {
+        let Some((node, edge)) = self
+            .folders
+            .get_mut(folder)
+            .and_then(|f| f.messages.remove(key))
+        else {
+            return vec![];
+        };
+        self.threads.remove(&node);
+        delete(node, edge).to_vec()
+    }
+
+    /// Deletes the nodes of all e-mails of the folder.
+    pub fn clear_folder(&mut self, folder: &str) -> Vec<Transaction> {
+        let Some(folder) = self.folders.get_mut(folder) else {
+            return vec![];
+        };
+        let mut messages = folder.messages.drain().collect::<Vec<_>>();
+        messages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        messages
+            .into_iter()
+            .flat_map(|(_, (node, edge))| {
+                self.threads.remove(&node);
+                delete(node, edge)
+            })
+            .collect()
+    }
+
+    /// Updates the conversations and the people of the e-mails, once all
+    /// changes of the source have been done.
+    pub fn update_threads(&mut self) -> Vec<Transaction> {
+        self.threads.update()
+    }
+
+    /// Creates the node of the folder, contained in its parent folder, or in
+    /// the root if the parent is unknown.
+    fn create_folder(&mut self, name: &str, delimiter: Option<&str>) -> Vec<Transaction> {
+        let (parent, label) = match delimiter.and_then(|d| name.rsplit_once(d)) {
+            Some((parent, label)) => (self.folder(parent), label),
+            None => (None, name),
+        };
+        let parent = parent.unwrap_or_else(|| self.id.root_node());
+        let mut node = Node::label(label);
+        node.id = NodeID::hash_domain_parts(self.domain, &[self.id.as_ref(), name.as_bytes()]);
+        let edge = self.contains(&parent, &node.id);
+        self.folders.insert(
+            name.into(),
+            MailFolder {
+                id: node.id.clone(),
+                edge: edge.id.clone(),
+                messages: HashMap::new(),
+            },
+        );
+        vec![
+            Transaction::create_node(node),
+            Transaction::create_edge(edge),
+        ]
+    }
+
+    /// Returns a [EdgeKind::Contains] edge with an ID derived from its nodes.
+    ///
+    /// [EdgeKind::Contains]: crate::structs::EdgeKind::Contains
+    fn contains(&self, container: &NodeID, object: &NodeID) -> Edge {
+        let mut edge = Edge::contains(container.clone(), object.clone());
+        edge.id = EdgeID::hash_domain_parts(self.domain, &[container.as_ref(), object.as_ref()]);
+        edge
+    }
+}
+
+/// Deletes the node and its edge.
+fn delete(node: NodeID, edge: EdgeID) -> [Transaction; 2] {
+    [
+        Transaction::update_edge(edge, vec![EdgeAction::Delete]),
+        Transaction::update_node(node, vec![NodeUpdate::Delete]),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAIL: &str = "From: Alice <alice@example.com>\r
+To: Bob <bob@example.com>, carol@example.com\r
+Subject: =?utf-8?q?Caf=C3=A9?= plans\r
+Date: Mon, 3 Feb 2025 10:00:00 +0100\r
+Message-ID: <2@example.com>\r
+In-Reply-To: <1@example.com>\r
+References: <0@example.com> <1@example.com>\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"b\"\r
+\r
+--b\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+See you there.\r
+--b\r
+Content-Type: application/pdf; name=\"menu.pdf\"\r
+Content-Disposition: attachment; filename=\"menu.pdf\"\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERg==\r
+--b--\r
+";
+
+    #[test]
+    fn test_message_node() {
+        let node = message_node(NodeID::rnd(), MAIL.as_bytes());
+        assert_eq!(node.label, "Café plans");
+        let DataBlob::Entry(name, headers) = &node.data_blob[&HEADERS_BLOB] else {
+            panic!("Expected the headers");
+        };
+        assert_eq!(name, "headers");
+        let text = |name: &str| headers[name].clone();
+        assert_eq!(
+            text("from"),
+            DataBlob::Text("Alice <alice@example.com>".into())
+        );
+        assert_eq!(
+            text("to"),
+            DataBlob::Text("Bob <bob@example.com>, carol@example.com".into())
+        );
+        assert_eq!(text("message-id"), DataBlob::Text("<2@example.com>".into()));
+        assert_eq!(
+            text("references"),
+            DataBlob::Text("<0@example.com> <1@example.com>".into())
+        );
+        assert!(matches!(&headers["date"], DataBlob::Entry(name, fields)
+            if name == "datetime" && fields["timestamp"] == DataBlob::Int(1_738_573_200_000_000_000i128.into())));
+        assert_eq!(
+            node.data_blob[&BODY_BLOB],
+            DataBlob::Text("See you there.".into())
+        );
+        assert_eq!(
+            node.data_blob[&ATTACHMENT_BLOBS],
+            DataBlob::Hash(U256::hash_data(b"%PDF"))
+        );
+        assert!(
+            matches!(&node.data_blob[&(ATTACHMENT_BLOBS + 1)], DataBlob::Entry(name, fields)
+            if name == "attachment" && fields["name"] == DataBlob::Text("menu.pdf".into()))
+        );
+
+        let node = message_node(NodeID::rnd(), b"");
+        assert_eq!(node.label, "(invalid message)");
+    }
+}
@@ -0,0 +1,250 @@
+//! Reads the e-mails of a Maildir, with the same nodes as
+//! [crate::storage::imap::SourceIMAP], see [Mailbox]:
+//! - the Maildir itself is the `INBOX` folder
+//! - its sub-directories starting with a `.` are the other folders, in the
+//!   Maildir++ layout: `.Archive.2024` is the folder `2024` in `Archive`
+//! - the e-mails are read from `cur` and `new`, and are identified by the
+//!   unique part of their file name, so changing their flags or moving them
+//!   from `new` to `cur` doesn't create a new node
+//!
+//! Only new e-mails are read again, and removed e-mails and folders are
+//! deleted.
+//! The source cannot write to the Maildir.
+
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+
+use crate::{
+    storage::{
+        dir_trait::{DirectoryEntry, Reader},
+        mail::Mailbox,
+    },
+    structs::{Source, SourceID, Transaction},
+};
+
+/// The folder of the Maildir itself.
+pub const INBOX: &str = "INBOX";
+
+#[derive(Debug)]
+pub struct SourceMaildir<R>
+where
+    R: Reader + std::fmt::Debug + Sync + Send,
+{
+    reader: R,
+    id: SourceID,
+    mailbox: Mailbox,
+}
+
+#[async_trait]
+impl<R: Reader + std::fmt::Debug + Sync + Send> Source for SourceMaildir<R> {
+    /// The first call returns all folders and e-mails, the following calls
+    /// only the changes.
+    /// If reading fails, the state is kept as it was, so the next call
+    /// returns the changes again.
+    async fn get_updates(&mut self) -> Result<Vec<Transaction>> {
+        let mailbox = self.mailbox.clone();
+        let res = self.scan().await;
+        if res.is_err() {
+            self.mailbox = mailbox;
+        }
+        res
+    }
+
+    async fn add_tx(&mut self, _txs: Vec<Transaction>) -> Result<()> {
+        bail!("Writing to a Maildir is not supported")
+    }
+
+    /// Returns the unique ID of this source, derived from the location of the
+    /// Maildir.
+    fn get_id(&self) -> SourceID {
+        self.id.clone()
+    }
+}
+
+impl<R: Reader + std::fmt::Debug + Sync + Send> SourceMaildir<R> {
+    pub fn new(reader: R) -> Self {
+        let id = SourceID::hash_domain_parts("SourceMaildir", &[reader.location().as_bytes()]);
+        Self {
+            reader,
+            mailbox: Mailbox::new("SourceMaildir", id.clone()),
+            id,
+        }
+    }
+
+    /// Reads the folders and the changes of their e-mails.
+    async fn scan(&mut self) -> Result<Vec<Transaction>> {
+        let mut txs = self.mailbox.root(&self.reader.location());
+        let root = self.reader.read_directory(&[]).await?;
+        let mut folders = vec![];
+        if is_maildir(&root) {
+            folders.push((INBOX.to_string(), None));
+        }
+        for entry in &root {
+            if let DirectoryEntry::Directory(dir) = entry
+                && let Some(name) = dir.strip_prefix('.')
+                && !name.is_empty()
+                && is_maildir(&self.reader.read_directory(&[dir]).await?)
+            {
+                folders.push((name.to_string(), Some(".".to_string())));
+            }
+        }
+        txs.extend(self.mailbox.set_folders(&folders));
+        for (name, _) in &folders {
+            let dir = match name.as_str() {
+                INBOX => None,
+                name => Some(format!(".{name}")),
+            };
+            txs.extend(self.read_folder(name, dir.as_deref()).await?);
+        }
+        txs.extend(self.mailbox.update_threads());
+        Ok(txs)
+    }
+
+    /// Reads the new e-mails of the folder in `dir`, or in the root for
+    /// [None], and deletes the removed ones.
+    async fn read_folder(&mut self, name: &str, dir: Option<&str>) -> Result<Vec<Transaction>> {
+        let mut known = self.mailbox.keys(name);
+        let mut txs = vec![];
+        for sub in ["cur", "new"] {
+            let path = dir.into_iter().chain([sub]).collect::<Vec<_>>();
+            let Ok(entries) = self.reader.read_directory(&path).await else {
+                continue;
+            };
+            for entry in entries {
+                let DirectoryEntry::File(file) = entry else {
+                    continue;
+                };
+                if file.starts_with('.') {
+                    continue;
+                }
+                let key = unique(&file);
+                match known.iter().position(|k| k == key) {
+                    Some(pos) => {
+                        known.swap_remove(pos);
+                    }
+                    None => {
+                        let mut file_path = path.clone();
+                        file_path.push(&file);
+                        let raw = self.reader.read_bytes(&file_path).await?;
+                        txs.extend(self.mailbox.add_message(name, key, &raw));
+                    }
+                }
+            }
+        }
+        known.sort();
+        for key in known {
+            txs.extend(self.mailbox.remove_message(name, &key));
+        }
+        Ok(txs)
+    }
+}
+
+/// Returns true if the entries of a directory are those of a Maildir.
+fn is_maildir(entries: &[DirectoryEntry]) -> bool {
+    entries.contains(&DirectoryEntry::Directory("cur".into()))
+}
+
+/// Returns the unique part of the file name of an e-mail, without the flags
+/// following the `:`, or the `!` used on Windows.
+fn unique(file: &str) -> &str {
+    file.split([':', '!']).next().unwrap_or(file)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        storage::{
+            dir_trait::{EmulatedDir, FailingDir, Writer},
+            mail::BODY_BLOB,
+        },
+        structs::DataBlob,
+        worldview::WorldView,
+    };
+
+    use super::*;
+
+    fn mail(subject: &str) -> String {
+        format!("Subject: {subject}\r\nFrom: alice@example.com\r\n\r\nAbout {subject}.\r\n")
+    }
+
+    #[tokio::test]
+    async fn test_maildir() -> Result<()> {
+        let dir = EmulatedDir::new_from_string(&[
+            ("cur/1.host:2,S", &mail("Hello")),
+            ("new/2.host", &mail("Lunch")),
+            ("tmp/3.host", &mail("Partial")),
+            (".Archive/cur/4.host:2,", &mail("Old")),
+            (".Archive.2024/cur/5.host:2,S", &mail("Older")),
+            (".Trash/unrelated", ""),
+        ]);
+        let mut source = SourceMaildir::new(dir);
+        let txs = source.get_updates().await?;
+        // The root, 3 folders and 4 e-mails, with their edges, and the
+        // sender with an edge to each e-mail.
+        assert_eq!(txs.len(), 1 + 2 * 3 + 2 * 4 + 1 + 4);
+        let mut ww = WorldView::new();
+        ww.process_updates(txs).await?;
+
+        let archive = source.mailbox.folder("Archive").unwrap();
+        let year = ww
+            .get_node(&source.mailbox.folder("Archive.2024").unwrap())
+            .unwrap();
+        assert_eq!(year.label, "2024");
+        assert!(year.edges.iter().any(|e| e.nodes().contains(&&archive)));
+        let hello = source.mailbox.message(INBOX, "1.host").unwrap();
+        let hello = ww.get_node(&hello).unwrap();
+        assert_eq!(hello.label, "Hello");
+        assert_eq!(
+            hello.data_blob[&BODY_BLOB],
+            DataBlob::Text("About Hello.\r\n".into())
+        );
+        assert!(source.get_updates().await?.is_empty());
+
+        // Reading an e-mail moves it to cur and changes its flags.
+        let lunch = source.mailbox.message(INBOX, "2.host").unwrap();
+        let disk = &mut source.reader;
+        disk.rename(&["new", "2.host"], &["cur", "2.host:2,S"])
+            .await?;
+        assert!(source.get_updates().await?.is_empty());
+        assert_eq!(source.mailbox.message(INBOX, "2.host"), Some(lunch));
+
+        let disk = &mut source.reader;
+        disk.write_file(&["new", "6.host"], &mail("Dinner")).await?;
+        disk.remove(&["cur", "1.host:2,S"]).await?;
+        disk.remove(&[".Archive.2024"]).await?;
+        let txs = source.get_updates().await?;
+        // The folder and its e-mail are deleted, one e-mail is removed and one
+        // added with the edge from its sender. The edges of the deleted
+        // e-mails are deleted with them.
+        assert_eq!(txs.len(), 2 * 2 + 2 * 2 + 1);
+        ww.process_updates(txs).await?;
+        assert!(ww.get_node(&year.id).is_none());
+        assert!(ww.get_node(&hello.id).is_none());
+        let dinner = source.mailbox.message(INBOX, "6.host").unwrap();
+        assert_eq!(ww.get_node(&dinner).unwrap().label, "Dinner");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_maildir_error() -> Result<()> {
+        let dir = EmulatedDir::new_from_string(&[
+            ("cur/1.host:2,S", &mail("Hello")),
+            ("new/2.host", &mail("Lunch")),
+        ]);
+        let mut source = SourceMaildir::new(FailingDir {
+            dir,
+            failing: vec!["2.host".into()],
+        });
+        assert!(source.get_updates().await.is_err());
+
+        // The e-mail read before the error is returned again.
+        source.reader.failing.clear();
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        for key in ["1.host", "2.host"] {
+            let id = source.mailbox.message(INBOX, key).unwrap();
+            assert!(ww.get_node(&id).is_some());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+//! Reads the e-mails of a directory of mbox files, like the `Mail` directory
+//! of Thunderbird or mutt, with the same nodes as
+//! [crate::storage::imap::SourceIMAP], see [Mailbox]:
+//! - every mbox file is a folder, named by its path
+//! - every directory is a folder, with a `.sbd` suffix removed, so the
+//!   e-mails of `Archive` and the folders of `Archive.sbd` are in the same
+//!   folder
+//! - hidden files and the `.msf` index files of Thunderbird are skipped
+//!
+//! The e-mails of an mbox file start with a `From ` line following an empty
+//! line, and the `>From ` lines escaped by mboxrd are restored.
+//! As they have no stable name, they are identified by the hash of their
+//! content.
+//!
+//! Only the files which changed since the previous call are read again, and
+//! removed e-mails and folders are deleted.
+//! The source cannot write to the mbox files.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use flarch::nodeids::U256;
+
+use crate::{
+    storage::{
+        dir_trait::{DirectoryEntry, Metadata, Reader},
+        mail::Mailbox,
+    },
+    structs::{Source, SourceID, Transaction},
+};
+
+/// An mbox file, with the name of its folder.
+type MboxFile = (String, Vec<String>);
+
+#[derive(Debug)]
+pub struct SourceMbox<R>
+where
+    R: Reader + std::fmt::Debug + Sync + Send,
+{
+    reader: R,
+    id: SourceID,
+    mailbox: Mailbox,
+    /// The [Metadata] of the mbox files when they were read, by their folder.
+    read: HashMap<String, Metadata>,
+}
+
+#[async_trait]
+impl<R: Reader + std::fmt::Debug + Sync + Send> Source for SourceMbox<R> {
+    /// The first call returns all folders and e-mails, the following calls
+    /// only the changes.
+    /// If reading fails, the state is kept as it was, so the next call
+    /// returns the changes again.
+    async fn get_updates(&mut self) -> Result<Vec<Transaction>> {
+        let (mailbox, read) = (self.mailbox.clone(), self.read.clone());
+        let res = self.scan().await;
+        if res.is_err() {
+            (self.mailbox, self.read) = (mailbox, read);
+        }
+        res
+    }
+
+    async fn add_tx(&mut self, _txs: Vec<Transaction>) -> Result<()> {
+        bail!("Writing to mbox files is not supported")
+    }
+
+    /// Returns the unique ID of this source, derived from the location of the
+    /// directory.
+    fn get_id(&self) -> SourceID {
+        self.id.clone()
+    }
+}
+
+impl<R: Reader + std::fmt::Debug + Sync + Send> SourceMbox<R> {
+    pub fn new(reader: R) -> Self {
+        let id = SourceID::hash_domain_parts("SourceMbox", &[reader.location().as_bytes()]);
+        Self {
+            reader,
+            mailbox: Mailbox::new("SourceMbox", id.clone()),
+            id,
+            read: HashMap::new(),
+        }
+    }
+
+    /// Reads the folders and the changed mbox files.
+    async fn scan(&mut self) -> Result<Vec<Transaction>> {
+        let mut txs = self.mailbox.root(&self.reader.location());
+        let mut folders = vec![];
+        let mut files = vec![];
+        self.find(vec![], &mut folders, &mut files).await?;
+        folders.sort();
+        folders.dedup();
+        let delimited = folders
+            .into_iter()
+            .map(|name| (name, Some("/".to_string())))
+            .collect::<Vec<_>>();
+        txs.extend(self.mailbox.set_folders(&delimited));
+        self.read
+            .retain(|name, _| files.iter().any(|(file, _)| file == name));
+        for (name, path) in files {
+            let path = path.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+            let meta = self.reader.metadata(&path).await?;
+            if self.read.get(&name) == Some(&meta) {
+                continue;
+            }
+            let content = self.reader.read_bytes(&path).await?;
+            txs.extend(self.read_mbox(&name, &content));
+            self.read.insert(name, meta);
+        }
+        txs.extend(self.mailbox.update_threads());
+        Ok(txs)
+    }
+
+    /// Collects the names of all folders, and the mbox files with their path.
+    #[async_recursion]
+    async fn find(
+        &self,
+        path: Vec<String>,
+        folders: &mut Vec<String>,
+        files: &mut Vec<MboxFile>,
+    ) -> Result<()> {
+        let dir = path.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+        for entry in self.reader.read_directory(&dir).await? {
+            let (DirectoryEntry::Directory(name) | DirectoryEntry::File(name)) = &entry;
+            if name.starts_with('.') || name.ends_with(".msf") {
+                continue;
+            }
+            let mut entry_path = path.clone();
+            entry_path.push(name.clone());
+            let folder = entry_path
+                .iter()
+                .map(|p| p.strip_suffix(".sbd").unwrap_or(p))
+                .collect::<Vec<_>>()
+                .join("/");
+            folders.push(folder.clone());
+            match entry {
+                DirectoryEntry::Directory(_) => self.find(entry_path, folders, files).await?,
+                DirectoryEntry::File(_) => files.push((folder, entry_path)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the new e-mails of the mbox file, and deletes the removed ones.
+    fn read_mbox(&mut self, folder: &str, content: &[u8]) -> Vec<Transaction> {
+        let mut known = self.mailbox.keys(folder);
+        let mut txs = vec![];
+        let mut count: HashMap<U256, usize> = HashMap::new();
+        for message in split_mbox(content) {
+            let hash = U256::hash_data(&message);
+            let n = count.entry(hash).or_default();
+            *n += 1;
+            let key = format!("{hash}-{n}");
+            match known.iter().position(|k| k == &key) {
+                Some(pos) => {
+                    known.swap_remove(pos);
+                }
+                None => txs.extend(self.mailbox.add_message(folder, &key, &message)),
+            }
+        }
+        known.sort();
+        for key in known {
+            txs.extend(self.mailbox.remove_message(folder, &key));
+        }
+        txs
+    }
+}
+
+/// Splits the content of an mbox file into its e-mails, without their `From `
+/// line and the empty line separating them.
+pub fn split_mbox(content: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut current: Option<Vec<u8>> = None;
+    let mut empty = true;
+    for line in content.split_inclusive(|b| *b == b'\n') {
+        if empty && line.starts_with(b"From ") {
+            messages.extend(current.replace(vec![]));
+            empty = false;
+            continue;
+        }
+        empty = line == b"\n" || line == b"\r\n";
+        if let Some(message) = current.as_mut() {
+            let quotes = line.iter().take_while(|b| **b == b'>').count();
+            match quotes > 0 && line[quotes..].starts_with(b"From ") {
+                true => message.extend(&line[1..]),
+                false => message.extend(line),
+            }
+        }
+    }
+    messages.extend(current);
+    for message in &mut messages {
+        if message.ends_with(b"\r\n\r\n") {
+            message.truncate(message.len() - 2);
+        } else if message.ends_with(b"\n\n") {
+            message.pop();
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        storage::{
+            dir_trait::{EmulatedDir, FailingDir, Writer},
+            mail::{HEADERS_BLOB, message_node},
+        },
+        structs::DataBlob,
+        worldview::WorldView,
+    };
+
+    use super::*;
+
+    const MBOX: &str = "From alice@example.com Mon Feb  3 10:00:00 2025
+Subject: Hello
+From: alice@example.com
+
+About Hello.
+>From the start.
+
+From bob@example.com Mon Feb  3 11:00:00 2025
+Subject: Lunch
+
+>From here.
+>>From there.
+";
+
+    #[test]
+    fn test_split_mbox() {
+        let messages = split_mbox(MBOX.as_bytes());
+        assert_eq!(
+            messages,
+            vec![
+                b"Subject: Hello\nFrom: alice@example.com\n\nAbout Hello.\nFrom the start.\n"
+                    .to_vec(),
+                b"Subject: Lunch\n\nFrom here.\n>From there.\n".to_vec(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mbox() -> Result<()> {
+        let dir = EmulatedDir::new_from_string(&[
+            ("Inbox", MBOX),
+            ("Inbox.msf", ""),
+            ("Inbox.sbd/Lists", "From x\nSubject: List\n\nHi\n"),
+            ("Archive/2024", ""),
+        ]);
+        let mut source = SourceMbox::new(dir);
+        let txs = source.get_updates().await?;
+        // The root, 4 folders and 3 e-mails, with their edges, and the only
+        // sender with its edge.
+        assert_eq!(txs.len(), 1 + 2 * 4 + 2 * 3 + 2);
+        let mut ww = WorldView::new();
+        ww.process_updates(txs).await?;
+        let inbox = source.mailbox.folder("Inbox").unwrap();
+        let lists = ww
+            .get_node(&source.mailbox.folder("Inbox/Lists").unwrap())
+            .unwrap();
+        assert_eq!(lists.label, "Lists");
+        assert!(lists.edges.iter().any(|e| e.nodes().contains(&&inbox)));
+        assert!(source.mailbox.folder("Archive/2024").is_some());
+
+        // The e-mails look the same as those of the other sources.
+        let messages = split_mbox(MBOX.as_bytes());
+        let key = format!("{}-1", U256::hash_data(&messages[0]));
+        let hello = source.mailbox.message("Inbox", &key).unwrap();
+        let hello = ww.get_node(&hello).unwrap();
+        let expected = message_node(hello.id.clone(), &messages[0]);
+        assert_eq!(hello.label, "Hello");
+        assert_eq!(hello.data_blob, expected.data_blob);
+        assert!(
+            matches!(&hello.data_blob[&HEADERS_BLOB], DataBlob::Entry(name, _) if name == "headers")
+        );
+        assert!(source.get_updates().await?.is_empty());
+
+        // Removing the first e-mail only deletes it, and its sender.
+        let lunch = MBOX.split_at(MBOX.find("\nFrom bob").unwrap() + 1).1;
+        source
+            .reader
+            .overwrite(&["Inbox"], lunch.as_bytes())
+            .await?;
+        let txs = source.get_updates().await?;
+        assert_eq!(txs.len(), 2 + 1);
+        ww.process_updates(txs).await?;
+        assert!(ww.get_node(&hello.id).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mbox_error() -> Result<()> {
+        let dir = EmulatedDir::new_from_string(&[("Inbox", MBOX), ("Sent", MBOX)]);
+        let mut source = SourceMbox::new(FailingDir {
+            dir,
+            failing: vec!["Sent".into()],
+        });
+        assert!(source.get_updates().await.is_err());
+
+        // The file read before the error is read again.
+        source.reader.failing.clear();
+        let mut ww = WorldView::new();
+        ww.process_updates(source.get_updates().await?).await?;
+        let key = format!("{}-1", U256::hash_data(&split_mbox(MBOX.as_bytes())[0]));
+        for folder in ["Inbox", "Sent"] {
+            let id = source.mailbox.message(folder, &key).unwrap();
+            assert!(ww.get_node(&id).is_some());
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,17 @@
+pub mod csv;
 pub mod dir_trait;
 pub mod disk;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fs_dir;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod git;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod git_store;
+pub mod handlers;
+pub mod ical;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod imap;
+pub mod mail;
+pub mod maildir;
+pub mod mbox;
+pub mod threads;
@@ -0,0 +1,499 @@
+//! Reconstructs the conversations of the e-mails of a
+//! [crate::storage::mail::Mailbox], following the threading algorithm of
+//! <https://www.jwz.org/doc/threading.html>:
+//! - the `References` and `In-Reply-To` headers link the e-mails to their
+//!   parents, also through the e-mails which are missing
+//! - a reply has an [EdgeKind::Reference] edge to its closest existing
+//!   parent, stored as a [DataBlob::Edge] at [REPLY_BLOB]
+//! - every conversation with more than one e-mail becomes a
+//!   [NodeKind::Label] node, labelled with the subject of its first e-mail,
+//!   which contains all its e-mails
+//! - the senders and recipients become [NodeKind::Label] nodes with a
+//!   `person` [DataBlob::Entry], one per address, with an [EdgeKind::Using]
+//!   edge to each of their e-mails
+//!
+//! Unlike the original algorithm, e-mails are not grouped by their subject,
+//! as this merges unrelated conversations with a common subject.
+//!
+//! [NodeKind::Label]: crate::structs::NodeKind::Label
+
+use std::collections::HashMap;
+
+use crate::structs::{
+    DataBlob, Edge, EdgeAction, EdgeID, EdgeKind, Node, NodeID, NodeUpdate, SourceID, Timestamp,
+    Transaction, Validity,
+};
+
+/// Index of the [DataBlob::Edge] pointing from a reply to its parent.
+pub const REPLY_BLOB: u32 = 1 << 24;
+
+/// The headers of an e-mail needed to thread it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Envelope {
+    pub subject: Option<String>,
+    pub message_id: Option<String>,
+    /// The IDs of the ancestors, starting with the oldest, and ending with the
+    /// parent.
+    pub references: Vec<String>,
+    /// The name and address of the sender and the recipients.
+    pub participants: Vec<(Option<String>, String)>,
+    pub date: Option<Timestamp>,
+}
+
+/// The nodes and edges created for the conversations.
+#[derive(Debug, Clone, Default)]
+struct Graph {
+    /// The labels of the conversations.
+    conversations: HashMap<NodeID, String>,
+    /// The [EdgeKind::Contains] edges of the conversations, the
+    /// [EdgeKind::Using] edges of the people, and the replies.
+    edges: HashMap<EdgeID, Edge>,
+    /// The [EdgeKind::Reference] edge of each reply to its parent.
+    replies: HashMap<NodeID, Edge>,
+    people: HashMap<NodeID, Node>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Threads {
+    domain: &'static str,
+    id: SourceID,
+    envelopes: HashMap<NodeID, Envelope>,
+    changed: bool,
+    /// What the previous call to [Threads::update] created.
+    applied: Graph,
+}
+
+impl Threads {
+    /// The `domain` and the [SourceID] are used to derive the IDs, like the
+    /// [crate::storage::mail::Mailbox] does.
+    pub fn new(domain: &'static str, id: SourceID) -> Self {
+        Self {
+            domain,
+            id,
+            envelopes: HashMap::new(),
+            changed: false,
+            applied: Graph::default(),
+        }
+    }
+
+    /// Adds the e-mail, which must have been created before the next call to
+    /// [Threads::update].
+    pub fn add(&mut self, node: NodeID, envelope: Envelope) {
+        self.envelopes.insert(node, envelope);
+        self.changed = true;
+    }
+
+    /// Removes the e-mail, which is deleted.
+    pub fn remove(&mut self, node: &NodeID) {
+        if self.envelopes.remove(node).is_some() {
+            // The node is deleted with its edges, or created again without
+            // its reply and its edges.
+            self.applied.replies.remove(node);
+            self.applied
+                .edges
+                .retain(|_, edge| !edge.nodes().contains(&node));
+            self.changed = true;
+        }
+    }
+
+    /// Returns the changes to the conversations and people since the previous
+    /// call.
+    pub fn update(&mut self) -> Vec<Transaction> {
+        if !self.changed {
+            return vec![];
+        }
+        self.changed = false;
+        let graph = self.graph();
+        let txs = self.diff(&graph);
+        self.applied = graph;
+        txs
+    }
+
+    /// Returns the conversations and the people of all e-mails.
+    fn graph(&self) -> Graph {
+        let mut nodes = self.envelopes.keys().collect::<Vec<_>>();
+        nodes.sort_by_key(|id| id.to_string());
+
+        // The e-mails of each message ID, and the parent of each message ID,
+        // even if its e-mail is missing.
+        let mut messages: HashMap<String, Vec<&NodeID>> = HashMap::new();
+        let mut parents: HashMap<String, String> = HashMap::new();
+        for node in &nodes {
+            let envelope = &self.envelopes[*node];
+            let id = message_id(node, envelope);
+            messages.entry(id.clone()).or_default().push(node);
+            for pair in envelope.references.windows(2) {
+                if !parents.contains_key(&pair[1]) && !is_ancestor(&parents, &pair[1], &pair[0]) {
+                    parents.insert(pair[1].clone(), pair[0].clone());
+                }
+            }
+            // The e-mail itself knows its parent best.
+            if let Some(parent) = envelope.references.last()
+                && !is_ancestor(&parents, &id, parent)
+            {
+                parents.insert(id, parent.clone());
+            }
+        }
+
+        let mut graph = Graph::default();
+        // The e-mails of each conversation, by the ID of its root, with their
+        // depth.
+        let mut threads: HashMap<String, Vec<(usize, &NodeID)>> = HashMap::new();
+        for node in &nodes {
+            let envelope = &self.envelopes[*node];
+            let mut root = message_id(node, envelope);
+            let mut reply_to = None;
+            let mut depth = 0;
+            while let Some(parent) = parents.get(&root) {
+                if reply_to.is_none()
+                    && let Some(parent) = messages.get(parent)
+                {
+                    reply_to = Some(parent[0]);
+                }
+                root = parent.clone();
+                depth += 1;
+            }
+            threads.entry(root).or_default().push((depth, node));
+            if let Some(parent) = reply_to {
+                let kind = EdgeKind::Reference {
+                    dest: parent.clone(),
+                    blob: None,
+                };
+                let edge = self.edge(&[*node, parent], kind, envelope.date);
+                graph.edges.insert(edge.id.clone(), edge.clone());
+                graph.replies.insert((*node).clone(), edge);
+            }
+            for (name, address) in &envelope.participants {
+                let person = self.person(name.as_deref(), address);
+                let person = graph.people.entry(person.id.clone()).or_insert(person);
+                if person.label == address.as_str()
+                    && let Some(name) = name
+                {
+                    *person = self.person(Some(name), address);
+                }
+                let kind = EdgeKind::Using {
+                    client: person.id.clone(),
+                    object: (*node).clone(),
+                };
+                let edge = self.edge(&[&person.id, node], kind, envelope.date);
+                graph.edges.insert(edge.id.clone(), edge);
+            }
+        }
+
+        for (root, mut thread) in threads {
+            if thread.len() < 2 {
+                continue;
+            }
+            thread.sort_by_key(|(depth, node)| (*depth, node.to_string()));
+            let first = &self.envelopes[thread[0].1];
+            let label = first
+                .subject
+                .as_deref()
+                .map(base_subject)
+                .unwrap_or("(no subject)");
+            let id = NodeID::hash_domain_parts(
+                self.domain,
+                &[self.id.as_ref(), b"conversation", root.as_bytes()],
+            );
+            for (_, node) in thread {
+                let kind = EdgeKind::Contains {
+                    container: id.clone(),
+                    object: node.clone(),
+                };
+                let edge = self.edge(&[&id, node], kind, self.envelopes[node].date);
+                graph.edges.insert(edge.id.clone(), edge);
+            }
+            graph.conversations.insert(id, label.to_string());
+        }
+        graph
+    }
+
+    /// Returns the [Transaction]s to go from the applied graph to the new
+    /// one: edges are removed before nodes, and added after them.
+    fn diff(&self, graph: &Graph) -> Vec<Transaction> {
+        let old = &self.applied;
+        let mut txs = vec![];
+        let removed_edges = sorted(old.edges.keys().filter(|id| !graph.edges.contains_key(id)));
+        txs.extend(
+            removed_edges
+                .into_iter()
+                .map(|id| Transaction::update_edge(id.clone(), vec![EdgeAction::Delete])),
+        );
+        let removed_nodes = sorted(
+            old.conversations
+                .keys()
+                .filter(|id| !graph.conversations.contains_key(id))
+                .chain(
+                    old.people
+                        .keys()
+                        .filter(|id| !graph.people.contains_key(id)),
+                ),
+        );
+        txs.extend(
+            removed_nodes
+                .into_iter()
+                .map(|id| Transaction::update_node(id.clone(), vec![NodeUpdate::Delete])),
+        );
+
+        for id in sorted(graph.conversations.keys()) {
+            let label = &graph.conversations[id];
+            match old.conversations.get(id) {
+                None => {
+                    let mut node = Node::label(label);
+                    node.id = id.clone();
+                    txs.push(Transaction::create_node(node));
+                }
+                Some(old) if old != label => txs.push(Transaction::update_node(
+                    id.clone(),
+                    vec![NodeUpdate::Label(label.clone())],
+                )),
+                Some(_) => {}
+            }
+        }
+        for id in sorted(graph.people.keys()) {
+            let person = &graph.people[id];
+            match old.people.get(id) {
+                None => txs.push(Transaction::create_node(person.clone())),
+                Some(old) if old != person => txs.push(Transaction::update_node(
+                    id.clone(),
+                    vec![
+                        NodeUpdate::Label(person.label.clone()),
+                        NodeUpdate::DataBlob(0, person.data_blob[&0].clone()),
+                    ],
+                )),
+                Some(_) => {}
+            }
+        }
+        for id in sorted(graph.edges.keys().filter(|id| !old.edges.contains_key(id))) {
+            txs.push(Transaction::create_edge(graph.edges[id].clone()));
+        }
+
+        for node in sorted(graph.replies.keys()) {
+            let edge = &graph.replies[node];
+            if old.replies.get(node) != Some(edge) {
+                let blob = DataBlob::Edge(edge.clone());
+                txs.push(Transaction::update_node(
+                    node.clone(),
+                    vec![NodeUpdate::DataBlob(REPLY_BLOB, blob)],
+                ));
+            }
+        }
+        let removed_replies = sorted(
+            old.replies
+                .keys()
+                .filter(|node| !graph.replies.contains_key(node)),
+        );
+        txs.extend(removed_replies.into_iter().map(|node| {
+            Transaction::update_node(node.clone(), vec![NodeUpdate::DataBlobRemove(REPLY_BLOB)])
+        }));
+        txs
+    }
+
+    /// Returns the node of the person, see [person].
+    fn person(&self, name: Option<&str>, address: &str) -> Node {
+        person(self.domain, &self.id, name, address)
+    }
+
+    /// Returns an [Edge] with an ID derived from its nodes, valid from the
+    /// date of the e-mail.
+    fn edge(&self, nodes: &[&NodeID], kind: EdgeKind, date: Option<Timestamp>) -> Edge {
+        let parts = nodes.iter().map(|id| id.as_ref()).collect::<Vec<_>>();
+        Edge {
+            id: EdgeID::hash_domain_parts(self.domain, &parts),
+            kind,
+            validity: Validity::From(date.unwrap_or_default()),
+            history: vec![],
+        }
+    }
+}
+
+/// Returns the [NodeKind::Label] node of a person, with a `person`
+/// [DataBlob::Entry] holding their `name` and `email`, and an ID derived from
+/// the address, so all e-mails and events of a source share it.
+pub fn person(domain: &str, id: &SourceID, name: Option<&str>, address: &str) -> Node {
+    let mut person = Node::label(name.unwrap_or(address));
+    person.id = NodeID::hash_domain_parts(
+        domain,
+        &[id.as_ref(), b"person", address.to_lowercase().as_bytes()],
+    );
+    person.data_blob.insert(
+        0,
+        DataBlob::Entry(
+            "person".into(),
+            HashMap::from([
+                (
+                    "name".into(),
+                    DataBlob::Text(name.unwrap_or_default().into()),
+                ),
+                ("email".into(), DataBlob::Text(address.into())),
+            ]),
+        ),
+    );
+    person
+}
+
+/// Returns the message ID of the e-mail, or one derived from its node if it
+/// has none.
+fn message_id(node: &NodeID, envelope: &Envelope) -> String {
+    envelope
+        .message_id
+        .clone()
+        .unwrap_or_else(|| format!("datahog:{node}"))
+}
+
+/// Returns true if `ancestor` is `id`, or one of its parents.
+fn is_ancestor(parents: &HashMap<String, String>, ancestor: &str, id: &str) -> bool {
+    let mut id = id;
+    loop {
+        if id == ancestor {
+            return true;
+        }
+        match parents.get(id) {
+            Some(parent) => id = parent,
+            None => return false,
+        }
+    }
+}
+
+/// Returns the subject without the `Re:` and `Fwd:` prefixes.
+fn base_subject(subject: &str) -> &str {
+    let mut subject = subject.trim();
+    while let Some(prefix) = ["re:", "fwd:", "fw:"].iter().find(|prefix| {
+        subject
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    }) {
+        subject = subject[prefix.len()..].trim_start();
+    }
+    subject
+}
+
+fn sorted<'a, T: ToString + 'a>(ids: impl Iterator<Item = &'a T>) -> Vec<&'a T> {
+    let mut ids = ids.collect::<Vec<_>>();
+    ids.sort_by_key(|id| id.to_string());
+    ids
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{storage::mail::Mailbox, worldview::WorldView};
+
+    use super::*;
+
+    fn mail(id: &str, from: &str, subject: &str, references: &str) -> String {
+        format!(
+            "Message-ID: <{id}>\r\nFrom: {from}\r\nTo: bob@example.com\r\n\
+            Subject: {subject}\r\nReferences: {references}\r\n\r\nHi\r\n"
+        )
+    }
+
+    fn reply(ww: &WorldView, node: &NodeID) -> Option<NodeID> {
+        match ww.get_node(node)?.data_blob.get(&REPLY_BLOB) {
+            Some(DataBlob::Edge(Edge {
+                kind: EdgeKind::Reference { dest, .. },
+                ..
+            })) => Some(dest.clone()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_threads() -> anyhow::Result<()> {
+        let mut mailbox = Mailbox::new("Test", SourceID::rnd());
+        let mut txs = mailbox.root("test");
+        txs.extend(mailbox.set_folders(&[("INBOX".into(), None)]));
+        let mails = [
+            mail("a@x", "Alice <alice@example.com>", "Plans", ""),
+            mail("b@x", "Bob <bob@example.com>", "Re: Plans", "<a@x>"),
+            // The parent of this reply is missing.
+            mail("c@x", "alice@example.com", "Re: Re: Plans", "<a@x> <m@x>"),
+            mail("d@x", "alice@example.com", "Other", ""),
+        ];
+        for (i, raw) in mails.iter().enumerate() {
+            txs.extend(mailbox.add_message("INBOX", &i.to_string(), raw.as_bytes()));
+        }
+        txs.extend(mailbox.update_threads());
+        let mut ww = WorldView::new();
+        ww.process_updates(txs).await?;
+        let [a, b, c, d] = ["0", "1", "2", "3"].map(|key| mailbox.message("INBOX", key).unwrap());
+
+        assert_eq!(reply(&ww, &a), None);
+        assert_eq!(reply(&ww, &b), Some(a.clone()));
+        assert_eq!(reply(&ww, &c), Some(a.clone()));
+        let conversation = |ww: &WorldView, node: &NodeID| {
+            ww.get_node(node)
+                .unwrap()
+                .edges
+                .iter()
+                .find_map(|e| match &e.kind {
+                    EdgeKind::Contains { container, .. } => {
+                        let container = ww.get_node(container)?;
+                        (container.label != "INBOX").then_some(container)
+                    }
+                    _ => None,
+                })
+        };
+        let plans = conversation(&ww, &b).unwrap();
+        assert_eq!(plans.label, "Plans");
+        assert_eq!(conversation(&ww, &a).unwrap().id, plans.id);
+        assert_eq!(conversation(&ww, &c).unwrap().id, plans.id);
+        assert!(conversation(&ww, &d).is_none());
+
+        // Alice and Bob, once each, with Bob's name taken from his e-mail.
+        let people = |ww: &WorldView, node: &NodeID| {
+            let mut people = ww
+                .get_node(node)
+                .unwrap()
+                .edges
+                .iter()
+                .filter_map(|e| match &e.kind {
+                    EdgeKind::Using { client, .. } => Some(ww.get_node(client)?.label),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            people.sort();
+            people
+        };
+        assert_eq!(people(&ww, &a), ["Alice", "Bob"]);
+        assert_eq!(people(&ww, &c), ["Alice", "Bob"]);
+        assert!(mailbox.update_threads().is_empty());
+
+        // Without the first e-mail, the replies are still in the conversation.
+        let txs = mailbox.remove_message("INBOX", "0");
+        ww.process_updates(txs).await?;
+        ww.process_updates(mailbox.update_threads()).await?;
+        assert_eq!(reply(&ww, &b), None);
+        assert_eq!(reply(&ww, &c), None);
+        assert_eq!(conversation(&ww, &c).unwrap().id, plans.id);
+
+        // Adding the first e-mail again, and replacing the second one, creates
+        // their edges again.
+        let mut txs = vec![];
+        for (i, raw) in mails.iter().enumerate().take(2) {
+            txs.extend(mailbox.add_message("INBOX", &i.to_string(), raw.as_bytes()));
+        }
+        txs.extend(mailbox.update_threads());
+        ww.process_updates(txs).await?;
+        assert_eq!(reply(&ww, &b), Some(a.clone()));
+        let Some(DataBlob::Edge(edge)) =
+            ww.get_node(&b).unwrap().data_blob.get(&REPLY_BLOB).cloned()
+        else {
+            panic!("Expected the reply");
+        };
+        assert!(ww.get_edge(&edge.id).is_some());
+        let replies = ww
+            .get_node(&a)
+            .unwrap()
+            .edges
+            .iter()
+            .filter(|e| matches!(e.kind, EdgeKind::Reference { .. }))
+            .count();
+        assert_eq!(replies, 2);
+        assert_eq!(conversation(&ww, &a).unwrap().id, plans.id);
+        assert_eq!(conversation(&ww, &b).unwrap().id, plans.id);
+        assert_eq!(people(&ww, &a), ["Alice", "Bob"]);
+        assert_eq!(people(&ww, &b), ["Bob"]);
+        assert!(mailbox.update_threads().is_empty());
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 //! The basic structs used throughout the datahog library.
 
-use std::collections::HashMap;
+// The `len` method is generated by the `AsU256` derive macro.
+#![allow(clippy::len_without_is_empty)]
+
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Result;
 use bytes::Bytes;
@@ -10,6 +13,7 @@ use flmacro::{AsU256, VersionedSerde};
 use num_bigfloat::BigFloat;
 use num_bigint::BigInt;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 
 /// A [Transaction] is the fundamental storage entity in `DataHog`.
 /// All [Transaction]s must be read in order to get the current state
@@ -236,6 +240,27 @@ pub trait Source: std::fmt::Debug {
 
     /// Returns the unique ID of this source.
     fn get_id(&self) -> SourceID;
+
+    /// Called before the first [Source::get_updates] if this [Source] has
+    /// been added before, e.g. when it is added again after a restart.
+    /// `nodes` are all [Node]s of the [crate::worldview::WorldView], so the
+    /// [Source] can find the [NodeID]s it gave out from its `root`.
+    fn restore(&mut self, _root: &NodeID, _nodes: &HashMap<NodeID, Node>) {}
+
+    /// How the sync loop should fetch the updates of this [Source].
+    /// It is called once when the sync loop starts.
+    fn sync_mode(&mut self) -> SyncMode {
+        SyncMode::Poll(Duration::from_secs(10))
+    }
+}
+
+/// Defines when a [Source] is asked for updates in the sync loop.
+pub enum SyncMode {
+    /// Call [Source::get_updates] regularly with the given interval.
+    Poll(Duration),
+    /// Call [Source::get_updates] every time a message is received.
+    /// The sync loop stops when the channel is closed.
+    Notify(mpsc::Receiver<()>),
 }
 
 pub trait HasID<T>: std::fmt::Debug {
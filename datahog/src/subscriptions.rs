@@ -0,0 +1,288 @@
+//! Subscriptions allow to be notified of changes in the
+//! [crate::worldview::WorldView].
+//! After each applied [crate::structs::Transaction], every subscriber gets
+//! one [Changes] with the [ChangeEvent]s matching its [Watch].
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use tokio::sync::mpsc;
+
+use crate::structs::{Edge, EdgeID, EdgeKind, Node, NodeID, Timestamp};
+
+/// What a subscriber is interested in.
+#[derive(Clone)]
+pub enum Watch {
+    /// Changes to this [Node], and to the [Edge]s connected to it.
+    Node(NodeID),
+    /// Changes to this [Edge].
+    Edge(EdgeID),
+    /// Changes to this [Node] and all [Node]s it contains, recursively,
+    /// following the [EdgeKind::Contains] edges.
+    /// Also the [Edge]s connected to these [Node]s.
+    Subtree(NodeID),
+    /// Changes to all [Node]s for which the query returns true, before or
+    /// after the change.
+    Query(Arc<dyn Fn(&Node) -> bool + Send + Sync>),
+}
+
+impl std::fmt::Debug for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Watch::Node(id) => write!(f, "Node({id})"),
+            Watch::Edge(id) => write!(f, "Edge({id})"),
+            Watch::Subtree(id) => write!(f, "Subtree({id})"),
+            Watch::Query(_) => write!(f, "Query(..)"),
+        }
+    }
+}
+
+/// A single change of a [Node] or an [Edge].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ChangeEvent {
+    NodeCreated(Node),
+    NodeUpdated { old: Node, new: Node },
+    NodeDeleted(Node),
+    EdgeCreated(Edge),
+    EdgeUpdated { old: Edge, new: Edge },
+    EdgeDeleted(Edge),
+}
+
+/// All [ChangeEvent]s of one transaction which match a [Watch].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Changes {
+    pub timestamp: Timestamp,
+    pub events: Vec<ChangeEvent>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SubscriptionID(u64);
+
+/// Keeps track of all subscribers.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    next_id: u64,
+    subscribers: HashMap<SubscriptionID, (Watch, mpsc::UnboundedSender<Changes>)>,
+}
+
+impl Subscriptions {
+    pub fn subscribe(
+        &mut self,
+        watch: Watch,
+    ) -> (SubscriptionID, mpsc::UnboundedReceiver<Changes>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = SubscriptionID(self.next_id);
+        self.next_id += 1;
+        self.subscribers.insert(id, (watch, tx));
+        (id, rx)
+    }
+
+    pub fn unsubscribe(&mut self, id: &SubscriptionID) {
+        self.subscribers.remove(id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Sends the matching events to all subscribers.
+    /// Subscribers which dropped their receiver are removed.
+    pub fn notify(
+        &mut self,
+        timestamp: Timestamp,
+        events: &[ChangeEvent],
+        nodes: &HashMap<NodeID, Node>,
+    ) {
+        if events.is_empty() {
+            return;
+        }
+        self.subscribers.retain(|_, (watch, tx)| {
+            let subtree = match watch {
+                Watch::Subtree(root) => subtree(root, nodes, events),
+                _ => HashSet::new(),
+            };
+            let events = events
+                .iter()
+                .filter(|e| e.matches(watch, &subtree))
+                .cloned()
+                .collect::<Vec<_>>();
+            events.is_empty() || tx.send(Changes { timestamp, events }).is_ok()
+        });
+    }
+}
+
+impl ChangeEvent {
+    fn matches(&self, watch: &Watch, subtree: &HashSet<NodeID>) -> bool {
+        match (self, watch) {
+            (
+                ChangeEvent::NodeCreated(node) | ChangeEvent::NodeDeleted(node),
+                Watch::Node(id) | Watch::Subtree(id),
+            ) => &node.id == id || subtree.contains(&node.id),
+            (ChangeEvent::NodeUpdated { new, .. }, Watch::Node(id) | Watch::Subtree(id)) => {
+                &new.id == id || subtree.contains(&new.id)
+            }
+            (ChangeEvent::NodeCreated(node) | ChangeEvent::NodeDeleted(node), Watch::Query(q)) => {
+                q(node)
+            }
+            (ChangeEvent::NodeUpdated { old, new }, Watch::Query(q)) => q(old) || q(new),
+            (
+                ChangeEvent::EdgeCreated(edge) | ChangeEvent::EdgeDeleted(edge),
+                Watch::Node(_) | Watch::Subtree(_) | Watch::Edge(_),
+            ) => edge.touches(watch, subtree),
+            (ChangeEvent::EdgeUpdated { old, new }, _) => {
+                old.touches(watch, subtree) || new.touches(watch, subtree)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Edge {
+    fn touches(&self, watch: &Watch, subtree: &HashSet<NodeID>) -> bool {
+        match watch {
+            Watch::Node(id) => self.nodes().contains(&id),
+            Watch::Edge(id) => &self.id == id,
+            Watch::Subtree(_) => self.nodes().iter().any(|n| subtree.contains(n)),
+            Watch::Query(_) => false,
+        }
+    }
+}
+
+/// Returns the root and all nodes it contains, following the
+/// [EdgeKind::Contains] edges of the nodes, as well as the edges deleted
+/// in this transaction.
+fn subtree(
+    root: &NodeID,
+    nodes: &HashMap<NodeID, Node>,
+    events: &[ChangeEvent],
+) -> HashSet<NodeID> {
+    let mut children: HashMap<&NodeID, Vec<&NodeID>> = HashMap::new();
+    for event in events {
+        if let ChangeEvent::EdgeDeleted(edge) | ChangeEvent::EdgeUpdated { old: edge, .. } = event
+            && let EdgeKind::Contains { container, object } = &edge.kind
+        {
+            children.entry(container).or_default().push(object);
+        }
+    }
+    let mut found = HashSet::from([root.clone()]);
+    let mut todo = vec![root.clone()];
+    while let Some(id) = todo.pop() {
+        let from_node = nodes.get(&id).into_iter().flat_map(|n| {
+            n.edges.iter().filter_map(|e| match &e.kind {
+                EdgeKind::Contains { container, object } if container == &id => Some(object),
+                _ => None,
+            })
+        });
+        let from_events = children.get(&id).into_iter().flatten().cloned();
+        for child in from_node.chain(from_events) {
+            if found.insert(child.clone()) {
+                todo.push(child.clone());
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use either::Either;
+
+    use super::*;
+    use crate::{
+        structs::{NodeUpdate, Record, RecordCUD, SourceID, Transaction},
+        worldview::WorldView,
+    };
+
+    fn update_node(id: &NodeID, updates: Vec<NodeUpdate>) -> Transaction {
+        Transaction {
+            timestamp: 0,
+            records: vec![Record::Node(RecordCUD {
+                base: Either::Left(id.clone()),
+                updates,
+            })],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions() -> anyhow::Result<()> {
+        let mut wv = WorldView::new();
+        let sid = SourceID::rnd();
+        let (root, child, other) = (
+            Node::label("root"),
+            Node::label("child"),
+            Node::label("other"),
+        );
+
+        let (_, mut rx_node) = wv.subscribe(Watch::Node(child.id.clone()));
+        let (_, mut rx_tree) = wv.subscribe(Watch::Subtree(root.id.clone()));
+        let (_, mut rx_query) = wv.subscribe(Watch::Query(Arc::new(|n: &Node| {
+            n.label.starts_with("oth")
+        })));
+
+        for node in [&root, &child, &other] {
+            wv.add_transactions(&sid, vec![Transaction::create_node(node.clone())])
+                .await?;
+        }
+        let edge = Edge::contains(root.id.clone(), child.id.clone());
+        wv.add_transactions(&sid, vec![Transaction::create_edge(edge.clone())])
+            .await?;
+        wv.add_transactions(
+            &sid,
+            vec![update_node(
+                &child.id,
+                vec![NodeUpdate::Label("child2".into())],
+            )],
+        )
+        .await?;
+        wv.add_transactions(
+            &sid,
+            vec![update_node(
+                &other.id,
+                vec![NodeUpdate::Label("nothing".into())],
+            )],
+        )
+        .await?;
+
+        let changes = |rx: &mut mpsc::UnboundedReceiver<Changes>| {
+            let mut events = vec![];
+            while let Ok(c) = rx.try_recv() {
+                events.extend(c.events);
+            }
+            events
+        };
+
+        let ev_node = changes(&mut rx_node);
+        assert_eq!(ev_node.len(), 3);
+        assert!(matches!(&ev_node[0], ChangeEvent::NodeCreated(n) if n.id == child.id));
+        assert!(matches!(&ev_node[1], ChangeEvent::EdgeCreated(e) if e.id == edge.id));
+        assert!(matches!(&ev_node[2], ChangeEvent::NodeUpdated{new, ..} if new.label == "child2"));
+
+        let ev_tree = changes(&mut rx_tree);
+        assert_eq!(ev_tree.len(), 3);
+        assert!(matches!(&ev_tree[0], ChangeEvent::NodeCreated(n) if n.id == root.id));
+
+        let ev_query = changes(&mut rx_query);
+        assert_eq!(ev_query.len(), 2);
+        assert!(
+            matches!(&ev_query[1], ChangeEvent::NodeUpdated{new, ..} if new.label == "nothing")
+        );
+
+        drop(rx_node);
+        wv.add_transactions(&sid, vec![update_node(&child.id, vec![NodeUpdate::Delete])])
+            .await?;
+        assert!(wv.get_node(&child.id).is_none());
+        // The edges of the deleted node are deleted, too.
+        assert!(wv.get_edge(&edge.id).is_none());
+        assert!(wv.get_node(&root.id).unwrap().edges.is_empty());
+        let ev_tree = changes(&mut rx_tree);
+        assert!(
+            matches!(&ev_tree[..], [ChangeEvent::NodeDeleted(n), ChangeEvent::EdgeDeleted(e)]
+            if n.id == child.id && e.id == edge.id)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+//! The sync loop fetches the updates of all [Source]s of a [WorldView] in the
+//! background.
+//!
+//! Every [Source] runs in its own task, following its [SyncMode], so a slow
+//! [Source] doesn't block the others.
+//! The updates are sent to a single task which applies them to the [WorldView].
+//! If a [Source] returns an error, it is retried with an exponential backoff.
+//! The [SourceHealth] of each [Source] can be read through the [SyncHandle].
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use flarch::tasks::{spawn_local, wait};
+use tokio::sync::{Mutex, mpsc, watch};
+
+use crate::{
+    impls::timestamp_now,
+    structs::{Source, SourceID, SyncMode, Timestamp, Transaction},
+    worldview::WorldView,
+};
+
+/// How the sync loop reacts to errors of a [Source].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncConfig {
+    /// The first delay after an error.
+    pub backoff_min: Duration,
+    /// The delay after an error is doubled for each consecutive error, up
+    /// to this value.
+    pub backoff_max: Duration,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            backoff_min: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The health of a single [Source].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceHealth {
+    /// When the last call to [Source::get_updates] succeeded.
+    pub last_success: Option<Timestamp>,
+    /// When the last call to [Source::get_updates] failed, and why.
+    pub last_error: Option<(Timestamp, String)>,
+    /// How many calls to [Source::get_updates] failed since the last success.
+    pub consecutive_errors: u32,
+    /// How many updates from this [Source] have been applied.
+    pub transactions: usize,
+}
+
+impl SourceHealth {
+    /// Time in nanoseconds since the last successful update.
+    /// Returns [None] if the [Source] never succeeded.
+    pub fn lag(&self, now: Timestamp) -> Option<Timestamp> {
+        self.last_success.map(|ls| now - ls)
+    }
+}
+
+type Healths = Arc<std::sync::Mutex<HashMap<SourceID, SourceHealth>>>;
+
+/// Stops the sync loop when dropped.
+pub struct SyncHandle {
+    health: Healths,
+    stop: watch::Sender<bool>,
+}
+
+impl SyncHandle {
+    /// Starts one task for every [Source] of the [WorldView], and one task
+    /// to apply the updates.
+    /// [Source]s added to the [WorldView] later on are not synched, the
+    /// sync loop needs to be restarted for them.
+    pub async fn start(wv: Arc<Mutex<WorldView>>, config: SyncConfig) -> Self {
+        let sources = wv.lock().await.sources();
+        let health: Healths = Arc::new(std::sync::Mutex::new(
+            sources
+                .keys()
+                .map(|sid| (sid.clone(), SourceHealth::default()))
+                .collect(),
+        ));
+        let (stop, _) = watch::channel(false);
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        for (sid, source) in sources {
+            let mode = source.lock().await.sync_mode();
+            spawn_local(poll_source(
+                SourcePoller {
+                    sid,
+                    source,
+                    health: health.clone(),
+                    stop: stop.subscribe(),
+                    config: config.clone(),
+                    updates: tx.clone(),
+                },
+                mode,
+            ));
+        }
+        spawn_local(apply_updates(wv, rx, health.clone()));
+
+        Self { health, stop }
+    }
+
+    /// Returns the current health of all [Source]s.
+    pub fn health(&self) -> HashMap<SourceID, SourceHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Stops all tasks, after their current poll if one is running.
+    /// Tasks waiting for the next poll stop right away.
+    pub fn stop(&self) {
+        self.stop.send_replace(true);
+    }
+}
+
+impl Drop for SyncHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct SourcePoller {
+    sid: SourceID,
+    source: Arc<Mutex<Box<dyn Source + Send>>>,
+    health: Healths,
+    stop: watch::Receiver<bool>,
+    config: SyncConfig,
+    updates: mpsc::UnboundedSender<(SourceID, Vec<Transaction>)>,
+}
+
+impl SourcePoller {
+    /// Fetches the updates and returns the delay before the next try in case of
+    /// an error.
+    async fn poll(&mut self) -> Option<Duration> {
+        let res = self.source.lock().await.get_updates().await;
+        let now = timestamp_now();
+        let mut healths = self.health.lock().unwrap();
+        let health = healths.entry(self.sid.clone()).or_default();
+        match res {
+            Ok(txs) => {
+                health.last_success = Some(now);
+                health.consecutive_errors = 0;
+                if !txs.is_empty() {
+                    let _ = self.updates.send((self.sid.clone(), txs));
+                }
+                None
+            }
+            Err(e) => {
+                log::warn!("Source {} failed to get updates: {e:#}", self.sid);
+                health.last_error = Some((now, format!("{e:#}")));
+                let backoff = self
+                    .config
+                    .backoff_min
+                    .saturating_mul(2u32.saturating_pow(health.consecutive_errors));
+                health.consecutive_errors += 1;
+                Some(backoff.min(self.config.backoff_max))
+            }
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        *self.stop.borrow() || self.updates.is_closed()
+    }
+}
+
+/// Returns the output of the future, or [None] if the sync loop is stopped
+/// before it is ready.
+async fn unless_stopped<T>(
+    stop: &mut watch::Receiver<bool>,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = stop.wait_for(|stop| *stop) => None,
+    }
+}
+
+async fn poll_source(mut poller: SourcePoller, mode: SyncMode) {
+    let mut stop = poller.stop.clone();
+    match mode {
+        SyncMode::Poll(interval) => {
+            while !poller.stopped() {
+                let delay = poller.poll().await.unwrap_or(interval);
+                if unless_stopped(&mut stop, wait(delay)).await.is_none() {
+                    break;
+                }
+            }
+        }
+        SyncMode::Notify(mut notify) => {
+            while !poller.stopped() {
+                let next = match poller.poll().await {
+                    Some(backoff) => unless_stopped(&mut stop, wait(backoff)).await.map(Some),
+                    None => unless_stopped(&mut stop, notify.recv()).await,
+                };
+                if !matches!(next, Some(Some(()))) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn apply_updates(
+    wv: Arc<Mutex<WorldView>>,
+    mut rx: mpsc::UnboundedReceiver<(SourceID, Vec<Transaction>)>,
+    health: Healths,
+) {
+    while let Some((sid, txs)) = rx.recv().await {
+        let applied = match wv.lock().await.process_updates(txs).await {
+            Ok((applied, _, _)) => applied.len(),
+            Err(e) => {
+                log::error!("Couldn't apply updates from {sid}: {e:?}");
+                0
+            }
+        };
+        if let Some(h) = health.lock().unwrap().get_mut(&sid) {
+            h.transactions += applied;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use flarch::tasks::wait_ms;
+
+    use super::*;
+    use crate::structs::Node;
+
+    /// Returns one new node per call, after a delay.
+    /// The `errors` calls after the first one fail.
+    /// It is polled, or notified through `notify` if it is set.
+    #[derive(Debug)]
+    struct TestSource {
+        id: SourceID,
+        delay: u64,
+        errors: u32,
+        calls: u32,
+        notify: Option<mpsc::Receiver<()>>,
+    }
+
+    impl TestSource {
+        fn new(delay: u64, errors: u32) -> Box<Self> {
+            Box::new(Self {
+                id: SourceID::rnd(),
+                delay,
+                errors,
+                calls: 0,
+                notify: None,
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Source for TestSource {
+        async fn get_updates(&mut self) -> anyhow::Result<Vec<Transaction>> {
+            wait_ms(self.delay).await;
+            self.calls += 1;
+            // The first call must succeed, else the source cannot be added.
+            if (2..2 + self.errors).contains(&self.calls) {
+                anyhow::bail!("error {}", self.calls);
+            }
+            Ok(vec![Transaction::create_node(Node::label("test"))])
+        }
+
+        async fn add_tx(&mut self, _txs: Vec<Transaction>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn get_id(&self) -> SourceID {
+            self.id.clone()
+        }
+
+        fn sync_mode(&mut self) -> SyncMode {
+            match self.notify.take() {
+                Some(notify) => SyncMode::Notify(notify),
+                None => SyncMode::Poll(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    /// The time is paused, so it only advances when all tasks wait.
+    #[tokio::test(start_paused = true)]
+    async fn test_sync() -> anyhow::Result<()> {
+        let mut wv = WorldView::new();
+        let fast = TestSource::new(0, 0);
+        let fast_id = fast.get_id();
+        let fast_root = wv.add_source(fast).await?;
+        let slow = TestSource::new(300, 0);
+        let slow_id = slow.get_id();
+        wv.add_source(slow).await?;
+        let failing = TestSource::new(0, 3);
+        let failing_id = failing.get_id();
+        wv.add_source(failing).await?;
+        assert!(wv.get_node(&fast_root).is_some());
+
+        let wv = Arc::new(Mutex::new(wv));
+        let sync = SyncHandle::start(
+            wv.clone(),
+            SyncConfig {
+                backoff_min: Duration::from_millis(5),
+                backoff_max: Duration::from_millis(20),
+            },
+        )
+        .await;
+        wait_ms(205).await;
+
+        let health = sync.health();
+        assert_eq!(health.len(), 3);
+        assert_eq!(health[&fast_id].transactions, 21);
+        assert!(health[&fast_id].lag(timestamp_now()).is_some());
+        assert_eq!(health[&slow_id].transactions, 0);
+        assert!(health[&slow_id].last_success.is_none());
+        let failing_health = &health[&failing_id];
+        assert!(failing_health.last_error.is_some());
+        assert!(failing_health.last_success.is_some());
+        assert_eq!(failing_health.consecutive_errors, 0);
+        assert!(failing_health.transactions > 0);
+
+        sync.stop();
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stop_notify() -> anyhow::Result<()> {
+        let mut wv = WorldView::new();
+        let (notify, rx) = mpsc::channel(1);
+        let mut source = TestSource::new(0, 0);
+        source.notify = Some(rx);
+        let id = source.get_id();
+        wv.add_source(source).await?;
+        let wv = Arc::new(Mutex::new(wv));
+        let sync = SyncHandle::start(wv.clone(), SyncConfig::default()).await;
+        let source = wv.lock().await.sources()[&id].clone();
+
+        notify.send(()).await?;
+        wait_ms(10).await;
+        assert_eq!(sync.health()[&id].transactions, 2);
+        // The task of the source holds a reference to it.
+        assert_eq!(Arc::strong_count(&source), 3);
+
+        // Stopping ends the task, even if the notifications never stop.
+        drop(sync);
+        wait_ms(10).await;
+        assert_eq!(Arc::strong_count(&source), 2);
+        assert!(notify.is_closed());
+        Ok(())
+    }
+}
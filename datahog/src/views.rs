@@ -43,17 +43,23 @@ impl Node {
         dn
     }
 
+    /// Replaces the [DataBlob::Text]s and the [DataView] with the [DataNode].
+    /// All other [DataBlob]s are not part of the [DataNode], and are kept.
     pub fn view_data_node_set(&mut self, dn: &DataNode) {
-        self.data_blob.clear();
-        self.data_view = *self.data_node_set(dn);
+        self.data_blob
+            .retain(|_, blob| !matches!(blob, DataBlob::Text(_)));
+        self.data_view = *self.data_node_set(dn, &mut 0);
     }
 
-    fn data_node_set(&mut self, dn: &DataNode) -> Box<DataView> {
-        let index = self.data_blob.len() as u32;
+    fn data_node_set(&mut self, dn: &DataNode, next: &mut u32) -> Box<DataView> {
+        while self.data_blob.contains_key(next) {
+            *next += 1;
+        }
+        let index = *next;
         self.data_blob
             .insert(index, DataBlob::Text(dn.data.clone()));
-        let child = dn.child.first().map(|c| self.data_node_set(c));
-        let sibling = dn.sibling.first().map(|c| self.data_node_set(c));
+        let child = dn.child.first().map(|c| self.data_node_set(c, next));
+        let sibling = dn.sibling.first().map(|c| self.data_node_set(c, next));
         Box::new(DataView {
             index,
             child,
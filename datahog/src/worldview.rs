@@ -2,13 +2,36 @@
 //! ensuring that the data is consistent and up-to-date. It provides a single
 //! interface for accessing and manipulating the data, making it easy to work
 //! with the data from different sources.
+//!
+//! Before applying new [Transaction]s, the [WorldView] checks them against
+//! the [ContainsPolicy], so no unwanted `Contains`-cycles get introduced.
+//! After applying a [Transaction], all subscribers get notified of the
+//! changes they [Watch], and all [NodeObject]s and [EdgeObject]s get the
+//! current version of their element.
 
 use anyhow::Result;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
 
+use tokio::sync::{Mutex, mpsc, watch};
+
+use crate::integrity::{ContainsPolicy, contains_cycles};
+use crate::objects::{EdgeObject, NodeObject};
 use crate::structs::{
-    Edge, EdgeID, EdgeKind, Node, NodeID, Record, RecordEvent, Source, SourceID, Transaction,
+    Edge, EdgeAction, EdgeID, Node, NodeID, NodeUpdate, Record, RecordEvent, Source, SourceID,
+    Transaction, Validity,
 };
+use crate::subscriptions::{ChangeEvent, Changes, SubscriptionID, Subscriptions, Watch};
+
+/// The part of the [WorldView] which needs to be stored to recreate it
+/// after a restart.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct WorldViewState {
+    /// All [Transaction]s applied to the [WorldView].
+    pub transactions: Vec<Transaction>,
+    /// The root [Node] of each [Source].
+    pub source_root: HashMap<SourceID, NodeID>,
+}
 
 #[derive(Debug)]
 pub struct WorldView {
@@ -16,41 +39,101 @@ pub struct WorldView {
     nodes: HashMap<NodeID, Node>,
     edges: HashMap<EdgeID, Edge>,
     source_root: HashMap<SourceID, NodeID>,
-    sources: HashMap<SourceID, Box<dyn Source + Send>>,
+    sources: HashMap<SourceID, Arc<Mutex<Box<dyn Source + Send>>>>,
+    contains_policy: ContainsPolicy,
+    subscriptions: Subscriptions,
+    node_objects: HashMap<NodeID, watch::Sender<Option<Node>>>,
+    edge_objects: HashMap<EdgeID, watch::Sender<Option<Edge>>>,
+}
+
+impl Default for WorldView {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WorldView {
     pub fn new() -> Self {
-        let wv = Self {
+        Self {
             transactions: vec![],
             nodes: HashMap::new(),
             edges: HashMap::new(),
             source_root: HashMap::new(),
             sources: HashMap::new(),
-        };
+            contains_policy: ContainsPolicy::default(),
+            subscriptions: Subscriptions::default(),
+            node_objects: HashMap::new(),
+            edge_objects: HashMap::new(),
+        }
+    }
 
+    /// Recreates a [WorldView] by applying all [Transaction]s of the state.
+    /// The [Source]s need to be added again, and will keep their root [Node].
+    pub fn from_state(state: WorldViewState) -> Self {
+        let mut wv = Self::new();
+        for tx in state.transactions {
+            wv.do_tx(tx);
+        }
+        wv.source_root = state.source_root;
         wv
     }
 
+    /// Returns the state needed to recreate this [WorldView] with [WorldView::from_state].
+    pub fn state(&self) -> WorldViewState {
+        WorldViewState {
+            transactions: self.transactions.clone(),
+            source_root: self.source_root.clone(),
+        }
+    }
+
+    /// Adds a [Source] and applies all its [Transaction]s.
+    /// Returns the root [Node] of the [Source]: if the [Source] has been added
+    /// before, the stored root is used, else the first [Node] of the [Source].
+    /// After [WorldView::from_state], the [Transaction]s which are already
+    /// part of the state are not stored again.
     pub async fn add_source(
         &mut self,
         mut source: Box<dyn Source + Send>,
     ) -> anyhow::Result<NodeID> {
         let sid = source.get_id();
+        if let Some(root) = self.source_root.get(&sid) {
+            source.restore(root, &self.nodes);
+        }
         let txs = source.get_updates().await?;
         let (_, nodes, _) = self.process_updates(txs).await?;
-        self.sources.insert(sid.clone(), source);
-        if let Some(root) = nodes.first() {
-            self.source_root.insert(sid, root.clone());
-            Ok(root.clone())
-        } else {
-            anyhow::bail!("No nodes found in this source");
-        }
+        self.sources
+            .insert(sid.clone(), Arc::new(Mutex::new(source)));
+        let root = match self.source_root.get(&sid) {
+            Some(root) => root.clone(),
+            None => match nodes.first() {
+                Some(root) => root.clone(),
+                None => anyhow::bail!("No nodes found in this source"),
+            },
+        };
+        self.source_root.insert(sid, root.clone());
+        Ok(root)
     }
 
+    /// Checks the [Transaction]s against the integrity rules, then stores them
+    /// in the [Source] and applies them.
+    /// If any of the [Transaction]s breaks a rule, or the [Source] cannot store
+    /// them, none of them are applied, and the [NodeObject]s and [EdgeObject]s
+    /// of their elements get the current version again.
     pub async fn add_transactions(&mut self, sid: &SourceID, txs: Vec<Transaction>) -> Result<()> {
-        if let Some(source) = self.sources.get_mut(sid) {
-            source.add_tx(txs.clone()).await?;
+        let checked = self.contains_policy.check(
+            &self.edges,
+            &Transaction {
+                timestamp: 0,
+                records: txs.iter().flat_map(|tx| tx.records.clone()).collect(),
+            },
+        );
+        let stored = match (checked, self.sources.get(sid)) {
+            (Ok(()), Some(source)) => source.lock().await.add_tx(txs.clone()).await,
+            (checked, _) => checked,
+        };
+        if let Err(e) = stored {
+            self.resend_objects(&txs);
+            return Err(e);
         }
         for tx in txs {
             self.do_tx(tx);
@@ -66,40 +149,193 @@ impl WorldView {
         self.edges.get(id).cloned()
     }
 
+    /// Fetches the updates of all [Source]s one after the other.
+    /// For a background update, use [crate::sync::SyncHandle].
     pub async fn fetch(&mut self) -> Result<(Vec<Transaction>, Vec<NodeID>, Vec<EdgeID>)> {
         let mut txs = vec![];
-        for source in self.sources.values_mut() {
-            txs.extend(source.get_updates().await?);
+        for source in self.sources.values() {
+            txs.extend(source.lock().await.get_updates().await?);
         }
         let (txs, nodes, edges) = self.process_updates(txs).await?;
         Ok((txs, nodes, edges))
     }
 
+    /// Allows or forbids `Contains`-cycles going through this container.
+    pub fn allow_contains_cycle(&mut self, container: &NodeID, allow: bool) {
+        self.contains_policy.allow_cycle(container, allow);
+    }
+
+    /// Returns all `Contains`-cycles currently in the graph.
+    pub fn contains_cycles(&self) -> Vec<Vec<NodeID>> {
+        contains_cycles(&self.edges)
+    }
+
+    /// Returns a channel which receives all changes matching the [Watch],
+    /// once per applied [Transaction].
+    pub fn subscribe(
+        &mut self,
+        watch: Watch,
+    ) -> (SubscriptionID, mpsc::UnboundedReceiver<Changes>) {
+        self.subscriptions.subscribe(watch)
+    }
+
+    pub fn unsubscribe(&mut self, id: &SubscriptionID) {
+        self.subscriptions.unsubscribe(id);
+    }
+
+    /// Returns a [NodeObject] for this [Node], which gets refreshed by all applied
+    /// [Transaction]s.
+    /// The [Transaction]s from the receiver must be passed to [WorldView::add_transactions].
+    pub fn node_object(
+        &mut self,
+        id: &NodeID,
+    ) -> Option<(NodeObject, mpsc::Receiver<Transaction>)> {
+        let node = self.get_node(id)?;
+        let current = self
+            .node_objects
+            .entry(id.clone())
+            .or_insert_with(|| watch::Sender::new(Some(node.clone())))
+            .subscribe();
+        Some(NodeObject::new(node, current))
+    }
+
+    /// Returns an [EdgeObject] for this [Edge], which gets refreshed by all applied
+    /// [Transaction]s.
+    /// The [Transaction]s from the receiver must be passed to [WorldView::add_transactions].
+    pub fn edge_object(
+        &mut self,
+        id: &EdgeID,
+    ) -> Option<(EdgeObject, mpsc::Receiver<Transaction>)> {
+        let edge = self.get_edge(id)?;
+        let current = self
+            .edge_objects
+            .entry(id.clone())
+            .or_insert_with(|| watch::Sender::new(Some(edge.clone())))
+            .subscribe();
+        Some(EdgeObject::new(edge, current))
+    }
+
     pub fn root_nodes(&self) -> Vec<NodeID> {
         self.source_root.values().cloned().collect::<Vec<_>>()
     }
 
-    async fn process_updates(
+    /// Returns all [Source]s, so they can be synched in the background.
+    pub(crate) fn sources(&self) -> HashMap<SourceID, Arc<Mutex<Box<dyn Source + Send>>>> {
+        self.sources.clone()
+    }
+
+    /// Applies the [Transaction]s coming from the [Source]s.
+    /// The [Transaction]s breaking the [ContainsPolicy] are rejected, the ones
+    /// which change nothing are skipped, and only the applied ones are
+    /// returned, with the IDs of their [Node]s and [Edge]s.
+    pub(crate) async fn process_updates(
         &mut self,
         txs: Vec<Transaction>,
     ) -> anyhow::Result<(Vec<Transaction>, Vec<NodeID>, Vec<EdgeID>)> {
-        let (mut nodes, mut edges) = (vec![], vec![]);
-        for tx in &txs {
+        let (mut applied, mut nodes, mut edges) = (vec![], vec![], vec![]);
+        for tx in txs {
+            if let Err(e) = self.contains_policy.check(&self.edges, &tx) {
+                log::warn!("Rejecting transaction: {e:?}");
+                continue;
+            }
+            if !self.changes_state(&tx) {
+                continue;
+            }
             let (mut ns, mut es) = self.do_tx(tx.clone());
             nodes.append(&mut ns);
             edges.append(&mut es);
+            applied.push(tx);
         }
-        Ok((txs, nodes, edges))
+        Ok((applied, nodes, edges))
+    }
+
+    /// Returns true if the [Transaction] changes a [Node] or an [Edge], not
+    /// counting their history.
+    fn changes_state(&self, tx: &Transaction) -> bool {
+        let mut nodes = HashMap::new();
+        let mut edges = HashMap::new();
+        for r in &tx.records {
+            match r {
+                Record::Node(rc) => {
+                    let id = rc.get_id();
+                    let old = nodes
+                        .get(&id)
+                        .cloned()
+                        .unwrap_or_else(|| self.nodes.get(&id).cloned());
+                    let mut new = match &rc.base {
+                        either::Either::Left(_) => old.clone(),
+                        either::Either::Right(node) => Some(Node {
+                            edges: old.as_ref().map(|n| n.edges.clone()).unwrap_or_default(),
+                            history: old.as_ref().map(|n| n.history.clone()).unwrap_or_default(),
+                            ..node.clone()
+                        }),
+                    };
+                    if rc.updates.contains(&NodeUpdate::Delete) {
+                        new = None;
+                    }
+                    if let Some(node) = new.as_mut() {
+                        for update in &rc.updates {
+                            node.update(update.clone());
+                        }
+                    }
+                    if new != old {
+                        return true;
+                    }
+                    nodes.insert(id, new);
+                }
+                Record::Edge(rc) => {
+                    let id = rc.get_id();
+                    let old = edges
+                        .get(&id)
+                        .cloned()
+                        .unwrap_or_else(|| self.edges.get(&id).cloned());
+                    let mut new = match &rc.base {
+                        either::Either::Left(_) => old.clone(),
+                        either::Either::Right(edge) => {
+                            let mut edge = edge.clone();
+                            if let Some(old) = &old {
+                                edge.history = old.history.clone();
+                                // An edge which is valid since earlier stays so
+                                // when a source creates it again.
+                                if let (Validity::From(since), Validity::From(again)) =
+                                    (&old.validity, &edge.validity)
+                                    && since <= again
+                                {
+                                    edge.validity = old.validity.clone();
+                                }
+                            }
+                            Some(edge)
+                        }
+                    };
+                    if rc.updates.contains(&EdgeAction::Delete) {
+                        new = None;
+                    }
+                    if let Some(edge) = new.as_mut() {
+                        for update in &rc.updates {
+                            edge.update(update.clone());
+                        }
+                    }
+                    if new != old {
+                        return true;
+                    }
+                    edges.insert(id, new);
+                }
+            }
+        }
+        false
     }
 
     fn do_tx(&mut self, tx: Transaction) -> (Vec<NodeID>, Vec<EdgeID>) {
         let (mut nids, mut eids) = (vec![], vec![]);
+        let mut events = vec![];
         self.transactions.push(tx.clone());
         for r in tx.records {
             let rec_event = RecordEvent(tx.timestamp, r.clone());
             match r {
                 Record::Node(rc) => {
-                    nids.push(rc.get_id());
+                    let id = rc.get_id();
+                    nids.push(id.clone());
+                    let old = self.nodes.get(&id).cloned();
                     match rc.base {
                         either::Either::Left(id) => {
                             if let Some(node) = self.nodes.get_mut(&id) {
@@ -109,48 +345,147 @@ impl WorldView {
                             }
                         }
                         either::Either::Right(mut node) => {
-                            node.add_history(rec_event);
+                            // Re-creating an existing node, e.g. when a source is
+                            // imported again, keeps its edges and history.
+                            if let Some(old) = &old {
+                                node.edges = old.edges.clone();
+                                node.history = old.history.clone();
+                            }
+                            node.add_history(rec_event.clone());
                             self.nodes.insert(node.id.clone(), node);
                         }
                     }
+                    if rc.updates.contains(&NodeUpdate::Delete) {
+                        if let Some(node) = self.nodes.remove(&id) {
+                            events.push(ChangeEvent::NodeDeleted(node));
+                        }
+                        for edge in self.delete_edges_of(&rec_event, &id) {
+                            eids.push(edge.id.clone());
+                            events.push(ChangeEvent::EdgeDeleted(edge));
+                        }
+                    } else if let Some(new) = self.nodes.get(&id).cloned() {
+                        events.push(match old {
+                            Some(old) => ChangeEvent::NodeUpdated { old, new },
+                            None => ChangeEvent::NodeCreated(new),
+                        });
+                    }
                 }
                 Record::Edge(rc) => {
-                    eids.push(rc.get_id());
-                    match rc.base {
+                    let id = rc.get_id();
+                    eids.push(id.clone());
+                    let old = self.edges.get(&id).cloned();
+                    if let Some(old) = &old {
+                        self.remove_edge_from_nodes(&rec_event, old);
+                    }
+                    let new = match rc.base {
                         either::Either::Left(id) => {
-                            if let Some(mut edge) = self.edges.get(&id).cloned() {
-                                self.remove_edge_from_nodes(&rec_event, &edge);
+                            let edge = old.clone().map(|mut edge| {
                                 edge.add_history(rec_event.clone());
-                                self.apply_edge_to_nodes(&rec_event, &edge);
-                                self.edges.insert(edge.id.clone(), edge);
-                            } else {
+                                edge
+                            });
+                            // The edges of a deleted node are already gone.
+                            if edge.is_none() && !rc.updates.contains(&EdgeAction::Delete) {
                                 log::error!("Edge {id} not found for update");
                             }
+                            edge
                         }
                         either::Either::Right(mut edge) => {
                             edge.add_history(rec_event.clone());
-                            self.apply_edge_to_nodes(&rec_event, &edge);
-                            self.edges.insert(edge.id.clone(), edge);
+                            Some(edge)
                         }
+                    };
+                    let Some(new) = new else {
+                        continue;
+                    };
+                    if rc.updates.contains(&EdgeAction::Delete) {
+                        self.edges.remove(&id);
+                        events.push(ChangeEvent::EdgeDeleted(new));
+                    } else {
+                        self.apply_edge_to_nodes(&rec_event, &new);
+                        self.edges.insert(id, new.clone());
+                        events.push(match old {
+                            Some(old) => ChangeEvent::EdgeUpdated { old, new },
+                            None => ChangeEvent::EdgeCreated(new),
+                        });
                     }
                 }
             }
         }
+        self.subscriptions
+            .notify(tx.timestamp, &events, &self.nodes);
+        self.update_objects();
         (nids, eids)
     }
 
-    fn remove_edge_from_nodes(&mut self, re: &RecordEvent, edge: &Edge) {
-        for node in match &edge.kind {
-            EdgeKind::Equality(_node_ids) => {
-                todo!()
+    /// Sends the current version of their element to all [NodeObject]s and
+    /// [EdgeObject]s, and forgets the elements without objects.
+    fn update_objects(&mut self) {
+        self.node_objects.retain(|_, tx| !tx.is_closed());
+        self.edge_objects.retain(|_, tx| !tx.is_closed());
+        for (id, tx) in &self.node_objects {
+            let node = self.nodes.get(id);
+            tx.send_if_modified(|current| {
+                let modified = current.as_ref() != node;
+                if modified {
+                    *current = node.cloned();
+                }
+                modified
+            });
+        }
+        for (id, tx) in &self.edge_objects {
+            let edge = self.edges.get(id);
+            tx.send_if_modified(|current| {
+                let modified = current.as_ref() != edge;
+                if modified {
+                    *current = edge.cloned();
+                }
+                modified
+            });
+        }
+    }
+
+    /// Sends the current version of the elements of rejected [Transaction]s to
+    /// their handles, which already applied the changes locally.
+    fn resend_objects(&self, txs: &[Transaction]) {
+        for record in txs.iter().flat_map(|tx| &tx.records) {
+            match record {
+                Record::Node(rc) => {
+                    let id = rc.get_id();
+                    if let Some(tx) = self.node_objects.get(&id) {
+                        tx.send_replace(self.nodes.get(&id).cloned());
+                    }
+                }
+                Record::Edge(rc) => {
+                    let id = rc.get_id();
+                    if let Some(tx) = self.edge_objects.get(&id) {
+                        tx.send_replace(self.edges.get(&id).cloned());
+                    }
+                }
             }
-            EdgeKind::Definition { object, label } => vec![object, label],
-            EdgeKind::Using { client, object } => vec![client, object],
-            EdgeKind::Contains { container, object } => {
-                vec![container, object]
+        }
+    }
+
+    /// Deletes all [Edge]s connected to a deleted [Node], and returns them.
+    fn delete_edges_of(&mut self, re: &RecordEvent, id: &NodeID) -> Vec<Edge> {
+        let mut ids = self
+            .edges
+            .values()
+            .filter(|edge| edge.nodes().contains(&id))
+            .map(|edge| edge.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.to_string());
+        let mut deleted = vec![];
+        for id in ids {
+            if let Some(edge) = self.edges.remove(&id) {
+                self.remove_edge_from_nodes(re, &edge);
+                deleted.push(edge);
             }
-            _ => todo!(),
-        } {
+        }
+        deleted
+    }
+
+    fn remove_edge_from_nodes(&mut self, re: &RecordEvent, edge: &Edge) {
+        for node in edge.nodes() {
             if let Some(node) = self.nodes.get_mut(node) {
                 node.edges.retain(|e| e.id != edge.id);
                 node.history.push(re.clone());
@@ -159,24 +494,14 @@ impl WorldView {
     }
 
     fn apply_edge_to_nodes(&mut self, re: &RecordEvent, edge: &Edge) {
-        for node in match &edge.kind {
-            EdgeKind::Equality(_node_ids) => {
-                todo!()
-            }
-            EdgeKind::Definition { object, label } => vec![object, label],
-            EdgeKind::Using { client, object } => vec![client, object],
-            EdgeKind::Contains { container, object } => {
-                vec![container, object]
-            }
-            _ => todo!(),
-        } {
+        for node in edge.nodes() {
             if let Some(node) = self.nodes.get_mut(node) {
                 // TODO: fix this
                 node.edges.insert(0, edge.clone());
-                if let Some(history) = node.history.last_mut() {
-                    if history != re {
-                        node.history.push(re.clone());
-                    }
+                if let Some(history) = node.history.last_mut()
+                    && history != re
+                {
+                    node.history.push(re.clone());
                 }
             }
         }
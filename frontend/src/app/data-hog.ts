@@ -50,6 +50,10 @@ export class DataHogService {
     return (await this._dh?.search_nodes(search)) || [];
   }
 
+  markUsed(id: NodeID) {
+    this._dh?.mark_used(id);
+  }
+
   async searchLabels(search: string): Promise<Node[]> {
     return (await this.searchNodes(search)).filter((n) => n.kind === 'Label');
   }
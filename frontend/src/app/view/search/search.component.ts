@@ -38,9 +38,9 @@ export class SearchComponent implements OnInit, OnDestroy {
   ) {}
 
   async ngOnInit() {
-    // Set up debounced search
+    // Set up debounced search - results are ranked, so keep up with the typing
     this.searchSubscription = this.searchSubject
-      .pipe(debounceTime(400), distinctUntilChanged())
+      .pipe(debounceTime(100), distinctUntilChanged())
       .subscribe((query) => {
         this.update_search(query);
       });
@@ -173,6 +173,7 @@ export class SearchComponent implements OnInit, OnDestroy {
   }
 
   navigateToNode(node: Node) {
+    this.dh.markUsed(node.id);
     // Route markdown nodes to the markdown view
     if (node.kind.startsWith('MimeType')) {
       this.router.navigate(['/markdown', node.id.toString()]);
@@ -1,5 +1,6 @@
 use std::{collections::HashMap, str::FromStr};
 
+use datahog::{impls::timestamp_now, search::QuickSearch};
 pub use datahog::{
     structs::{Edge, EdgeID, Node, NodeID, NodeKind},
     views::DataNode,
@@ -154,6 +155,7 @@ pub struct Datahog {
     root: NodeID,
     nodes: HashMap<NodeID, Node>,
     edges: HashMap<EdgeID, Edge>,
+    search: QuickSearch,
 }
 
 #[wasm_bindgen]
@@ -164,6 +166,7 @@ impl Datahog {
             root: NodeID::zero(),
             nodes: HashMap::new(),
             edges: HashMap::new(),
+            search: QuickSearch::default(),
         };
         dh.init_root().await?;
         Ok(dh)
@@ -181,6 +184,7 @@ impl Datahog {
             root: NodeID::zero(),
             nodes: HashMap::new(),
             edges: HashMap::new(),
+            search: QuickSearch::default(),
         };
         if dh.init_root().await.is_err() {
             let root = Node::label("Universe_local");
@@ -255,16 +259,22 @@ impl Datahog {
         Ok(())
     }
 
+    /// Returns the nodes matching the search, best match first.
     pub async fn search_nodes(&self, search: String) -> Result<Vec<NodeWrapper>, String> {
-        let lower = search.to_lowercase();
         Ok(self
-            .nodes
-            .values()
-            .filter(|n| n.label.to_lowercase().contains(&lower))
+            .search
+            .search(&search, self.nodes.values(), timestamp_now())
+            .into_iter()
+            .filter_map(|hit| self.nodes.get(&hit.id))
             .map(|n| NodeWrapper(n.clone()))
             .collect())
     }
 
+    /// Marks the node as used, so it ranks higher in the following searches.
+    pub fn mark_used(&mut self, id: &NodeIDWrapper) {
+        self.search.mark_used(&(**id).into(), timestamp_now());
+    }
+
     async fn get<T: DeserializeOwned>(&mut self, api: &str, id: U256) -> Result<Option<T>, String> {
         match &self.backend {
             Backend::URL(url) => reqwest::get(&format!("{url}/get_{api}?id={id:?}"))